@@ -5,23 +5,57 @@ function fetchAndInstantiate(url, importObject) {
         .then(results => results.instance);
 }
 
+// Same order as `LogLevel`
+const LOG_ERROR = 0;
+const LOG_WARN = 1;
+const LOG_INFO = 2;
+const LOG_DEBUG = 3;
+
 class HlsPlayer {
-    constructor(hls_wasm) {
+    // `config` is an optional object in the form of `HlsPlayerConfig`
+    constructor(hls_wasm, config) {
         this.hls_wasm = hls_wasm;
         this.api = hls_wasm.exports;
-        this.player = this.api.hls_player_new();
+        if (config === undefined) {
+            this.player = this.api.hls_player_new();
+        } else {
+            const json = (new TextEncoder).encode(JSON.stringify(config));
+            let error = 0;
+            this.player = this.with_wasm_str(json, s => {
+                // Receives the error through a slot in the wasm memory
+                return this.with_wasm_bytes(new Uint8Array(4), slot => {
+                    const slot_ptr = this.api.wasm_bytes_ptr(slot);
+                    const player = this.api.hls_player_new_with_config(s, slot_ptr);
+                    error = new Int32Array(this.api.memory.buffer, slot_ptr, 1)[0];
+                    return player;
+                });
+            });
+            if (this.player == 0) {
+                const reason = JSON.stringify(this.wasm_str_into_json(error));
+                throw new Error(
+                    `Invalid player configuration: ${JSON.stringify(config)}: ${reason}`);
+            }
+        }
+        this.log_level = this.api.hls_player_log_level(this.player);
 
         let media_source = new MediaSource();
         media_source.addEventListener('sourceopen', () => {
-            console.log("[DEBUG] Event.sourceopen");
-            const mimeCodec = 'video/mp4; codecs="avc1.4dc00d,mp4a.40.2"'; // TODO
-            this.sb = media_source.addSourceBuffer(mimeCodec);
-            this.sb.mode = 'sequence';
+            this.debug("Event.sourceopen");
+            this.poll_segment();
         }, false);
         this.media_source = media_source;
+        this.tracks = null;
 
         this.video = document.getElementsByTagName('video')[0];
         this.video.src = URL.createObjectURL(media_source);
+        this.text_track = this.video.addTextTrack('subtitles');
+        this.text_track.mode = 'showing';
+        this.caption_track = this.video.addTextTrack('captions');
+        this.caption_track.mode = 'showing';
+        this.video.addEventListener('timeupdate', () => {
+            // Resumes segment fetching as the buffered media is consumed
+            this.poll();
+        });
         this.video.play();
     }
 
@@ -32,13 +66,13 @@ class HlsPlayer {
                 let error =
                     this.with_wasm_str((new TextEncoder).encode(m3u8_url), url => {
                         return this.with_wasm_str(new Uint8Array(m3u8), m3u8 => {
-                            console.log("[DEBUG] Starts playing m3u8");
+                            this.info("Starts playing m3u8");
                             return this.api.hls_player_play(this.player, url, m3u8);
                         })
                     });
                 if (error != 0) {
                     let json = this.wasm_str_into_json(error);
-                    console.log(json);
+                    this.error(json);
                     return Promise.reject(JSON.stringify(json));
                 }
 
@@ -47,28 +81,130 @@ class HlsPlayer {
             .catch(error => alert(`Cannot fetch ${m3u8_url}\n\n[Reason]\n${error}`))
     }
 
-    fetch_url(action_id, url) {
-        console.log(`[DEBUG] Starts fetching url: [${action_id}] ${url}`);
+    audio_renditions() {
+        return this.wasm_str_into_json(this.api.hls_player_audio_renditions(this.player));
+    }
+
+    subtitle_renditions() {
+        return this.wasm_str_into_json(this.api.hls_player_subtitle_renditions(this.player));
+    }
+
+    select_subtitle_rendition(name_or_language) {
+        let error = this.with_wasm_str((new TextEncoder).encode(name_or_language), s => {
+            return this.api.hls_player_select_subtitle_rendition(this.player, s);
+        });
+        if (error != 0) {
+            this.error(this.wasm_str_into_json(error));
+            return;
+        }
+        this.poll();
+    }
+
+    select_audio_rendition(name_or_language) {
+        let error = this.with_wasm_str((new TextEncoder).encode(name_or_language), s => {
+            return this.api.hls_player_select_audio_rendition(this.player, s);
+        });
+        if (error != 0) {
+            this.error(this.wasm_str_into_json(error));
+            return;
+        }
+        this.poll();
+    }
+
+    // Returns the wall-clock time of the current (or the given) playback position
+    position_to_date(position = this.video.currentTime) {
+        let date_time = this.api.hls_player_position_to_date_time(this.player, position);
+        return isNaN(date_time) ? null : new Date(date_time);
+    }
+
+    date_to_position(date) {
+        let position = this.api.hls_player_date_time_to_position(this.player, date.getTime());
+        return isNaN(position) ? null : position;
+    }
+
+    seek(position) {
+        let error = this.api.hls_player_seek(this.player, position);
+        if (error != 0) {
+            this.error(this.wasm_str_into_json(error));
+            return;
+        }
+        if (this.tracks != null) {
+            for (let track of this.tracks) {
+                track.sb.abort();
+            }
+        }
+        this.video.currentTime = position;
+        this.poll();
+    }
+
+    fetch_url(action_id, url, range) {
+        this.debug(`Starts fetching url: [${action_id}] ${url}`);
+        let headers = {};
+        if (range) {
+            headers["Range"] = `bytes=${range["offset"]}-${range["offset"] + range["length"] - 1}`;
+        }
         const start_time = new Date();
-        fetch(url)
-            .then(response => response.arrayBuffer())
-            .then(body => {
-                const end_time = new Date();
-                const fetch_duration_ms = end_time - start_time;
-                let error = this.with_wasm_bytes(new Uint8Array(body), bytes => {
-                    return this.api.hls_player_handle_data(this.player, action_id, bytes, fetch_duration_ms);
-                });
-                if (error != 0) {
-                    let json = this.wasm_str_into_json(error);
-                    console.log(json);
-                    return Promise.reject(JSON.stringify(json));
+        fetch(url, {headers: headers})
+            .then(response => {
+                if (!response.ok) {
+                    this.warn(`Cannot fetch url: [${action_id}] ${url} (status:${response.status})`);
+                    return this.handle_fetch_error(action_id, response.status);
                 }
-                console.log(`[DEBUG] Handled: [${action_id}] ${url} (delay:${fetch_duration_ms})`);
-                this.poll();
+                return response.arrayBuffer().then(body => {
+                    const end_time = new Date();
+                    const fetch_duration_ms = end_time - start_time;
+                    let error = this.with_wasm_bytes(new Uint8Array(body), bytes => {
+                        return this.api.hls_player_handle_data(this.player, action_id, bytes, fetch_duration_ms);
+                    });
+                    if (error != 0) {
+                        let json = this.wasm_str_into_json(error);
+                        if (json["kind"] == "StaleResponse") {
+                            // The request was cancelled by a seek or a variant switch
+                            this.debug(`Discarded: [${action_id}] ${url}`);
+                            return;
+                        }
+                        this.error(json);
+                        return Promise.reject(JSON.stringify(json));
+                    }
+                    this.debug(`Handled: [${action_id}] ${url} (delay:${fetch_duration_ms})`);
+                    this.poll();
+                });
+            }, error => {
+                this.warn(`Cannot fetch url: [${action_id}] ${url} (${error})`);
+                return this.handle_fetch_error(action_id, 0);
             })
             .catch(error => alert(`Cannot fetch url: ${url}: ${error}`))
     }
 
+    // `status` is 0 if no response was received (e.g., network errors)
+    handle_fetch_error(action_id, status) {
+        let error = this.api.hls_player_handle_fetch_error(this.player, action_id, status);
+        if (error != 0) {
+            let json = this.wasm_str_into_json(error);
+            this.error(json);
+            return Promise.reject(JSON.stringify(json));
+        }
+        this.poll();
+    }
+
+    log(level, label, message) {
+        if (level <= this.log_level) {
+            console.log(`[${label}]`, message);
+        }
+    }
+    error(message) {
+        this.log(LOG_ERROR, "ERROR", message);
+    }
+    warn(message) {
+        this.log(LOG_WARN, "WARN", message);
+    }
+    info(message) {
+        this.log(LOG_INFO, "INFO", message);
+    }
+    debug(message) {
+        this.log(LOG_DEBUG, "DEBUG", message);
+    }
+
     with_wasm_str(src_utf8, callback) {
         let wasm_buf = this.api.wasm_str_new(src_utf8.length);
         let wasm_ptr = this.api.wasm_str_ptr(wasm_buf);
@@ -90,22 +226,95 @@ class HlsPlayer {
     }
 
     wasm_str_into_json(s) {
-        let buf = new Uint8Array(this.api.memory.buffer, this.api.wasm_str_ptr(s), this.api.wasm_str_len(s));
-        let json = JSON.parse(new TextDecoder("utf-8").decode(buf));
-        this.api.wasm_str_free(s);
-        return json;
+        return JSON.parse(this.wasm_str_into_string(s));
     }
     wasm_bytes_into_uint8array(b) {
         let array = new Uint8Array(this.api.memory.buffer, this.api.wasm_bytes_ptr(b), this.api.wasm_bytes_len(b));
         this.api.wasm_bytes_free(b);
         return array;
     }
+    wasm_str_into_string(s) {
+        let buf = new Uint8Array(this.api.memory.buffer, this.api.wasm_str_ptr(s), this.api.wasm_str_len(s));
+        let string = new TextDecoder("utf-8").decode(buf);
+        this.api.wasm_str_free(s);
+        return string;
+    }
+    create_source_buffers() {
+        if (this.media_source.readyState != 'open') {
+            return false;
+        }
+
+        let tracks = [{
+            mime_type: this.api.hls_player_mime_type(this.player),
+            set_buffer_level: this.api.hls_player_set_buffer_level,
+            next_mime_type: this.api.hls_player_next_mime_type,
+            is_discontinuity: this.api.hls_player_is_discontinuity,
+            timestamp_offset: this.api.hls_player_timestamp_offset,
+            next_segment: this.api.hls_player_next_segment
+        }];
+        if (this.api.hls_player_has_alternate_audio(this.player)) {
+            tracks.push({
+                mime_type: this.api.hls_player_audio_mime_type(this.player),
+                set_buffer_level: this.api.hls_player_set_audio_buffer_level,
+                next_mime_type: this.api.hls_player_next_audio_mime_type,
+                is_discontinuity: this.api.hls_player_is_audio_discontinuity,
+                timestamp_offset: this.api.hls_player_audio_timestamp_offset,
+                next_segment: this.api.hls_player_next_audio_segment
+            });
+        }
+        if (tracks.some(track => track.mime_type == 0)) {
+            tracks.filter(track => track.mime_type != 0)
+                .forEach(track => this.api.wasm_str_free(track.mime_type));
+            return false;
+        }
+
+        for (let track of tracks) {
+            track.mime_type = this.wasm_str_into_string(track.mime_type);
+            this.debug(`MIME type: ${track.mime_type}`);
+            track.sb = this.media_source.addSourceBuffer(track.mime_type);
+            track.sb.mode = 'sequence';
+            track.sb.addEventListener('updateend', () => {
+                this.poll_segment();
+            });
+        }
+        this.tracks = tracks;
+        return true;
+    }
     poll_segment() {
-        if (this.sb.updating) {
+        if (this.tracks == null && !this.create_source_buffers()) {
+            return;
+        }
+
+        for (let track of this.tracks) {
+            this.poll_track_segment(track);
+            this.report_buffer_level(track);
+        }
+        if (this.api.hls_player_is_ended(this.player) &&
+            this.media_source.readyState == 'open' &&
+            this.tracks.every(track => !track.sb.updating)) {
+            this.debug("End of stream");
+            this.media_source.endOfStream();
+        }
+    }
+    poll_track_segment(track) {
+        if (track.sb.updating) {
             return;
         }
 
-        let wasm_bytes = this.api.hls_player_next_segment(this.player);
+        const next_mime_type = track.next_mime_type(this.player);
+        if (next_mime_type != 0) {
+            // The codecs may change at initialization segments (e.g., after a variant switch)
+            const mime_type = this.wasm_str_into_string(next_mime_type);
+            if (mime_type != track.mime_type && typeof track.sb.changeType === 'function') {
+                this.debug(`MIME type: ${track.mime_type} -> ${mime_type}`);
+                track.sb.changeType(mime_type);
+                track.mime_type = mime_type;
+            }
+        }
+
+        const is_discontinuity = track.is_discontinuity(this.player);
+        const timestamp_offset = track.timestamp_offset(this.player);
+        let wasm_bytes = track.next_segment(this.player);
         if (wasm_bytes == 0) {
             return;
         }
@@ -114,16 +323,94 @@ class HlsPlayer {
             new Uint8Array(this.api.memory.buffer,
                            this.api.wasm_bytes_ptr(wasm_bytes),
                            this.api.wasm_bytes_len(wasm_bytes));
-        console.log(`[DEBUG] segment: ${segment.length} bytes (ptr:${this.api.wasm_bytes_ptr(wasm_bytes)})`);
-        this.sb.appendBuffer(segment);
+        this.debug(`segment: ${segment.length} bytes (ptr:${this.api.wasm_bytes_ptr(wasm_bytes)})`);
+        if (is_discontinuity) {
+            // Makes the next appended segment start at the end of the buffered ones
+            this.debug("Discontinuity");
+            track.sb.abort();
+        }
+        if (!isNaN(timestamp_offset)) {
+            // The first segment after a seek (or a start from the middle of the playlist)
+            track.sb.timestampOffset = timestamp_offset;
+        }
+        if (track.sb.buffered.length == 0) {
+            // Playback starts from the middle of the playlist (e.g., near the live edge)
+            let start_position = this.api.hls_player_start_position(this.player);
+            if (isNaN(start_position)) {
+                start_position = timestamp_offset;
+            }
+            if (this.video.currentTime < start_position) {
+                this.video.currentTime = start_position;
+            }
+        }
+        track.sb.appendBuffer(segment);
         this.api.wasm_bytes_free(wasm_bytes);
-
-        this.sb.addEventListener('updateend', () => {
-            this.poll_segment();
-        });
+    }
+    report_buffer_level(track) {
+        const time = this.video.currentTime;
+        let level = 0;
+        for (let i = 0; i < track.sb.buffered.length; i++) {
+            if (track.sb.buffered.start(i) <= time && time <= track.sb.buffered.end(i)) {
+                level = track.sb.buffered.end(i) - time;
+                break;
+            }
+        }
+        let error = track.set_buffer_level(this.player, level);
+        if (error != 0) {
+            this.error(this.wasm_str_into_json(error));
+        }
+    }
+    poll_cues(take_cues, text_track) {
+        let json = take_cues(this.player);
+        if (json == 0) {
+            return;
+        }
+        for (let cue of this.wasm_str_into_json(json)) {
+            let vtt_cue = new VTTCue(cue["start"], cue["end"], cue["text"]);
+            if (cue["id"]) {
+                vtt_cue.id = cue["id"];
+            }
+            text_track.addCue(vtt_cue);
+        }
+    }
+    poll_timed_metadata() {
+        let json = this.api.hls_player_take_timed_metadata(this.player);
+        if (json == 0) {
+            return;
+        }
+        for (let metadata of this.wasm_str_into_json(json)) {
+            this.debug(`Timed metadata: ${JSON.stringify(metadata)}`);
+            this.video.dispatchEvent(new CustomEvent('hls-timed-metadata', {detail: metadata}));
+        }
+    }
+    poll_ad_events() {
+        let json = this.api.hls_player_take_ad_events(this.player);
+        if (json == 0) {
+            return;
+        }
+        for (let event of this.wasm_str_into_json(json)) {
+            this.debug(`Ad event: ${JSON.stringify(event)}`);
+            this.video.dispatchEvent(new CustomEvent('hls-ad-event', {detail: event}));
+        }
+    }
+    poll_events() {
+        while (true) {
+            let json = this.api.hls_player_next_event(this.player);
+            if (json == 0) {
+                break;
+            }
+            let event = this.wasm_str_into_json(json);
+            this.debug(`Event: ${JSON.stringify(event)}`);
+            this.video.dispatchEvent(new CustomEvent('hls-event', {detail: event}));
+        }
     }
     poll() {
         this.poll_segment();
+        this.poll_cues(this.api.hls_player_take_subtitle_cues, this.text_track);
+        this.poll_cues(this.api.hls_player_take_caption_cues, this.caption_track);
+        this.poll_timed_metadata();
+        this.poll_ad_events();
+        this.poll_events();
 
         while (true) {
             let json = this.api.hls_player_next_action(this.player);
@@ -132,20 +419,20 @@ class HlsPlayer {
             }
 
             let action = this.wasm_str_into_json(json);
-            console.log(`[DEBUG] Next Action: ${JSON.stringify(action)}`);
+            this.debug(`Next Action: ${JSON.stringify(action)}`);
             if (action["type"] == "FetchData") {
-                this.fetch_url(action["action_id"], action["url"]);
+                this.fetch_url(action["action_id"], action["url"], action["range"]);
             } else if (action["type"] == "SetTimeout") {
                 setTimeout(() => {
                     let error = this.api.hls_player_handle_timeout(this.player, action["action_id"]);
                     if (error != 0) {
                         let json = this.wasm_str_into_json(error);
-                        console.log(json);
+                        this.error(json);
                     };
                     this.poll();
                 }, action["duration"]);
             } else {
-                console.log("[WARN] Unknown action");
+                this.warn("Unknown action");
             }
         }
     }
@@ -1,23 +1,18 @@
 use std;
 use hls_m3u8;
 use mse_fmp4;
+use serde_json;
 use trackable::Trackable;
 use trackable::error::{ErrorKind as TrackableErrorKind, ErrorKindExt, TrackableError};
 use url;
 
 /// This crate specific error type.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, TrackableError)]
 pub struct Error(TrackableError<ErrorKind>);
-derive_traits_for_trackable_error_newtype!(Error, ErrorKind);
 impl Error {
     pub fn to_json_string(&self) -> String {
-        use std::error::Error as StdError;
-
         let kind = format!("{:?}", self.kind());
-        let reason = self.cause()
-            .as_ref()
-            .map(|c| c.to_string())
-            .unwrap_or_else(|| "".to_owned());
+        let reason = self.reason();
         let mut trace = Vec::new();
         for location in self.history().iter().flat_map(|h| h.events()) {
             if location.message().is_empty() {
@@ -40,6 +35,16 @@ impl Error {
             });
         json.to_string()
     }
+
+    /// Returns the description of the cause of this error (empty if unknown).
+    pub fn reason(&self) -> String {
+        use std::error::Error as StdError;
+
+        // `TrackableError` does not implement `source()` yet
+        #[allow(deprecated)]
+        let cause = self.0.cause();
+        cause.map(|c| c.to_string()).unwrap_or_default()
+    }
 }
 impl From<hls_m3u8::Error> for Error {
     fn from(f: hls_m3u8::Error) -> Self {
@@ -58,22 +63,37 @@ impl From<std::str::Utf8Error> for Error {
         ErrorKind::InvalidInput.cause(f).into()
     }
 }
+impl From<serde_json::Error> for Error {
+    fn from(f: serde_json::Error) -> Self {
+        ErrorKind::InvalidInput.cause(f).into()
+    }
+}
 impl From<mse_fmp4::Error> for Error {
     fn from(f: mse_fmp4::Error) -> Self {
         let kind = match *f.kind() {
             mse_fmp4::ErrorKind::InvalidInput => ErrorKind::InvalidInput,
-            mse_fmp4::ErrorKind::Unsupported | mse_fmp4::ErrorKind::Other => ErrorKind::Other,
+            mse_fmp4::ErrorKind::Unsupported => ErrorKind::Unsupported,
+            mse_fmp4::ErrorKind::Other => ErrorKind::Other,
         };
         kind.takes_over(f).into()
     }
 }
 
 /// The list of the possible error kinds
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
 pub enum ErrorKind {
     /// Input data is invalid.
     InvalidInput,
 
+    /// Input data uses a feature not supported by this crate.
+    Unsupported,
+
+    /// A resource could not be fetched even after retries.
+    FetchFailed,
+
+    /// A response to a request that is no longer needed (e.g., issued before a seek).
+    StaleResponse,
+
     /// Other errors (e.g., I/O error).
     Other,
 }
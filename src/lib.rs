@@ -1,5 +1,5 @@
+extern crate aes;
 extern crate hls_m3u8;
-extern crate mpeg2ts;
 extern crate mse_fmp4;
 extern crate serde;
 #[macro_use]
@@ -40,6 +40,7 @@ pub type Result<T> = std::result::Result<T, Error>;
 
 #[derive(Debug)]
 pub struct MaybeJson<T> {
+    #[allow(dead_code)]
     json_str: WasmStr,
     _phantom: PhantomData<T>,
 }
@@ -81,6 +82,9 @@ impl<T> Ptr<T> {
             _phantom: PhantomData,
         }
     }
+    /// # Safety
+    ///
+    /// `self` must be a pointer created by `Ptr::new` that has not been freed yet.
     pub unsafe fn free(&mut self) {
         if self.ptr != 0 {
             let _ = Box::from_raw(self.ptr as *mut T);
@@ -106,11 +110,17 @@ impl WasmStr {
         let s = unsafe { String::from_utf8_unchecked(vec![0; size]) };
         WasmStr(Ptr::new(s))
     }
+    pub fn null() -> Self {
+        WasmStr(Ptr::null())
+    }
 
     pub fn as_ptr(&self) -> i32 {
         self.deref().as_ptr() as i32
     }
 
+    /// # Safety
+    ///
+    /// See `Ptr::free`.
     pub unsafe fn free(&mut self) {
         self.0.free();
     }
@@ -141,6 +151,9 @@ impl WasmBytes {
         self.deref().as_ptr() as i32
     }
 
+    /// # Safety
+    ///
+    /// See `Ptr::free`.
     pub unsafe fn free(&mut self) {
         self.0.free();
     }
@@ -0,0 +1,144 @@
+//! ADTS (Audio Data Transport Stream) utilities.
+use mse_fmp4::aac::{AacProfile, ChannelConfiguration, SamplingFrequency};
+
+use {ErrorKind, Result};
+
+/// Number of PCM samples in an AAC frame.
+pub const SAMPLES_IN_FRAME: u32 = 1024;
+
+const SAMPLING_FREQUENCIES: [(SamplingFrequency, u32); 13] = [
+    (SamplingFrequency::Hz96000, 96_000),
+    (SamplingFrequency::Hz88200, 88_200),
+    (SamplingFrequency::Hz64000, 64_000),
+    (SamplingFrequency::Hz48000, 48_000),
+    (SamplingFrequency::Hz44100, 44_100),
+    (SamplingFrequency::Hz32000, 32_000),
+    (SamplingFrequency::Hz24000, 24_000),
+    (SamplingFrequency::Hz22050, 22_050),
+    (SamplingFrequency::Hz16000, 16_000),
+    (SamplingFrequency::Hz12000, 12_000),
+    (SamplingFrequency::Hz11025, 11_025),
+    (SamplingFrequency::Hz8000, 8_000),
+    (SamplingFrequency::Hz7350, 7_350),
+];
+
+const CHANNEL_CONFIGURATIONS: [ChannelConfiguration; 8] = [
+    ChannelConfiguration::SentViaInbandPce,
+    ChannelConfiguration::OneChannel,
+    ChannelConfiguration::TwoChannels,
+    ChannelConfiguration::ThreeChannels,
+    ChannelConfiguration::FourChannels,
+    ChannelConfiguration::FiveChannels,
+    ChannelConfiguration::SixChannels,
+    ChannelConfiguration::EightChannels,
+];
+
+/// Returns the length of the ADTS frame at the head of `data`,
+/// or `None` if `data` does not contain the whole fixed header.
+pub fn frame_len(data: &[u8]) -> Option<usize> {
+    if data.len() < 7 {
+        return None;
+    }
+    Some(
+        (usize::from(data[3] & 0b0000_0011) << 11) | (usize::from(data[4]) << 3)
+            | (usize::from(data[5]) >> 5),
+    )
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct AdtsHeader {
+    pub profile: AacProfile,
+    pub sampling_frequency: SamplingFrequency,
+    pub channel_configuration: ChannelConfiguration,
+
+    /// Length of the header (7 bytes, or 9 bytes if it is followed by a CRC).
+    pub header_len: usize,
+
+    /// Length of the frame including the header.
+    pub frame_len: usize,
+}
+impl AdtsHeader {
+    /// Parses the ADTS header at the head of `frame`.
+    pub fn parse(frame: &[u8]) -> Result<Self> {
+        track_assert!(frame.len() >= 7, ErrorKind::InvalidInput, "Too short ADTS frame");
+        track_assert_eq!(
+            (frame[0], frame[1] & 0xF6),
+            (0xFF, 0xF0),
+            ErrorKind::InvalidInput,
+            "ADTS syncword not found"
+        );
+        let protection_absent = frame[1] & 0b0000_0001 != 0;
+        let header_len = if protection_absent { 7 } else { 9 };
+
+        let profile = match frame[2] >> 6 {
+            0 => AacProfile::Main,
+            1 => AacProfile::Lc,
+            2 => AacProfile::Ssr,
+            _ => AacProfile::Ltp,
+        };
+        let sampling_frequency_index = ((frame[2] >> 2) & 0b1111) as usize;
+        let sampling_frequency = track_assert_some!(
+            SAMPLING_FREQUENCIES.get(sampling_frequency_index),
+            ErrorKind::InvalidInput,
+            "Invalid sampling frequency index: {}",
+            sampling_frequency_index
+        ).0;
+        let channel_configuration =
+            CHANNEL_CONFIGURATIONS[(((frame[2] & 1) << 2) | (frame[3] >> 6)) as usize];
+
+        let frame_len = frame_len(frame).expect("Never fails");
+        track_assert!(
+            frame_len >= header_len && frame_len <= frame.len(),
+            ErrorKind::InvalidInput,
+            "Invalid ADTS frame length"
+        );
+        Ok(AdtsHeader {
+            profile,
+            sampling_frequency,
+            channel_configuration,
+            header_len,
+            frame_len,
+        })
+    }
+
+    pub fn sampling_frequency_hz(&self) -> u32 {
+        SAMPLING_FREQUENCIES
+            .iter()
+            .find(|x| x.0 == self.sampling_frequency)
+            .map_or(0, |x| x.1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn adts_header() {
+        // AAC-LC, 44.1kHz, stereo and 371 bytes without CRC
+        let mut frame = vec![0xFF, 0xF1, 0x50, 0x80, 0x2E, 0x7F, 0xFC];
+        frame.resize(371, 0);
+        let header = AdtsHeader::parse(&frame).unwrap();
+        assert_eq!(header.profile as u8, AacProfile::Lc as u8);
+        assert_eq!(header.sampling_frequency_hz(), 44_100);
+        assert_eq!(header.channel_configuration as u8, ChannelConfiguration::TwoChannels as u8);
+        assert_eq!((header.header_len, header.frame_len), (7, 371));
+        assert_eq!(frame_len(&frame[..7]), Some(371));
+        assert_eq!(frame_len(&frame[..6]), None);
+
+        // Truncated
+        assert!(AdtsHeader::parse(&frame[..370]).is_err());
+    }
+
+    #[test]
+    fn adts_header_with_crc() {
+        // AAC-LC, 48kHz, mono and 9 bytes of header
+        let frame = [0xFF, 0xF0, 0x4C, 0x40, 0x01, 0x3F, 0xFC, 0x12, 0x34, 0x00];
+        let header = AdtsHeader::parse(&frame).unwrap();
+        assert_eq!(header.sampling_frequency_hz(), 48_000);
+        assert_eq!(header.channel_configuration as u8, ChannelConfiguration::OneChannel as u8);
+        assert_eq!((header.header_len, header.frame_len), (9, 9));
+
+        assert!(AdtsHeader::parse(&[0xFF, 0xE1, 0x50, 0x80, 0x01, 0x1F, 0xFC]).is_err());
+    }
+}
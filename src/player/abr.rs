@@ -0,0 +1,334 @@
+use std::f64;
+
+use super::HlsPlayerConfig;
+
+/// Minimum number of bytes that must be observed before the estimate is trusted.
+const MIN_TOTAL_BYTES: u64 = 16 * 1024;
+
+/// Throughput estimator based on a pair of exponentially weighted moving averages.
+///
+/// The fast average reacts quickly to bandwidth drops
+/// while the slow one prevents overreacting to short bursts.
+#[derive(Debug)]
+pub struct BandwidthEstimator {
+    fast: Ewma,
+    slow: Ewma,
+    total_bytes: u64,
+}
+impl BandwidthEstimator {
+    pub fn new() -> Self {
+        BandwidthEstimator {
+            fast: Ewma::new(3.0),
+            slow: Ewma::new(9.0),
+            total_bytes: 0,
+        }
+    }
+
+    /// Adds a sample that `bytes` bytes were fetched in `duration_ms` milliseconds.
+    pub fn add_sample(&mut self, bytes: usize, duration_ms: u32) {
+        let duration_secs = f64::from(duration_ms.max(1)) / 1000.0;
+        let bits_per_sec = (bytes as f64 * 8.0) / duration_secs;
+        self.fast.sample(duration_secs, bits_per_sec);
+        self.slow.sample(duration_secs, bits_per_sec);
+        self.total_bytes += bytes as u64;
+    }
+
+    /// Returns the estimated bandwidth in bits per second.
+    ///
+    /// If not enough samples have been collected yet, it will return `None`.
+    pub fn estimate(&self) -> Option<u64> {
+        if self.total_bytes < MIN_TOTAL_BYTES {
+            None
+        } else {
+            let estimate = self.fast.estimate().min(self.slow.estimate());
+            Some(estimate as u64)
+        }
+    }
+}
+impl Default for BandwidthEstimator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Debug)]
+struct Ewma {
+    alpha: f64,
+    estimate: f64,
+    total_weight: f64,
+}
+impl Ewma {
+    fn new(half_life_secs: f64) -> Self {
+        Ewma {
+            alpha: (0.5f64.ln() / half_life_secs).exp(),
+            estimate: 0.0,
+            total_weight: 0.0,
+        }
+    }
+
+    fn sample(&mut self, weight: f64, value: f64) {
+        let adjusted_alpha = self.alpha.powf(weight);
+        self.estimate = value * (1.0 - adjusted_alpha) + adjusted_alpha * self.estimate;
+        self.total_weight += weight;
+    }
+
+    fn estimate(&self) -> f64 {
+        let zero_factor = 1.0 - self.alpha.powf(self.total_weight);
+        if zero_factor <= f64::EPSILON {
+            0.0
+        } else {
+            self.estimate / zero_factor
+        }
+    }
+}
+
+/// Adaptive bitrate controller that selects a variant stream by the estimated bandwidth.
+#[derive(Debug)]
+pub struct AbrController {
+    bandwidths: Vec<u64>,
+    is_failed: Vec<bool>,
+    current: usize,
+    estimator: BandwidthEstimator,
+    is_enabled: bool,
+    switch_up_factor: f64,
+    switch_down_factor: f64,
+}
+impl AbrController {
+    /// Makes a new `AbrController` instance.
+    ///
+    /// `bandwidths` are the `BANDWIDTH` attributes of the variants in the master playlist order.
+    ///
+    /// The initial variant is selected by `HlsPlayerConfig::initial_bandwidth`.
+    pub fn new(bandwidths: Vec<u64>, config: &HlsPlayerConfig) -> Self {
+        let mut this = AbrController {
+            is_failed: vec![false; bandwidths.len()],
+            bandwidths,
+            current: 0,
+            estimator: BandwidthEstimator::new(),
+            is_enabled: config.abr_enabled,
+            switch_up_factor: config.abr_switch_up_factor,
+            switch_down_factor: config.abr_switch_down_factor,
+        };
+        if let Some(bandwidth) = config.initial_bandwidth {
+            this.current = this.highest_variant_within(bandwidth as f64)
+                .unwrap_or_else(|| this.lowest_variant());
+        }
+        this
+    }
+
+    /// Returns the index of the variant being played.
+    pub fn current_variant(&self) -> usize {
+        self.current
+    }
+
+    /// Adds a sample of a segment fetched from the current variant.
+    ///
+    /// The variant is selectable again if it has failed before.
+    pub fn handle_segment_fetched(&mut self, bytes: usize, fetch_duration_ms: u32) {
+        self.estimator.add_sample(bytes, fetch_duration_ms);
+        self.is_failed[self.current] = false;
+    }
+
+    /// Makes the variants that have failed selectable again.
+    pub fn clear_failures(&mut self) {
+        for is_failed in &mut self.is_failed {
+            *is_failed = false;
+        }
+    }
+
+    /// Decides the variant that should be played next.
+    ///
+    /// If a switch is needed, it will return the index of the new variant.
+    /// This always returns `None` if `HlsPlayerConfig::abr_enabled` is `false`.
+    pub fn select_variant(&mut self) -> Option<usize> {
+        if !self.is_enabled {
+            return None;
+        }
+        let estimate = self.estimator.estimate()? as f64;
+        let current_bandwidth = self.bandwidths[self.current] as f64;
+
+        let next = if current_bandwidth > estimate * self.switch_down_factor {
+            self.highest_variant_within(estimate * self.switch_down_factor)
+                .unwrap_or_else(|| self.lowest_variant())
+        } else {
+            match self.highest_variant_within(estimate * self.switch_up_factor) {
+                Some(i) if self.bandwidths[i] as f64 > current_bandwidth => i,
+                _ => self.current,
+            }
+        };
+        if next == self.current {
+            None
+        } else {
+            self.current = next;
+            Some(next)
+        }
+    }
+
+    /// Excludes the current variant from the selection, and returns the variant to fall back to.
+    ///
+    /// The fallback is the highest variant within the bandwidth of the current one
+    /// (or the lowest one if there are no such variants). `None` is returned if all of
+    /// the variants have failed.
+    pub fn fallback_variant(&mut self) -> Option<usize> {
+        self.is_failed[self.current] = true;
+        let current_bandwidth = self.bandwidths[self.current] as f64;
+        let next = self.highest_variant_within(current_bandwidth)
+            .or_else(|| self.available_variants().min_by_key(|&(_, b)| b).map(|(i, _)| i))?;
+        self.current = next;
+        Some(next)
+    }
+
+    fn available_variants<'a>(&'a self) -> impl Iterator<Item = (usize, u64)> + 'a {
+        self.bandwidths
+            .iter()
+            .enumerate()
+            .filter(move |&(i, _)| !self.is_failed[i])
+            .map(|(i, &b)| (i, b))
+    }
+
+    fn highest_variant_within(&self, limit: f64) -> Option<usize> {
+        self.available_variants()
+            .filter(|&(_, b)| b as f64 <= limit)
+            .max_by_key(|&(_, b)| b)
+            .map(|(i, _)| i)
+    }
+
+    fn lowest_variant(&self) -> usize {
+        self.available_variants()
+            .min_by_key(|&(_, b)| b)
+            .map_or(self.current, |(i, _)| i)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BANDWIDTHS: [u64; 4] = [500_000, 1_000_000, 2_000_000, 4_000_000];
+
+    /// Adds samples of `bits_per_sec` for `secs` seconds.
+    fn add_samples(estimator: &mut BandwidthEstimator, bits_per_sec: u64, secs: u32) {
+        for _ in 0..secs {
+            estimator.add_sample((bits_per_sec / 8) as usize, 1000);
+        }
+    }
+
+    fn controller(config: &HlsPlayerConfig, bits_per_sec: u64) -> AbrController {
+        let mut abr = AbrController::new(BANDWIDTHS.to_vec(), config);
+        add_samples(&mut abr.estimator, bits_per_sec, 10);
+        abr
+    }
+
+    #[test]
+    fn estimate_of_constant_bandwidth() {
+        let mut estimator = BandwidthEstimator::new();
+        estimator.add_sample(8 * 1024, 100);
+        assert_eq!(estimator.estimate(), None);
+
+        estimator.add_sample(8 * 1024, 100);
+        let estimate = estimator.estimate().unwrap();
+        assert!((estimate as f64 - 655_360.0).abs() <= 1.0, "{}", estimate);
+    }
+
+    #[test]
+    fn estimate_follows_drops_faster_than_bursts() {
+        let mut estimator = BandwidthEstimator::new();
+        add_samples(&mut estimator, 1_000_000, 30);
+
+        // The fast average is used after a drop
+        add_samples(&mut estimator, 250_000, 2);
+        let estimate = estimator.estimate().unwrap();
+        assert_eq!(estimate, estimator.fast.estimate() as u64);
+        assert!(estimate > 250_000 && estimate < 1_000_000, "{}", estimate);
+
+        // The slow average is used after a burst
+        add_samples(&mut estimator, 8_000_000, 2);
+        let estimate = estimator.estimate().unwrap();
+        assert_eq!(estimate, estimator.slow.estimate() as u64);
+        assert!(estimate < 4_000_000, "{}", estimate);
+    }
+
+    #[test]
+    fn initial_variant() {
+        let mut config = HlsPlayerConfig::default();
+        assert_eq!(AbrController::new(BANDWIDTHS.to_vec(), &config).current_variant(), 0);
+
+        config.initial_bandwidth = Some(1_500_000);
+        assert_eq!(AbrController::new(BANDWIDTHS.to_vec(), &config).current_variant(), 1);
+
+        config.initial_bandwidth = Some(100_000);
+        assert_eq!(AbrController::new(BANDWIDTHS.to_vec(), &config).current_variant(), 0);
+    }
+
+    #[test]
+    fn variant_selection_by_bandwidth() {
+        let config = HlsPlayerConfig::default();
+
+        // No estimate yet
+        let mut abr = AbrController::new(BANDWIDTHS.to_vec(), &config);
+        assert_eq!(abr.select_variant(), None);
+
+        // Switches up to the highest variant within 70% of the estimate
+        let mut abr = controller(&config, 3_000_000);
+        assert_eq!(abr.select_variant(), Some(2));
+        assert_eq!(abr.current_variant(), 2);
+        assert_eq!(abr.select_variant(), None);
+
+        // Stays while the current variant is within 90% of the estimate
+        abr.estimator = BandwidthEstimator::new();
+        add_samples(&mut abr.estimator, 2_300_000, 10);
+        assert_eq!(abr.select_variant(), None);
+
+        // Switches down below 90% of the estimate
+        abr.estimator = BandwidthEstimator::new();
+        add_samples(&mut abr.estimator, 2_000_000, 10);
+        assert_eq!(abr.select_variant(), Some(1));
+
+        // Falls to the lowest variant if none is within the estimate
+        abr.estimator = BandwidthEstimator::new();
+        add_samples(&mut abr.estimator, 200_000, 10);
+        assert_eq!(abr.select_variant(), Some(0));
+    }
+
+    #[test]
+    fn disabled_abr_never_switches() {
+        let config = HlsPlayerConfig {
+            abr_enabled: false,
+            ..HlsPlayerConfig::default()
+        };
+        let mut abr = controller(&config, 10_000_000);
+        assert_eq!(abr.select_variant(), None);
+        assert_eq!(abr.current_variant(), 0);
+    }
+
+    #[test]
+    fn failed_variants_are_excluded_until_cleared() {
+        let config = HlsPlayerConfig {
+            initial_bandwidth: Some(10_000_000),
+            ..HlsPlayerConfig::default()
+        };
+        let mut abr = controller(&config, 10_000_000);
+        assert_eq!(abr.current_variant(), 3);
+
+        assert_eq!(abr.fallback_variant(), Some(2));
+        assert_eq!(abr.fallback_variant(), Some(1));
+        assert_eq!(abr.select_variant(), None);
+
+        abr.clear_failures();
+        assert_eq!(abr.select_variant(), Some(3));
+    }
+
+    #[test]
+    fn fallback_from_the_lowest_variant() {
+        let mut abr = controller(&HlsPlayerConfig::default(), 100_000);
+        assert_eq!(abr.fallback_variant(), Some(1));
+        assert_eq!(abr.fallback_variant(), Some(2));
+        assert_eq!(abr.fallback_variant(), Some(3));
+        assert_eq!(abr.fallback_variant(), None);
+        assert_eq!(abr.is_failed, [true; 4]);
+
+        // A segment fetched from the current variant shows that it works again
+        abr.handle_segment_fetched(1000, 10);
+        assert_eq!(abr.is_failed, [true, true, true, false]);
+    }
+}
@@ -10,6 +10,7 @@ pub enum Action {
     FetchData {
         action_id: ActionId,
         #[serde(with = "url_serde")] url: Url,
+        #[serde(skip_serializing_if = "Option::is_none")] range: Option<ByteRange>,
     },
     SetTimeout {
         action_id: ActionId,
@@ -25,6 +26,19 @@ impl Action {
     }
 }
 
+/// Byte range of a resource to be fetched.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+pub struct ByteRange {
+    pub offset: u64,
+    pub length: u64,
+}
+impl ByteRange {
+    /// Returns the offset of the byte following this range.
+    pub fn end(&self) -> u64 {
+        self.offset + self.length
+    }
+}
+
 #[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
 pub struct ActionId(u32);
 impl ActionId {
@@ -33,7 +47,7 @@ impl ActionId {
     }
 
     fn next(&mut self) -> ActionId {
-        let id = self.clone();
+        let id = *self;
         self.0 += 1;
         id
     }
@@ -51,7 +65,7 @@ pub struct ActionFactory {
 impl ActionFactory {
     pub fn new(stream_id: StreamId) -> Self {
         ActionFactory {
-            next_action_id: ActionId(u32::from(stream_id) << 8),
+            next_action_id: ActionId(u32::from(stream_id) << 24),
         }
     }
 
@@ -60,13 +74,21 @@ impl ActionFactory {
     }
 
     pub fn fetch_data(&mut self, url: Url) -> Action {
+        self.fetch_data_range(url, None)
+    }
+
+    pub fn fetch_data_range(&mut self, url: Url, range: Option<ByteRange>) -> Action {
         let action_id = self.next_action_id.next();
-        Action::FetchData { action_id, url }
+        Action::FetchData {
+            action_id,
+            url,
+            range,
+        }
     }
 
     pub fn set_timeout(&mut self, duration: Duration) -> Action {
         let action_id = self.next_action_id.next();
-        let duration = (duration.as_secs() * 1000) as u32 + duration.subsec_nanos() / 1_000_000;
+        let duration = (duration.as_secs() * 1000) as u32 + duration.subsec_millis();
         Action::SetTimeout {
             action_id,
             duration,
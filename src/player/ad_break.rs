@@ -0,0 +1,226 @@
+//! Ad break events signaled by playlist tags or SCTE-35 cues.
+use super::playlist_tags::{DateRange, Tag};
+use super::scte35::{self, SpliceSignal};
+
+/// Where an ad break was signaled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum AdMarkerSource {
+    #[serde(rename = "DATERANGE")]
+    DateRange,
+
+    /// `EXT-X-CUE-OUT` and `EXT-X-CUE-IN`.
+    #[serde(rename = "CUE")]
+    Cue,
+
+    /// SCTE-35 sections in the TS segments.
+    #[serde(rename = "SCTE35")]
+    Scte35,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "type")]
+pub enum AdEvent {
+    BreakStart {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+
+        /// Position of the start of the break in seconds.
+        time: f64,
+
+        /// Duration of the break in seconds.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        duration: Option<f64>,
+
+        source: AdMarkerSource,
+    },
+    BreakEnd {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+
+        /// Position of the end of the break in seconds.
+        time: f64,
+
+        source: AdMarkerSource,
+    },
+}
+impl AdEvent {
+    /// Makes the events signaled by a playlist tag followed by the segment starting at `time`.
+    pub fn from_tag(tag: &Tag, time: f64) -> Vec<AdEvent> {
+        match *tag {
+            Tag::DateRange(ref x) => Self::from_date_range(x, time),
+            Tag::CueOut { duration } => vec![AdEvent::BreakStart {
+                id: None,
+                time,
+                duration,
+                source: AdMarkerSource::Cue,
+            }],
+            Tag::CueIn => vec![AdEvent::BreakEnd {
+                id: None,
+                time,
+                source: AdMarkerSource::Cue,
+            }],
+        }
+    }
+
+    /// Makes the event corresponding to `signal` occurring at `time`.
+    pub fn from_splice_signal(signal: &SpliceSignal, time: f64, source: AdMarkerSource) -> Self {
+        let id = Some(signal.event_id.to_string());
+        Self::splice_event(signal, id, time, source)
+    }
+
+    fn splice_event(
+        signal: &SpliceSignal,
+        id: Option<String>,
+        time: f64,
+        source: AdMarkerSource,
+    ) -> Self {
+        if signal.is_out {
+            AdEvent::BreakStart {
+                id,
+                time,
+                duration: signal.duration,
+                source,
+            }
+        } else {
+            AdEvent::BreakEnd { id, time, source }
+        }
+    }
+
+    fn from_date_range(date_range: &DateRange, time: f64) -> Vec<AdEvent> {
+        let source = AdMarkerSource::DateRange;
+        let id = Some(date_range.id.clone());
+        let mut events = Vec::new();
+        if let Some(ref out) = date_range.scte35_out {
+            let cue_duration = scte35::parse_splice_info_section(out)
+                .ok()
+                .and_then(|signals| signals.into_iter().filter_map(|s| s.duration).next());
+            events.push(AdEvent::BreakStart {
+                id: id.clone(),
+                time,
+                duration: date_range
+                    .duration
+                    .or(date_range.planned_duration)
+                    .or(cue_duration),
+                source,
+            });
+        }
+        if let Some(ref cmd) = date_range.scte35_cmd {
+            for signal in scte35::parse_splice_info_section(cmd).unwrap_or_default() {
+                events.push(Self::splice_event(&signal, id.clone(), time, source));
+            }
+        }
+        if date_range.scte35_in.is_some() {
+            events.push(AdEvent::BreakEnd { id, time, source });
+        }
+        events
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::playlist_tags;
+    use super::super::scte35::tests::{hex, SPLICE_INSERT, TIME_SIGNAL};
+
+    /// Returns the events signaled by the tags in a playlist whose segments are 10 seconds long.
+    fn events(tags: &str) -> Vec<AdEvent> {
+        let m3u8 = format!(
+            "#EXTM3U\n#EXT-X-TARGETDURATION:10\n#EXTINF:10,\na.ts\n{}#EXTINF:10,\nb.ts\n",
+            tags
+        );
+        let parsed = playlist_tags::parse_media_playlist(&m3u8).unwrap();
+        parsed
+            .segment_tags
+            .iter()
+            .flat_map(|t| AdEvent::from_tag(&t.tag, 10.0 * t.segment_index as f64))
+            .collect()
+    }
+
+    #[test]
+    fn date_range_with_scte35_out() {
+        let tags = format!(
+            "#EXT-X-DATERANGE:ID=\"splice-4800008F\",START-DATE=\"2014-03-05T11:15:00Z\",\
+             SCTE35-OUT=0x{}\n",
+            SPLICE_INSERT.to_uppercase()
+        );
+        assert_eq!(
+            events(&tags),
+            [AdEvent::BreakStart {
+                id: Some("splice-4800008F".to_owned()),
+                time: 10.0,
+                duration: Some(f64::from(0x0052_CCF5) / 90_000.0),
+                source: AdMarkerSource::DateRange,
+            }]
+        );
+
+        // PLANNED-DURATION takes precedence over the duration of the cue
+        let tags = tags.replace(",SCTE35-OUT", ",PLANNED-DURATION=59.993,SCTE35-OUT");
+        match events(&tags)[0] {
+            AdEvent::BreakStart { duration, .. } => assert_eq!(duration, Some(59.993)),
+            ref e => panic!("Unexpected event: {:?}", e),
+        }
+    }
+
+    #[test]
+    fn date_range_with_scte35_in_and_cmd() {
+        let tags = format!(
+            "#EXT-X-DATERANGE:ID=\"a\",START-DATE=\"2014-03-05T11:16:00Z\",SCTE35-IN=0x{}\n\
+             #EXT-X-DATERANGE:ID=\"b\",START-DATE=\"2014-03-05T11:16:00Z\",SCTE35-CMD=0x{}\n",
+            SPLICE_INSERT, TIME_SIGNAL
+        );
+        assert_eq!(
+            events(&tags),
+            [
+                AdEvent::BreakEnd {
+                    id: Some("a".to_owned()),
+                    time: 10.0,
+                    source: AdMarkerSource::DateRange,
+                },
+                AdEvent::BreakStart {
+                    id: Some("b".to_owned()),
+                    time: 10.0,
+                    duration: Some(307.0),
+                    source: AdMarkerSource::DateRange,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn cue_out_and_cue_in() {
+        let events = events("#EXT-X-CUE-OUT:DURATION=30\n#EXT-X-CUE-IN\n");
+        assert_eq!(
+            events,
+            [
+                AdEvent::BreakStart {
+                    id: None,
+                    time: 10.0,
+                    duration: Some(30.0),
+                    source: AdMarkerSource::Cue,
+                },
+                AdEvent::BreakEnd {
+                    id: None,
+                    time: 10.0,
+                    source: AdMarkerSource::Cue,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn splice_signal_in_ts_segment() {
+        let signal = scte35::parse_splice_info_section(&hex(SPLICE_INSERT))
+            .unwrap()
+            .remove(0);
+        let event = AdEvent::from_splice_signal(&signal, 12.5, AdMarkerSource::Scte35);
+        assert_eq!(
+            event,
+            AdEvent::BreakStart {
+                id: Some("1207959695".to_owned()),
+                time: 12.5,
+                duration: signal.duration,
+                source: AdMarkerSource::Scte35,
+            }
+        );
+    }
+}
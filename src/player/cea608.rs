@@ -0,0 +1,494 @@
+//! CEA-608 closed captions carried in H.264 SEI messages.
+//!
+//! Only the first caption channel (CC1) is decoded.
+//! CEA-708 (DTVCC) services are not supported, but most CEA-708 streams also carry
+//! the CEA-608 compatible byte pairs.
+//!
+//! See: [ANSI/SCTE 128-1](https://www.scte.org/standards/) and CEA-608-E
+use std::collections::BTreeMap;
+use std::mem;
+
+use super::h264;
+use super::webvtt::Cue;
+
+const SEI_PAYLOAD_TYPE_USER_DATA_REGISTERED: u32 = 4;
+const ITU_T_T35_COUNTRY_CODE_US: u8 = 0xB5;
+const ATSC_PROVIDER_CODE: u16 = 0x0031;
+const ATSC_USER_IDENTIFIER: &[u8] = b"GA94";
+const USER_DATA_TYPE_CC_DATA: u8 = 0x03;
+
+/// `cc_type` of the byte pairs in the first field of line 21.
+const CC_TYPE_NTSC_FIELD1: u8 = 0;
+
+/// Bottom row of the caption grid.
+const BOTTOM_ROW: u8 = 15;
+
+/// Characters of the basic North American character set that differ from ASCII.
+const BASIC_CHARS: [(u8, char); 10] = [
+    (0x2A, 'á'),
+    (0x5C, 'é'),
+    (0x5E, 'í'),
+    (0x5F, 'ó'),
+    (0x60, 'ú'),
+    (0x7B, 'ç'),
+    (0x7C, '÷'),
+    (0x7D, 'Ñ'),
+    (0x7E, 'ñ'),
+    (0x7F, '█'),
+];
+
+const SPECIAL_CHARS: [char; 16] = [
+    '®', '°', '½', '¿', '™', '¢', '£', '♪', 'à', '\u{A0}', 'è', 'â', 'ê', 'î', 'ô', 'û',
+];
+
+const EXTENDED_CHARS_1: [char; 32] = [
+    'Á', 'É', 'Ó', 'Ú', 'Ü', 'ü', '‘', '¡', '*', '\'', '—', '©', '℠', '•', '“', '”',
+    'À', 'Â', 'Ç', 'È', 'Ê', 'Ë', 'ë', 'Î', 'Ï', 'ï', 'Ô', 'Ù', 'ù', 'Û', '«', '»',
+];
+
+const EXTENDED_CHARS_2: [char; 32] = [
+    'Ã', 'ã', 'Í', 'Ì', 'ì', 'Ò', 'ò', 'Õ', 'õ', '{', '}', '\\', '^', '_', '|', '~',
+    'Ä', 'ä', 'Ö', 'ö', 'ß', '¥', '¤', '¦', 'Å', 'å', 'Ø', 'ø', '┌', '┐', '└', '┘',
+];
+
+/// Returns the CEA-608 byte pairs of the first field contained in the SEI NAL unit.
+///
+/// Malformed SEI messages are ignored.
+pub fn extract_cc_data(sei_nal_unit: &[u8]) -> Vec<(u8, u8)> {
+    let rbsp = h264::remove_emulation_prevention_bytes(sei_nal_unit);
+    let mut pairs = Vec::new();
+    let mut rest = rbsp.get(1..).unwrap_or(&[]);
+    while rest.len() > 1 {
+        let (payload_type, size) = if let Some(x) = read_sei_header(&mut rest) {
+            x
+        } else {
+            break;
+        };
+        if rest.len() < size {
+            break;
+        }
+        let (payload, tail) = rest.split_at(size);
+        if payload_type == SEI_PAYLOAD_TYPE_USER_DATA_REGISTERED {
+            extract_cc_data_from_t35(payload, &mut pairs);
+        }
+        rest = tail;
+    }
+    pairs
+}
+
+fn read_sei_header(rest: &mut &[u8]) -> Option<(u32, usize)> {
+    let mut read_value = || {
+        let mut value = 0;
+        loop {
+            let (&b, tail) = rest.split_first()?;
+            *rest = tail;
+            value += u32::from(b);
+            if b != 0xFF {
+                return Some(value);
+            }
+        }
+    };
+    let payload_type = read_value()?;
+    let size = read_value()?;
+    Some((payload_type, size as usize))
+}
+
+fn extract_cc_data_from_t35(payload: &[u8], pairs: &mut Vec<(u8, u8)>) {
+    if payload.len() < 10
+        || payload[0] != ITU_T_T35_COUNTRY_CODE_US
+        || (u16::from(payload[1]) << 8 | u16::from(payload[2])) != ATSC_PROVIDER_CODE
+        || &payload[3..7] != ATSC_USER_IDENTIFIER
+        || payload[7] != USER_DATA_TYPE_CC_DATA
+    {
+        return;
+    }
+    let process_cc_data = payload[8] & 0b0100_0000 != 0;
+    let cc_count = (payload[8] & 0b0001_1111) as usize;
+    if !process_cc_data {
+        return;
+    }
+    for cc in payload[10..].chunks(3).take(cc_count) {
+        if cc.len() < 3 {
+            break;
+        }
+        let cc_valid = cc[0] & 0b0000_0100 != 0;
+        let cc_type = cc[0] & 0b0000_0011;
+        if cc_valid && cc_type == CC_TYPE_NTSC_FIELD1 {
+            pairs.push((cc[1], cc[2]));
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    PopOn,
+    RollUp(usize),
+    PaintOn,
+    Text,
+}
+
+/// Caption memory (row number to text).
+type Memory = BTreeMap<u8, String>;
+
+/// Decoder that turns the byte pairs of CC1 into timed cues.
+#[derive(Debug)]
+pub struct Cea608Decoder {
+    mode: Mode,
+    channel: u8,
+    last_control_code: Option<(u8, u8)>,
+    row: u8,
+    displayed: Memory,
+    non_displayed: Memory,
+    displayed_since: Option<f64>,
+    cues: Vec<Cue>,
+}
+impl Cea608Decoder {
+    pub fn new() -> Self {
+        Cea608Decoder {
+            mode: Mode::PopOn,
+            channel: 1,
+            last_control_code: None,
+            row: BOTTOM_ROW,
+            displayed: Memory::new(),
+            non_displayed: Memory::new(),
+            displayed_since: None,
+            cues: Vec::new(),
+        }
+    }
+
+    /// Returns the cues whose display has ended since the last call.
+    pub fn take_cues(&mut self) -> Vec<Cue> {
+        mem::take(&mut self.cues)
+    }
+
+    /// Handles a byte pair presented at `time` (in seconds).
+    pub fn handle_cc_data(&mut self, time: f64, cc_data_1: u8, cc_data_2: u8) {
+        let b1 = cc_data_1 & 0x7F;
+        let b2 = cc_data_2 & 0x7F;
+        if b1 == 0 && b2 == 0 {
+            return;
+        }
+
+        if (0x10..=0x1F).contains(&b1) {
+            // Control codes are transmitted twice for redundancy
+            if self.last_control_code == Some((b1, b2)) {
+                self.last_control_code = None;
+                return;
+            }
+            self.last_control_code = Some((b1, b2));
+            self.channel = if b1 & 0x08 == 0 { 1 } else { 2 };
+            if self.channel == 1 {
+                self.handle_control_code(time, b1, b2);
+            }
+        } else {
+            self.last_control_code = None;
+            if self.channel != 1 || self.mode == Mode::Text {
+                return;
+            }
+            self.write_char(time, basic_char(b1));
+            if b2 >= 0x20 {
+                self.write_char(time, basic_char(b2));
+            }
+        }
+    }
+
+    fn handle_control_code(&mut self, time: f64, b1: u8, b2: u8) {
+        match (b1, b2) {
+            (0x14, 0x20..=0x2F) | (0x15, 0x20..=0x2F) => self.handle_misc_control_code(time, b2),
+            (0x17, 0x21..=0x23) => {
+                // Tab offsets
+            }
+            (0x11, 0x20..=0x2F) => {
+                // Mid-row codes (styles) occupy a space
+                self.write_char(time, ' ');
+            }
+            (0x11, 0x30..=0x3F) => {
+                self.write_char(time, SPECIAL_CHARS[(b2 - 0x30) as usize]);
+            }
+            (0x12, 0x20..=0x3F) | (0x13, 0x20..=0x3F) => {
+                // Extended characters replace the preceding basic character
+                let table = if b1 == 0x12 {
+                    &EXTENDED_CHARS_1
+                } else {
+                    &EXTENDED_CHARS_2
+                };
+                self.backspace(time);
+                self.write_char(time, table[(b2 - 0x20) as usize]);
+            }
+            (_, 0x40..=0x7F) => {
+                // Roll-up captions are always written to the bottom row
+                if let Some(row) = pac_row(b1, b2) {
+                    if !matches!(self.mode, Mode::RollUp(_)) {
+                        self.row = row;
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn handle_misc_control_code(&mut self, time: f64, code: u8) {
+        match code {
+            0x20 => self.mode = Mode::PopOn,
+            0x21 => self.backspace(time),
+            0x25..=0x27 => {
+                if !matches!(self.mode, Mode::RollUp(_)) {
+                    self.end_displayed_cue(time);
+                    self.displayed.clear();
+                    self.non_displayed.clear();
+                }
+                self.mode = Mode::RollUp((code - 0x23) as usize);
+                self.row = BOTTOM_ROW;
+            }
+            0x29 => self.mode = Mode::PaintOn,
+            0x2A | 0x2B => self.mode = Mode::Text,
+            0x2C => {
+                self.end_displayed_cue(time);
+                self.displayed.clear();
+            }
+            0x2D => {
+                if let Mode::RollUp(rows) = self.mode {
+                    self.end_displayed_cue(time);
+                    self.roll_up(rows);
+                    if !self.displayed.is_empty() {
+                        self.displayed_since = Some(time);
+                    }
+                }
+            }
+            0x2E => self.non_displayed.clear(),
+            0x2F => {
+                self.end_displayed_cue(time);
+                mem::swap(&mut self.displayed, &mut self.non_displayed);
+                self.non_displayed.clear();
+                if !self.displayed.is_empty() {
+                    self.displayed_since = Some(time);
+                }
+                self.mode = Mode::PopOn;
+            }
+            _ => {}
+        }
+    }
+
+    fn write_char(&mut self, time: f64, c: char) {
+        let row = self.row;
+        if let Some(memory) = self.target_memory(time) {
+            memory.entry(row).or_default().push(c);
+        }
+    }
+
+    fn backspace(&mut self, time: f64) {
+        let row = self.row;
+        if let Some(memory) = self.target_memory(time) {
+            if let Some(text) = memory.get_mut(&row) {
+                text.pop();
+            }
+        }
+    }
+
+    /// Returns the memory modified by the current mode.
+    fn target_memory(&mut self, time: f64) -> Option<&mut Memory> {
+        match self.mode {
+            Mode::PopOn => Some(&mut self.non_displayed),
+            Mode::RollUp(_) | Mode::PaintOn => {
+                self.displayed_since.get_or_insert(time);
+                Some(&mut self.displayed)
+            }
+            Mode::Text => None,
+        }
+    }
+
+    fn roll_up(&mut self, rows: usize) {
+        let mut lines = mem::take(&mut self.displayed)
+            .into_values()
+            .collect::<Vec<_>>();
+        let excess = (lines.len() + 1).saturating_sub(rows);
+        lines.drain(..excess);
+        let first_row = BOTTOM_ROW - lines.len() as u8;
+        for (i, text) in lines.into_iter().enumerate() {
+            self.displayed.insert(first_row + i as u8, text);
+        }
+    }
+
+    fn end_displayed_cue(&mut self, time: f64) {
+        let start = if let Some(start) = self.displayed_since.take() {
+            start
+        } else {
+            return;
+        };
+        let text = self.displayed
+            .values()
+            .map(|t| t.trim())
+            .filter(|t| !t.is_empty())
+            .collect::<Vec<_>>()
+            .join("\n");
+        if !text.is_empty() && start < time {
+            self.cues.push(Cue {
+                id: None,
+                start,
+                end: time,
+                settings: String::new(),
+                text,
+            });
+        }
+    }
+}
+impl Default for Cea608Decoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn basic_char(b: u8) -> char {
+    BASIC_CHARS
+        .iter()
+        .find(|x| x.0 == b)
+        .map_or(b as char, |x| x.1)
+}
+
+/// Returns the row (1..=15) designated by the preamble address code.
+fn pac_row(b1: u8, b2: u8) -> Option<u8> {
+    let first_row = match b1 {
+        0x11 => 1,
+        0x12 => 3,
+        0x15 => 5,
+        0x16 => 7,
+        0x17 => 9,
+        0x10 => return if b2 < 0x60 { Some(11) } else { None },
+        0x13 => 12,
+        0x14 => 14,
+        _ => return None,
+    };
+    Some(if b2 < 0x60 { first_row } else { first_row + 1 })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Sets the odd parity bit of a CEA-608 byte.
+    fn parity(b: u8) -> u8 {
+        if b.count_ones() % 2 == 1 {
+            b
+        } else {
+            b | 0x80
+        }
+    }
+
+    /// Feeds the pairs (in characters or control codes) at `time` to `decoder`.
+    fn feed(decoder: &mut Cea608Decoder, time: f64, pairs: &[(u8, u8)]) {
+        for &(b1, b2) in pairs {
+            decoder.handle_cc_data(time, parity(b1), parity(b2));
+        }
+    }
+
+    /// Control codes are sent twice.
+    fn control(code: (u8, u8)) -> [(u8, u8); 2] {
+        [code, code]
+    }
+
+    fn text(s: &str) -> Vec<(u8, u8)> {
+        s.as_bytes()
+            .chunks(2)
+            .map(|c| (c[0], c.get(1).cloned().unwrap_or(0)))
+            .collect()
+    }
+
+    const RCL: (u8, u8) = (0x14, 0x20);
+    const RU2: (u8, u8) = (0x14, 0x25);
+    const EDM: (u8, u8) = (0x14, 0x2C);
+    const CR: (u8, u8) = (0x14, 0x2D);
+    const EOC: (u8, u8) = (0x14, 0x2F);
+    const PAC_ROW_15: (u8, u8) = (0x14, 0x70);
+
+    #[test]
+    fn cc_data_in_sei() {
+        // SEI (user_data_registered_itu_t_t35) followed by the RBSP trailing bits
+        let sei = [
+            0x06, 0x04, 0x17, 0xB5, 0x00, 0x31, 0x47, 0x41, 0x39, 0x34, 0x03, 0x44, 0xFF,
+            0xFC, 0x94, 0x20, // CC1
+            0xFD, 0x80, 0x80, // Field 2
+            0xF8, 0xC8, 0x45, // Invalid
+            0xFC, 0xC8, 0x45, // CC1
+            0xFF, 0x80,
+        ];
+        assert_eq!(extract_cc_data(&sei), [(0x94, 0x20), (0xC8, 0x45)]);
+
+        // Other registered user data
+        let mut other = sei;
+        other[6..10].copy_from_slice(b"DTG1");
+        assert!(extract_cc_data(&other).is_empty());
+    }
+
+    #[test]
+    fn pop_on_captions() {
+        let mut decoder = Cea608Decoder::new();
+        feed(&mut decoder, 1.0, &control(RCL));
+        feed(&mut decoder, 1.0, &control(PAC_ROW_15));
+        feed(&mut decoder, 1.0, &text("HELLO, WORLD"));
+        assert!(decoder.take_cues().is_empty());
+
+        feed(&mut decoder, 2.0, &control(EOC));
+        feed(&mut decoder, 4.5, &control(EDM));
+        assert_eq!(
+            decoder.take_cues(),
+            [Cue {
+                id: None,
+                start: 2.0,
+                end: 4.5,
+                settings: String::new(),
+                text: "HELLO, WORLD".to_owned(),
+            }]
+        );
+        assert!(decoder.take_cues().is_empty());
+    }
+
+    #[test]
+    fn roll_up_captions() {
+        let mut decoder = Cea608Decoder::new();
+        feed(&mut decoder, 1.0, &control(RU2));
+        feed(&mut decoder, 1.0, &text("FIRST"));
+        feed(&mut decoder, 2.0, &control(CR));
+        feed(&mut decoder, 2.0, &text("SECOND"));
+        feed(&mut decoder, 3.0, &control(CR));
+        feed(&mut decoder, 3.0, &text("THIRD"));
+        feed(&mut decoder, 4.0, &control(CR));
+
+        let cues = decoder.take_cues();
+        let cues = cues
+            .iter()
+            .map(|c| (c.start, c.end, c.text.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            cues,
+            [
+                (1.0, 2.0, "FIRST"),
+                (2.0, 3.0, "FIRST\nSECOND"),
+                (3.0, 4.0, "SECOND\nTHIRD"),
+            ]
+        );
+    }
+
+    #[test]
+    fn special_and_extended_characters() {
+        let mut decoder = Cea608Decoder::new();
+        feed(&mut decoder, 1.0, &control(RCL));
+        feed(&mut decoder, 1.0, &control((0x11, 0x37))); // ♪
+        feed(&mut decoder, 1.0, &[(0x20, 0x43), (0x41, 0x46)]); // " CAF"
+        feed(&mut decoder, 1.0, &[(0x45, 0x00)]);
+        feed(&mut decoder, 1.0, &control((0x12, 0x21))); // É replaces E
+        feed(&mut decoder, 1.0, &[(0x20, 0x7E)]); // " ñ"
+        feed(&mut decoder, 1.0, &control(EOC));
+        feed(&mut decoder, 2.0, &control(EDM));
+        assert_eq!(decoder.take_cues()[0].text, "♪ CAFÉ ñ");
+    }
+
+    #[test]
+    fn second_channel_is_ignored() {
+        let mut decoder = Cea608Decoder::new();
+        feed(&mut decoder, 1.0, &control((0x1C, 0x20))); // RCL of CC2
+        feed(&mut decoder, 1.0, &text("IGNORED"));
+        feed(&mut decoder, 2.0, &control((0x1C, 0x2F)));
+        feed(&mut decoder, 3.0, &control((0x1C, 0x2C)));
+        assert!(decoder.take_cues().is_empty());
+    }
+}
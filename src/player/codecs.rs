@@ -0,0 +1,332 @@
+//! Codec strings ([RFC 6381]) of the streams fed to Media Source Extensions.
+//!
+//! [RFC 6381]: https://tools.ietf.org/html/rfc6381
+use std::cmp;
+use std::iter;
+use mse_fmp4::fmp4::{InitializationSegment, SampleEntry};
+
+/// Sample entry types of the video codecs that can be found in the `CODECS` attribute.
+const VIDEO_SAMPLE_ENTRY_TYPES: &[&str] = &[
+    "avc1", "avc3", "hvc1", "hev1", "dvh1", "dvhe", "av01", "vp08", "vp09"
+];
+
+/// Sample entry types of the audio codecs (including encrypted ones).
+const AUDIO_SAMPLE_ENTRY_TYPES: &[&[u8]] = &[
+    b"mp4a", b"enca", b"ac-3", b"ec-3", b"ac-4", b"Opus", b"fLaC", b"alac"
+];
+
+/// Makes the codecs string of the tracks contained in `initialization_segment`.
+pub fn from_initialization_segment(initialization_segment: &InitializationSegment) -> String {
+    let mut codecs = Vec::new();
+    for trak in &initialization_segment.moov_box.trak_boxes {
+        let entries = &trak.mdia_box.minf_box.stbl_box.stsd_box.sample_entries;
+        for entry in entries {
+            match *entry {
+                SampleEntry::Avc(ref x) => {
+                    let c = &x.avcc_box.configuration;
+                    codecs.push(format!(
+                        "avc1.{:02x}{:02x}{:02x}",
+                        c.profile_idc, c.constraint_set_flag, c.level_idc
+                    ));
+                }
+                SampleEntry::Aac(ref x) => {
+                    codecs.push(format!("mp4a.40.{}", x.esds_box.profile as u8 + 1));
+                }
+            }
+        }
+    }
+    codecs.join(",")
+}
+
+/// Makes the codecs string from the sample entries (`stsd`) of an fMP4 initialization section
+/// (`EXT-X-MAP`).
+///
+/// Returns `None` if no sample entries are found.
+pub fn from_init_section(data: &[u8]) -> Option<String> {
+    let mut codecs = Vec::new();
+    for moov in child_boxes(data, b"moov") {
+        for trak in child_boxes(moov, b"trak") {
+            let stsd = ["mdia", "minf", "stbl", "stsd"]
+                .iter()
+                .try_fold(trak, |data, kind| first_child_box(data, kind.as_bytes()));
+            if let Some(stsd) = stsd {
+                // Skips the version, flags and entry count
+                let entries = stsd.get(8..).unwrap_or(&[]);
+                codecs.extend(boxes(entries).map(|(kind, entry)| sample_entry_codec(kind, entry)));
+            }
+        }
+    }
+    if codecs.is_empty() {
+        None
+    } else {
+        Some(codecs.join(","))
+    }
+}
+
+fn sample_entry_codec(kind: &[u8], entry: &[u8]) -> String {
+    let children = entry.get(sample_entry_fields_len(kind)..).unwrap_or(&[]);
+    let kind = if kind == b"encv" || kind == b"enca" {
+        // Encrypted entries keep the original type in `sinf/frma`
+        first_child_box(children, b"sinf")
+            .and_then(|sinf| first_child_box(sinf, b"frma"))
+            .and_then(|frma| frma.get(..4))
+            .unwrap_or(kind)
+    } else {
+        kind
+    };
+    let name = String::from_utf8_lossy(kind).into_owned();
+    match kind {
+        b"avc1" | b"avc3" => first_child_box(children, b"avcC")
+            .and_then(|c| c.get(1..4))
+            .map(|c| format!("{}.{:02x}{:02x}{:02x}", name, c[0], c[1], c[2])),
+        b"hvc1" | b"hev1" => first_child_box(children, b"hvcC").and_then(|c| hevc_codec(&name, c)),
+        b"mp4a" => first_child_box(children, b"esds").and_then(mp4a_codec),
+        b"Opus" => Some("opus".to_owned()),
+        b"fLaC" => Some("flac".to_owned()),
+        _ => None,
+    }.unwrap_or(name)
+}
+
+/// Returns the size of the fields preceding the child boxes of a sample entry.
+fn sample_entry_fields_len(kind: &[u8]) -> usize {
+    if AUDIO_SAMPLE_ENTRY_TYPES.contains(&kind) {
+        28
+    } else {
+        78
+    }
+}
+
+/// Makes the codec string of HEVC from `HEVCDecoderConfigurationRecord`
+/// (ISO/IEC 14496-15 Annex E).
+fn hevc_codec(name: &str, config: &[u8]) -> Option<String> {
+    let config = config.get(..13)?;
+    let profile_space = ["", "A", "B", "C"][usize::from(config[1] >> 6)];
+    let tier = if config[1] & 0x20 != 0 { 'H' } else { 'L' };
+    let profile_idc = config[1] & 0x1F;
+    let compatibility = read_uint(&config[2..6]) as u32;
+    let mut codec = format!(
+        "{}.{}{}.{:X}.{}{}",
+        name,
+        profile_space,
+        profile_idc,
+        compatibility.reverse_bits(),
+        tier,
+        config[12]
+    );
+    let constraints = &config[6..12];
+    let len = constraints.iter().rposition(|&b| b != 0).map_or(0, |i| i + 1);
+    for b in &constraints[..len] {
+        codec.push_str(&format!(".{:X}", b));
+    }
+    Some(codec)
+}
+
+/// Makes the codec string (e.g., `mp4a.40.2`) from an `esds` box (ISO/IEC 14496-1).
+fn mp4a_codec(esds: &[u8]) -> Option<String> {
+    let (tag, es) = descriptor(esds.get(4..)?)?;
+    if tag != 0x03 {
+        return None;
+    }
+    let flags = *es.get(2)?;
+    let mut offset = 3;
+    if flags & 0x80 != 0 {
+        offset += 2;
+    }
+    if flags & 0x40 != 0 {
+        offset += 1 + usize::from(*es.get(offset)?);
+    }
+    if flags & 0x20 != 0 {
+        offset += 2;
+    }
+    let (tag, decoder_config) = descriptor(es.get(offset..)?)?;
+    if tag != 0x04 {
+        return None;
+    }
+    let object_type = *decoder_config.first()?;
+    let audio_object_type = decoder_config
+        .get(13..)
+        .and_then(descriptor)
+        .filter(|&(tag, _)| tag == 0x05)
+        .and_then(|(_, info)| info.first())
+        .map(|b| b >> 3)
+        .filter(|&x| x != 0 && x != 31);
+    Some(match audio_object_type {
+        Some(x) => format!("mp4a.{:x}.{}", object_type, x),
+        None => format!("mp4a.{:x}", object_type),
+    })
+}
+
+/// Splits the tag and the payload of an MPEG-4 descriptor at the head of `data`.
+fn descriptor(data: &[u8]) -> Option<(u8, &[u8])> {
+    let tag = *data.first()?;
+    let mut len = 0;
+    let mut offset = 1;
+    loop {
+        let b = *data.get(offset)?;
+        len = (len << 7) | usize::from(b & 0x7F);
+        offset += 1;
+        if b & 0x80 == 0 || offset == 5 {
+            break;
+        }
+    }
+    let end = cmp::min(offset + len, data.len());
+    Some((tag, &data[offset..end]))
+}
+
+fn first_child_box<'a>(data: &'a [u8], kind: &'a [u8]) -> Option<&'a [u8]> {
+    child_boxes(data, kind).next()
+}
+
+fn child_boxes<'a>(data: &'a [u8], kind: &'a [u8]) -> impl Iterator<Item = &'a [u8]> + 'a {
+    boxes(data).filter(move |&(k, _)| k == kind).map(|(_, payload)| payload)
+}
+
+/// Iterates over the `(type, payload)` pairs of the ISO BMFF boxes in `data`.
+fn boxes(mut data: &[u8]) -> impl Iterator<Item = (&[u8], &[u8])> {
+    iter::from_fn(move || {
+        let (header, size) = match read_uint(data.get(..4)?) {
+            0 => (8, data.len() as u64),
+            1 => (16, read_uint(data.get(8..16)?)),
+            size => (8, size),
+        };
+        if size < header || size > data.len() as u64 {
+            return None;
+        }
+        let (head, rest) = data.split_at(size as usize);
+        data = rest;
+        Some((&head[4..8], &head[header as usize..]))
+    })
+}
+
+fn read_uint(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0, |n, &b| (n << 8) | u64::from(b))
+}
+
+/// Makes the MIME type (e.g., `video/mp4; codecs="avc1.4d401f,mp4a.40.2"`) for `codecs`.
+///
+/// Streams without any video codec are regarded as audio-only.
+pub fn mime_type(codecs: &str) -> String {
+    let has_video = codecs.split(',').any(is_video_codec);
+    let media_type = if has_video { "video" } else { "audio" };
+    format!(r#"{}/mp4; codecs="{}""#, media_type, codecs)
+}
+
+/// Returns the video codecs contained in `codecs`.
+pub fn video_codecs(codecs: &str) -> Option<String> {
+    filter_codecs(codecs, true)
+}
+
+/// Returns the audio (i.e., non-video) codecs contained in `codecs`.
+pub fn audio_codecs(codecs: &str) -> Option<String> {
+    filter_codecs(codecs, false)
+}
+
+fn filter_codecs(codecs: &str, video: bool) -> Option<String> {
+    let filtered = codecs
+        .split(',')
+        .map(|c| c.trim())
+        .filter(|c| is_video_codec(c) == video)
+        .collect::<Vec<_>>();
+    if filtered.is_empty() {
+        None
+    } else {
+        Some(filtered.join(","))
+    }
+}
+
+fn is_video_codec(codec: &str) -> bool {
+    let sample_entry_type = codec.trim().split('.').next().unwrap_or("");
+    VIDEO_SAMPLE_ENTRY_TYPES.contains(&sample_entry_type)
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    fn mp4_box(kind: &[u8], payloads: &[&[u8]]) -> Vec<u8> {
+        let payload = payloads.concat();
+        let mut data = Vec::new();
+        let size = (8 + payload.len()) as u32;
+        for shift in &[24, 16, 8, 0] {
+            data.push((size >> shift) as u8);
+        }
+        data.extend_from_slice(kind);
+        data.extend_from_slice(&payload);
+        data
+    }
+
+    pub fn init_section(entries: &[Vec<u8>]) -> Vec<u8> {
+        let traks = entries
+            .iter()
+            .map(|entry| {
+                let stsd = mp4_box(b"stsd", &[&[0, 0, 0, 0, 0, 0, 0, 1], entry]);
+                let stbl = mp4_box(b"stbl", &[&stsd]);
+                let minf = mp4_box(b"minf", &[&stbl]);
+                let mdia = mp4_box(b"mdia", &[&mp4_box(b"mdhd", &[&[0; 24]]), &minf]);
+                mp4_box(b"trak", &[&mp4_box(b"tkhd", &[&[0; 84]]), &mdia])
+            })
+            .collect::<Vec<_>>();
+        let mut moov_payloads = vec![&[][..]];
+        moov_payloads.extend(traks.iter().map(|t| &t[..]));
+        let ftyp = mp4_box(b"ftyp", &[b"iso6", &[0, 0, 0, 0]]);
+        [ftyp, mp4_box(b"moov", &moov_payloads)].concat()
+    }
+
+    pub fn avc1_entry() -> Vec<u8> {
+        let avcc = mp4_box(b"avcC", &[&[1, 0x64, 0x00, 0x1F, 0xFF, 0xE0, 0x01]]);
+        mp4_box(b"avc1", &[&[0; 78], &avcc])
+    }
+
+    pub fn mp4a_entry() -> Vec<u8> {
+        let decoder_specific_info = [0x05, 0x02, 0x12, 0x10];
+        let decoder_config = [
+            &[0x04, 0x11, 0x40, 0x15, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0][..],
+            &decoder_specific_info,
+        ].concat();
+        let es = [
+            // Tag, length (in the four bytes form), ES_ID and flags
+            &[0x03, 0x80, 0x80, 0x80, 0x19, 0x00, 0x01, 0x00][..],
+            &decoder_config,
+            &[0x06, 0x01, 0x02],
+        ].concat();
+        let esds = mp4_box(b"esds", &[&[0, 0, 0, 0], &es]);
+        mp4_box(b"mp4a", &[&[0; 28], &esds])
+    }
+
+    #[test]
+    fn codecs_of_avc_and_aac_init_section() {
+        let data = init_section(&[avc1_entry(), mp4a_entry()]);
+        assert_eq!(
+            from_init_section(&data),
+            Some("avc1.64001f,mp4a.40.2".to_owned())
+        );
+    }
+
+    #[test]
+    fn codecs_of_hevc_init_section() {
+        let hvcc = mp4_box(
+            b"hvcC",
+            &[&[1, 0x01, 0x60, 0, 0, 0, 0xB0, 0, 0, 0, 0, 0, 93, 0xF0, 0]],
+        );
+        let data = init_section(&[mp4_box(b"hvc1", &[&[0; 78], &hvcc])]);
+        assert_eq!(from_init_section(&data), Some("hvc1.1.6.L93.B0".to_owned()));
+    }
+
+    #[test]
+    fn codecs_of_encrypted_init_section() {
+        let avcc = mp4_box(b"avcC", &[&[1, 0x4D, 0x40, 0x1E]]);
+        let sinf = mp4_box(b"sinf", &[&mp4_box(b"frma", &[b"avc1"])]);
+        let encv = mp4_box(b"encv", &[&[0; 78], &avcc, &sinf]);
+        let data = init_section(&[encv]);
+        assert_eq!(from_init_section(&data), Some("avc1.4d401e".to_owned()));
+    }
+
+    #[test]
+    fn codecs_of_unknown_init_section() {
+        assert_eq!(from_init_section(&[]), None);
+        assert_eq!(from_init_section(&[0, 0, 0, 1, 1, 2]), None);
+        let data = init_section(&[mp4_box(b"ac-3", &[&[0; 28]])]);
+        assert_eq!(from_init_section(&data), Some("ac-3".to_owned()));
+        assert_eq!(mime_type("ac-3"), r#"audio/mp4; codecs="ac-3""#);
+    }
+}
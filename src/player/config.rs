@@ -0,0 +1,149 @@
+use serde_json;
+use std::cmp;
+use std::time::Duration;
+
+use {Error, Result};
+
+/// Configuration of `HlsPlayer`.
+///
+/// When deserialized (e.g., from JSON), omitted fields take their default values.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HlsPlayerConfig {
+    /// Whether to switch variants by the estimated bandwidth.
+    ///
+    /// Even if disabled, variants that fail to be fetched are fallen back from.
+    pub abr_enabled: bool,
+
+    /// Bandwidth (in bits per second) assumed before any estimate is available.
+    ///
+    /// The initial variant is the highest one within it (or the lowest one).
+    /// If `None`, the first variant in the master playlist is played first.
+    pub initial_bandwidth: Option<u64>,
+
+    /// Ratio of the estimated bandwidth that a higher variant may consume.
+    pub abr_switch_up_factor: f64,
+
+    /// Ratio of the estimated bandwidth that the current variant may consume
+    /// before switching down.
+    pub abr_switch_down_factor: f64,
+
+    /// Position (in seconds) from which playback starts.
+    ///
+    /// Negative values are relative to the end of the playlist.
+    /// This takes precedence over the `EXT-X-START` tag of the playlist.
+    pub start_position: Option<f64>,
+
+    /// How far (in target durations) from the end of a live playlist playback starts.
+    ///
+    /// This is ignored if the playlist has an `EXT-X-START` tag, and is raised to
+    /// the `HOLD-BACK` of the playlist if it is shorter.
+    pub live_start_target_durations: u32,
+
+    /// Maximum number of segments fetched in parallel for each media playlist.
+    ///
+    /// Segments fetched ahead are delivered in sequence order.
+    pub max_segment_fetches: usize,
+
+    /// Amount of media (in seconds) to buffer ahead of the playback position.
+    ///
+    /// Segment fetching pauses while the media fetched but not yet consumed, plus the level
+    /// reported by `HlsPlayer::set_buffer_level()`, exceeds this.
+    pub max_forward_buffer_secs: f64,
+
+    /// Maximum number of times a failed fetch is retried.
+    pub max_fetch_retries: u32,
+
+    /// Delay (in milliseconds) before the first retry of a failed fetch.
+    ///
+    /// The delay is doubled for each subsequent retry, up to `max_retry_delay_ms`.
+    pub retry_delay_ms: u64,
+
+    /// Maximum delay (in milliseconds) between retries.
+    pub max_retry_delay_ms: u64,
+
+    /// Delay (in milliseconds) before returning to the primary stream of a variant
+    /// after failing over to a redundant one.
+    ///
+    /// Variants fallen back from are also selectable again after this delay.
+    pub primary_retry_interval_ms: u64,
+
+    /// Most verbose level of the messages logged by the player.
+    pub log_level: LogLevel,
+}
+impl Default for HlsPlayerConfig {
+    fn default() -> Self {
+        HlsPlayerConfig {
+            abr_enabled: true,
+            initial_bandwidth: None,
+            abr_switch_up_factor: 0.7,
+            abr_switch_down_factor: 0.9,
+            start_position: None,
+            live_start_target_durations: 3,
+            max_segment_fetches: 1,
+            max_forward_buffer_secs: 30.0,
+            max_fetch_retries: 3,
+            retry_delay_ms: 1000,
+            max_retry_delay_ms: 8000,
+            primary_retry_interval_ms: 30_000,
+            log_level: LogLevel::Info,
+        }
+    }
+}
+impl HlsPlayerConfig {
+    /// Parses a JSON object into a configuration.
+    pub fn from_json(json: &str) -> Result<Self> {
+        track!(serde_json::from_str(json).map_err(Error::from))
+    }
+
+    /// Returns the delay before retrying a fetch that has failed `attempts + 1` times.
+    pub fn retry_delay(&self, attempts: u32) -> Duration {
+        let delay = self.retry_delay_ms
+            .saturating_mul(1 << cmp::min(attempts, 16))
+            .min(self.max_retry_delay_ms);
+        Duration::from_millis(delay)
+    }
+}
+
+/// Severity of log messages.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    Error = 0,
+    Warn = 1,
+    Info = 2,
+    Debug = 3,
+}
+
+#[cfg(test)]
+mod tests {
+    use ErrorKind;
+    use super::*;
+
+    #[test]
+    fn omitted_fields_take_default_values() {
+        let config = HlsPlayerConfig::from_json("{}").unwrap();
+        let default = HlsPlayerConfig::default();
+        assert_eq!(config.abr_enabled, default.abr_enabled);
+        assert_eq!(config.start_position, default.start_position);
+        assert_eq!(config.max_segment_fetches, default.max_segment_fetches);
+        assert_eq!(config.log_level, default.log_level);
+
+        let json = r#"{"max_segment_fetches": 4, "start_position": -10, "log_level": "debug"}"#;
+        let config = HlsPlayerConfig::from_json(json).unwrap();
+        assert_eq!(config.max_segment_fetches, 4);
+        assert_eq!(config.start_position, Some(-10.0));
+        assert_eq!(config.log_level, LogLevel::Debug);
+        assert_eq!(config.max_fetch_retries, default.max_fetch_retries);
+    }
+
+    #[test]
+    fn invalid_fields_are_rejected() {
+        let kind = |json| HlsPlayerConfig::from_json(json).err().map(|e| *e.kind());
+
+        // Typos are not silently ignored
+        assert_eq!(kind(r#"{"max_segment_fetch": 4}"#), Some(ErrorKind::InvalidInput));
+        assert_eq!(kind(r#"{"abr_enabled": "yes"}"#), Some(ErrorKind::InvalidInput));
+        assert_eq!(kind(r#"{"log_level": "verbose"}"#), Some(ErrorKind::InvalidInput));
+    }
+}
@@ -0,0 +1,203 @@
+use aes::Aes128;
+use aes::cipher::{BlockDecrypt, KeyInit};
+use aes::cipher::generic_array::GenericArray;
+
+use {ErrorKind, Result};
+
+pub const BLOCK_SIZE: usize = 16;
+
+/// AES-128 key.
+pub type Key = [u8; 16];
+
+/// Initialization vector of AES-128-CBC.
+pub type Iv = [u8; BLOCK_SIZE];
+
+pub fn key_from_bytes(bytes: &[u8]) -> Result<Key> {
+    track_assert_eq!(
+        bytes.len(),
+        16,
+        ErrorKind::InvalidInput,
+        "Unexpected AES-128 key size"
+    );
+    let mut key = [0; 16];
+    key.copy_from_slice(bytes);
+    Ok(key)
+}
+
+/// Makes the IV that is used if the `IV` attribute of `EXT-X-KEY` is absent.
+///
+/// See: [5.2.  IV for AES-128](https://tools.ietf.org/html/rfc8216#section-5.2)
+pub fn iv_from_sequence_number(seq: u64) -> Iv {
+    let mut iv = [0; BLOCK_SIZE];
+    for (i, b) in iv[8..].iter_mut().enumerate() {
+        *b = (seq >> (56 - i * 8)) as u8;
+    }
+    iv
+}
+
+/// Decrypts `data` encrypted with AES-128-CBC and PKCS7 padding.
+pub fn decrypt_aes128_cbc(key: &Key, iv: &Iv, data: &[u8]) -> Result<Vec<u8>> {
+    track_assert_eq!(
+        data.len() % BLOCK_SIZE,
+        0,
+        ErrorKind::InvalidInput,
+        "Encrypted data is not aligned to the AES block size"
+    );
+    track_assert!(!data.is_empty(), ErrorKind::InvalidInput);
+
+    let mut plain = data.to_owned();
+    decrypt_cbc_in_place(key, iv, &mut plain);
+
+    let padding = *plain.last().expect("Never fails") as usize;
+    track_assert!(
+        (1..=BLOCK_SIZE).contains(&padding),
+        ErrorKind::InvalidInput,
+        "Invalid PKCS7 padding"
+    );
+    let plain_len = plain.len() - padding;
+    track_assert!(
+        plain[plain_len..].iter().all(|&b| b as usize == padding),
+        ErrorKind::InvalidInput,
+        "Invalid PKCS7 padding"
+    );
+    plain.truncate(plain_len);
+    Ok(plain)
+}
+
+/// Decrypts the whole blocks of `data` with AES-128-CBC in place.
+///
+/// The trailing bytes that do not fill a block are left untouched.
+pub fn decrypt_cbc_in_place(key: &Key, iv: &Iv, data: &mut [u8]) {
+    let cipher = Aes128::new(GenericArray::from_slice(key));
+    let mut prev = *iv;
+    for block in data.chunks_mut(BLOCK_SIZE) {
+        if block.len() < BLOCK_SIZE {
+            break;
+        }
+        let mut cipher_block = [0; BLOCK_SIZE];
+        cipher_block.copy_from_slice(block);
+        cipher.decrypt_block(GenericArray::from_mut_slice(block));
+        for (b, p) in block.iter_mut().zip(prev.iter()) {
+            *b ^= *p;
+        }
+        prev = cipher_block;
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use aes::cipher::BlockEncrypt;
+    use super::*;
+
+    /// Encrypts `data` with AES-128-CBC and PKCS7 padding.
+    pub fn encrypt_aes128_cbc(key: &Key, iv: &Iv, data: &[u8]) -> Vec<u8> {
+        let cipher = Aes128::new(GenericArray::from_slice(key));
+        let padding = BLOCK_SIZE - data.len() % BLOCK_SIZE;
+        let mut encrypted = data.to_owned();
+        encrypted.extend(vec![padding as u8; padding]);
+        let mut prev = *iv;
+        for block in encrypted.chunks_mut(BLOCK_SIZE) {
+            for (b, p) in block.iter_mut().zip(prev.iter()) {
+                *b ^= *p;
+            }
+            cipher.encrypt_block(GenericArray::from_mut_slice(block));
+            prev.copy_from_slice(block);
+        }
+        encrypted
+    }
+
+    // F.2.2 CBC-AES128.Decrypt of NIST SP 800-38A
+    const NIST_KEY: &str = "2b7e151628aed2a6abf7158809cf4f3c";
+    const NIST_IV: &str = "000102030405060708090a0b0c0d0e0f";
+    const NIST_PLAINTEXT: &str = concat!(
+        "6bc1bee22e409f96e93d7e117393172a",
+        "ae2d8a571e03ac9c9eb76fac45af8e51",
+        "30c81c46a35ce411e5fbc1191a0a52ef",
+        "f69f2445df4f9b17ad2b417be66c3710"
+    );
+    const NIST_CIPHERTEXT: &str = concat!(
+        "7649abac8119b246cee98e9b12e9197d",
+        "5086cb9b507219ee95db113a917678b2",
+        "73bed6b8e3c1743b7116e69e22229516",
+        "3ff1caa1681fac09120eca307586e1a7"
+    );
+
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    fn key() -> Key {
+        key_from_bytes(&hex(NIST_KEY)).unwrap()
+    }
+
+    #[test]
+    fn decrypt_blocks_with_known_vector() {
+        let mut iv = [0; BLOCK_SIZE];
+        iv.copy_from_slice(&hex(NIST_IV));
+        let mut data = hex(NIST_CIPHERTEXT);
+        decrypt_cbc_in_place(&key(), &iv, &mut data);
+        assert_eq!(data, hex(NIST_PLAINTEXT));
+    }
+
+    #[test]
+    fn decrypt_with_explicit_iv() {
+        let mut iv = [0; BLOCK_SIZE];
+        iv.copy_from_slice(&hex(NIST_IV));
+
+        // The NIST ciphertext followed by a block of PKCS7 padding
+        let mut data = hex(NIST_CIPHERTEXT);
+        data.extend(hex("8cb82807230e1321d3fae00d18cc2012"));
+        assert_eq!(decrypt_aes128_cbc(&key(), &iv, &data).unwrap(), hex(NIST_PLAINTEXT));
+        assert_eq!(encrypt_aes128_cbc(&key(), &iv, &hex(NIST_PLAINTEXT)), data);
+    }
+
+    #[test]
+    fn decrypt_with_iv_from_sequence_number() {
+        assert_eq!(
+            iv_from_sequence_number(0x0102_0304_0506_0708),
+            [0, 0, 0, 0, 0, 0, 0, 0, 1, 2, 3, 4, 5, 6, 7, 8]
+        );
+
+        let iv = iv_from_sequence_number(5);
+        assert_eq!(iv[..15], [0; 15]);
+        assert_eq!(iv[15], 5);
+
+        // Encrypted with `openssl enc -aes-128-cbc` (the IV is 00000000000000000000000000000005)
+        let data = hex("a5395c1c280fe9a0f0f468e58c789f7d6a510fccd199bdf1b19aafe7f4ffdb73");
+        assert_eq!(
+            decrypt_aes128_cbc(&key(), &iv, &data).unwrap(),
+            b"hls segment data"
+        );
+
+        // Decrypting with the IV of another segment corrupts the first block
+        let other_iv = iv_from_sequence_number(6);
+        assert_ne!(
+            decrypt_aes128_cbc(&key(), &other_iv, &data).ok(),
+            Some(b"hls segment data".to_vec())
+        );
+    }
+
+    #[test]
+    fn decrypt_rejects_malformed_data() {
+        let iv = [0; BLOCK_SIZE];
+        assert!(decrypt_aes128_cbc(&key(), &iv, &[]).is_err());
+        assert!(decrypt_aes128_cbc(&key(), &iv, &[0; 17]).is_err());
+
+        // A single block whose plaintext ends with an invalid PKCS7 padding
+        let encrypt_block = |plain: &[u8]| encrypt_aes128_cbc(&key(), &iv, plain)[..16].to_vec();
+        let mut inconsistent = [3; 16];
+        inconsistent[14] = 2;
+        for plain in &[[0; 16], [17; 16], inconsistent] {
+            assert!(decrypt_aes128_cbc(&key(), &iv, &encrypt_block(plain)).is_err());
+        }
+        let mut valid = [3; 16];
+        valid[0] = 0;
+        assert_eq!(
+            decrypt_aes128_cbc(&key(), &iv, &encrypt_block(&valid)).unwrap(),
+            &valid[..13]
+        );
+    }
+}
@@ -0,0 +1,119 @@
+//! Wall-clock times given by `EXT-X-PROGRAM-DATE-TIME`.
+use trackable::error::ErrorKindExt;
+
+use {ErrorKind, Result};
+
+/// Parses an ISO 8601 date-time (e.g., `2010-02-19T14:54:23.031+08:00`),
+/// and returns it in milliseconds since the Unix epoch.
+///
+/// The time zone designator can be omitted, in which case UTC is assumed.
+pub fn parse(s: &str) -> Result<f64> {
+    let (date, time) = track_assert_some!(
+        s.split_once('T').or_else(|| s.split_once('t')),
+        ErrorKind::InvalidInput,
+        "Malformed date-time: {:?}",
+        s
+    );
+
+    let mut fields = date.splitn(3, '-');
+    let year: i64 = track!(parse_field(fields.next(), s))?;
+    let month: i64 = track!(parse_field(fields.next(), s))?;
+    let day: i64 = track!(parse_field(fields.next(), s))?;
+    track_assert!(
+        (1..=12).contains(&month) && (1..=31).contains(&day),
+        ErrorKind::InvalidInput,
+        "Malformed date: {:?}",
+        s
+    );
+
+    let (time, offset_minutes) = if let Some(time) = time.strip_suffix(['Z', 'z']) {
+        (time, 0)
+    } else if let Some(i) = time.rfind(['+', '-']) {
+        let sign = if time[i..].starts_with('-') { -1 } else { 1 };
+        let offset = time[i + 1..].replace(':', "");
+        track_assert_eq!(offset.len(), 4, ErrorKind::InvalidInput, "Malformed offset: {:?}", s);
+        let hours: i64 = track!(parse_field(offset.get(..2), s))?;
+        let minutes: i64 = track!(parse_field(offset.get(2..), s))?;
+        (&time[..i], sign * (hours * 60 + minutes))
+    } else {
+        (time, 0)
+    };
+
+    let mut fields = time.splitn(3, ':');
+    let hour: i64 = track!(parse_field(fields.next(), s))?;
+    let minute: i64 = track!(parse_field(fields.next(), s))?;
+    let second: f64 = track!(parse_field(fields.next(), s))?;
+    track_assert!(
+        hour < 24 && minute < 60 && (0.0..61.0).contains(&second),
+        ErrorKind::InvalidInput,
+        "Malformed time: {:?}",
+        s
+    );
+
+    let days = days_from_civil(year, month, day);
+    let seconds = days * 86_400 + hour * 3600 + (minute - offset_minutes) * 60;
+    Ok((seconds as f64 + second) * 1000.0)
+}
+
+fn parse_field<T>(field: Option<&str>, s: &str) -> Result<T>
+where
+    T: ::std::str::FromStr,
+    T::Err: ::std::error::Error + Send + Sync + 'static,
+{
+    let field = track_assert_some!(field, ErrorKind::InvalidInput, "Malformed date-time: {:?}", s);
+    let value = track!(field.parse().map_err(|e| ErrorKind::InvalidInput.cause(e)))?;
+    Ok(value)
+}
+
+/// Returns the number of days since 1970-01-01 in the proleptic Gregorian calendar.
+///
+/// See: http://howardhinnant.github.io/date_algorithms.html
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = if year >= 0 { year } else { year - 399 } / 400;
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+/// Mapping between positions in the presentation timeline and wall-clock times.
+#[derive(Debug, Clone, Default)]
+pub struct DateTimeMapping {
+    /// Pairs of a position (in seconds) and a date-time (in milliseconds since the Unix epoch),
+    /// sorted by the position.
+    points: Vec<(f64, f64)>,
+}
+impl DateTimeMapping {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records that the media at `position` was captured at `date_time`.
+    ///
+    /// Points preceding `position` are assumed to be already added.
+    pub fn insert(&mut self, position: f64, date_time: f64) {
+        while self.points.last().is_some_and(|p| p.0 >= position) {
+            self.points.pop();
+        }
+        self.points.push((position, date_time));
+    }
+
+    /// Converts `position` (in seconds) into a date-time (in milliseconds since the Unix epoch).
+    ///
+    /// Times between the points are extrapolated from the preceding one.
+    pub fn position_to_date_time(&self, position: f64) -> Option<f64> {
+        let i = self.points.iter().rposition(|p| p.0 <= position).unwrap_or(0);
+        self.points
+            .get(i)
+            .map(|p| p.1 + (position - p.0) * 1000.0)
+    }
+
+    /// Converts `date_time` (in milliseconds since the Unix epoch) into a position (in seconds).
+    pub fn date_time_to_position(&self, date_time: f64) -> Option<f64> {
+        let i = self.points.iter().rposition(|p| p.1 <= date_time).unwrap_or(0);
+        self.points
+            .get(i)
+            .map(|p| p.0 + (date_time - p.1) / 1000.0)
+    }
+}
@@ -0,0 +1,97 @@
+//! Notifications about the state of the player.
+use url::Url;
+use url_serde;
+
+use ErrorKind;
+
+/// Why the variant stream being played was switched.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum SwitchReason {
+    /// The estimated bandwidth changed.
+    Bandwidth,
+
+    /// The stream being played could not be fetched.
+    Failover,
+
+    /// The primary stream is retried after a failover to a redundant one.
+    Primary,
+}
+
+/// Notification returned by `HlsPlayer::next_event()`.
+///
+/// Ad breaks, timed metadata and cues are not events: they are placed on the media timeline
+/// and are taken in batches by `take_ad_events()`, `take_timed_metadata()` and
+/// `take_*_cues()` to be scheduled against the playback position.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "type")]
+pub enum Event {
+    /// Playback of the playlist at `url` has started.
+    Started {
+        #[serde(with = "url_serde")]
+        url: Url,
+    },
+
+    /// A media playlist has been loaded or reloaded.
+    PlaylistLoaded {
+        #[serde(with = "url_serde")]
+        url: Url,
+
+        /// Sequence number of the first segment in the playlist.
+        media_sequence: u64,
+
+        /// Number of segments in the playlist, including those skipped in a delta update.
+        segments: usize,
+
+        /// Whether new segments were found since the last load.
+        is_updated: bool,
+
+        is_ended: bool,
+    },
+
+    /// A media segment (or a partial segment) has been fetched.
+    SegmentFetched {
+        #[serde(with = "url_serde")]
+        url: Url,
+
+        bytes: usize,
+
+        fetch_duration_ms: u32,
+    },
+
+    /// The variant stream being played has been switched.
+    ///
+    /// `from` and `to` are the same if playback fails over to a redundant stream.
+    VariantSwitched {
+        from: usize,
+        to: usize,
+
+        /// The `BANDWIDTH` attribute of the new variant.
+        bandwidth: u64,
+
+        #[serde(with = "url_serde")]
+        url: Url,
+
+        reason: SwitchReason,
+    },
+
+    /// A fetch has failed.
+    FetchFailed {
+        #[serde(with = "url_serde")]
+        url: Url,
+
+        /// HTTP status code, or `None` if no response was received.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        status: Option<u16>,
+
+        will_retry: bool,
+    },
+
+    /// The caller has run out of buffered media while no segments are ready.
+    Stalled,
+
+    /// All of the segments have been delivered.
+    Ended,
+
+    /// Playback has been stopped by an error.
+    Error { kind: ErrorKind, reason: String },
+}
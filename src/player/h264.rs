@@ -0,0 +1,308 @@
+//! H.264 (AVC) byte stream utilities.
+use {ErrorKind, Result};
+
+pub const NAL_UNIT_TYPE_NON_IDR_SLICE: u8 = 1;
+pub const NAL_UNIT_TYPE_IDR_SLICE: u8 = 5;
+pub const NAL_UNIT_TYPE_SEI: u8 = 6;
+pub const NAL_UNIT_TYPE_SPS: u8 = 7;
+pub const NAL_UNIT_TYPE_PPS: u8 = 8;
+
+pub fn nal_unit_type(nal_unit: &[u8]) -> u8 {
+    nal_unit.first().map_or(0, |b| b & 0b0001_1111)
+}
+
+/// Returns the ranges of the NAL units (excluding start codes) in an Annex B byte stream.
+pub fn nal_unit_ranges(byte_stream: &[u8]) -> Vec<(usize, usize)> {
+    let mut starts = Vec::new();
+    let mut i = 0;
+    while i + 3 <= byte_stream.len() {
+        if byte_stream[i..i + 3] == [0, 0, 1] {
+            starts.push((i, i + 3));
+            i += 3;
+        } else {
+            i += 1;
+        }
+    }
+
+    let mut ranges = Vec::new();
+    for (j, &(_, start)) in starts.iter().enumerate() {
+        let mut end = starts.get(j + 1).map_or(byte_stream.len(), |s| s.0);
+        // The leading zero of a four-byte start code belongs to the next start code
+        while end > start && byte_stream[end - 1] == 0 && j + 1 < starts.len() {
+            end -= 1;
+        }
+        ranges.push((start, end));
+    }
+    ranges
+}
+
+/// Returns the NAL units (excluding start codes) in an Annex B byte stream.
+pub fn nal_units(byte_stream: &[u8]) -> Vec<&[u8]> {
+    nal_unit_ranges(byte_stream)
+        .into_iter()
+        .map(|(start, end)| &byte_stream[start..end])
+        .filter(|nal_unit| !nal_unit.is_empty())
+        .collect()
+}
+
+pub fn remove_emulation_prevention_bytes(nal_unit: &[u8]) -> Vec<u8> {
+    let mut rbsp = Vec::with_capacity(nal_unit.len());
+    let mut zeros = 0;
+    for &b in nal_unit {
+        if zeros >= 2 && b == 3 {
+            zeros = 0;
+            continue;
+        }
+        zeros = if b == 0 { zeros + 1 } else { 0 };
+        rbsp.push(b);
+    }
+    rbsp
+}
+
+pub fn add_emulation_prevention_bytes(rbsp: &[u8], buf: &mut Vec<u8>) {
+    let mut zeros = 0;
+    for &b in rbsp {
+        if zeros >= 2 && b <= 3 {
+            buf.push(3);
+            zeros = 0;
+        }
+        zeros = if b == 0 { zeros + 1 } else { 0 };
+        buf.push(b);
+    }
+}
+
+/// The fields of a sequence parameter set that are needed to build an initialization segment.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SpsSummary {
+    pub profile_idc: u8,
+    pub constraint_set_flag: u8,
+    pub level_idc: u8,
+    pub width: usize,
+    pub height: usize,
+}
+impl SpsSummary {
+    /// Parses the SPS NAL unit `nal_unit` (including the NAL unit header).
+    pub fn parse(nal_unit: &[u8]) -> Result<Self> {
+        let rbsp = remove_emulation_prevention_bytes(nal_unit);
+        track_assert!(rbsp.len() >= 4, ErrorKind::InvalidInput, "Too short SPS");
+        let profile_idc = rbsp[1];
+        let constraint_set_flag = rbsp[2];
+        let level_idc = rbsp[3];
+
+        let mut reader = BitReader::new(&rbsp[4..]);
+        let _seq_parameter_set_id = track!(reader.read_ue())?;
+        let mut chroma_format_idc = 1;
+        match profile_idc {
+            100 | 110 | 122 | 244 | 44 | 83 | 86 | 118 | 128 | 138 | 139 | 134 | 135 => {
+                chroma_format_idc = track!(reader.read_ue())?;
+                if chroma_format_idc == 3 {
+                    let _separate_colour_plane_flag = track!(reader.read_bit())?;
+                }
+                let _bit_depth_luma_minus8 = track!(reader.read_ue())?;
+                let _bit_depth_chroma_minus8 = track!(reader.read_ue())?;
+                let _qpprime_y_zero_transform_bypass_flag = track!(reader.read_bit())?;
+                let seq_scaling_matrix_present_flag = track!(reader.read_bit())?;
+                if seq_scaling_matrix_present_flag {
+                    let count = if chroma_format_idc == 3 { 12 } else { 8 };
+                    for i in 0..count {
+                        if track!(reader.read_bit())? {
+                            let size = if i < 6 { 16 } else { 64 };
+                            track!(skip_scaling_list(&mut reader, size))?;
+                        }
+                    }
+                }
+            }
+            _ => {}
+        }
+
+        let _log2_max_frame_num_minus4 = track!(reader.read_ue())?;
+        let pic_order_cnt_type = track!(reader.read_ue())?;
+        match pic_order_cnt_type {
+            0 => {
+                let _log2_max_pic_order_cnt_lsb_minus4 = track!(reader.read_ue())?;
+            }
+            1 => {
+                let _delta_pic_order_always_zero_flag = track!(reader.read_bit())?;
+                let _offset_for_non_ref_pic = track!(reader.read_se())?;
+                let _offset_for_top_to_bottom_field = track!(reader.read_se())?;
+                let num_ref_frames_in_pic_order_cnt_cycle = track!(reader.read_ue())?;
+                for _ in 0..num_ref_frames_in_pic_order_cnt_cycle {
+                    let _offset_for_ref_frame = track!(reader.read_se())?;
+                }
+            }
+            2 => {}
+            _ => track_panic!(ErrorKind::InvalidInput, "pic_order_cnt_type={}", pic_order_cnt_type),
+        }
+        let _max_num_ref_frames = track!(reader.read_ue())?;
+        let _gaps_in_frame_num_value_allowed_flag = track!(reader.read_bit())?;
+        let pic_width_in_mbs_minus1 = track!(reader.read_ue())? as usize;
+        let pic_height_in_map_units_minus1 = track!(reader.read_ue())? as usize;
+        let frame_mbs_only_flag = track!(reader.read_bit())?;
+        if !frame_mbs_only_flag {
+            let _mb_adaptive_frame_field_flag = track!(reader.read_bit())?;
+        }
+        let _direct_8x8_inference_flag = track!(reader.read_bit())?;
+        let frame_cropping_flag = track!(reader.read_bit())?;
+        let (crop_left, crop_right, crop_top, crop_bottom) = if frame_cropping_flag {
+            (
+                track!(reader.read_ue())? as usize,
+                track!(reader.read_ue())? as usize,
+                track!(reader.read_ue())? as usize,
+                track!(reader.read_ue())? as usize,
+            )
+        } else {
+            (0, 0, 0, 0)
+        };
+
+        let frame_height_factor = if frame_mbs_only_flag { 1 } else { 2 };
+        let (crop_unit_x, crop_unit_y) = match chroma_format_idc {
+            1 => (2, 2 * frame_height_factor),
+            2 => (2, frame_height_factor),
+            _ => (1, frame_height_factor),
+        };
+        let width = (pic_width_in_mbs_minus1 + 1) * 16;
+        let height = frame_height_factor * (pic_height_in_map_units_minus1 + 1) * 16;
+        let crop_x = crop_unit_x * (crop_left + crop_right);
+        let crop_y = crop_unit_y * (crop_top + crop_bottom);
+        track_assert!(
+            crop_x < width && crop_y < height,
+            ErrorKind::InvalidInput,
+            "Invalid frame cropping"
+        );
+        Ok(SpsSummary {
+            profile_idc,
+            constraint_set_flag,
+            level_idc,
+            width: width - crop_x,
+            height: height - crop_y,
+        })
+    }
+}
+
+fn skip_scaling_list(reader: &mut BitReader, size: usize) -> Result<()> {
+    let mut last_scale = 8;
+    let mut next_scale = 8;
+    for _ in 0..size {
+        if next_scale != 0 {
+            let delta_scale = track!(reader.read_se())?;
+            next_scale = (last_scale + delta_scale + 256) % 256;
+        }
+        if next_scale != 0 {
+            last_scale = next_scale;
+        }
+    }
+    Ok(())
+}
+
+/// Reader of the Exp-Golomb coded fields in an RBSP.
+#[derive(Debug)]
+pub struct BitReader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+impl<'a> BitReader<'a> {
+    pub fn new(bytes: &'a [u8]) -> Self {
+        BitReader { bytes, position: 0 }
+    }
+
+    pub fn read_bit(&mut self) -> Result<bool> {
+        let byte = track_assert_some!(
+            self.bytes.get(self.position / 8),
+            ErrorKind::InvalidInput,
+            "Unexpected end of RBSP"
+        );
+        let bit = (byte >> (7 - self.position % 8)) & 1;
+        self.position += 1;
+        Ok(bit == 1)
+    }
+
+    pub fn read_bits(&mut self, n: usize) -> Result<u64> {
+        let mut value = 0;
+        for _ in 0..n {
+            value = (value << 1) | u64::from(track!(self.read_bit())?);
+        }
+        Ok(value)
+    }
+
+    pub fn read_ue(&mut self) -> Result<u64> {
+        let mut leading_zeros = 0;
+        while !track!(self.read_bit())? {
+            leading_zeros += 1;
+            track_assert!(leading_zeros < 64, ErrorKind::InvalidInput);
+        }
+        let value = track!(self.read_bits(leading_zeros))?;
+        Ok((1 << leading_zeros) - 1 + value)
+    }
+
+    pub fn read_se(&mut self) -> Result<i64> {
+        let value = track!(self.read_ue())? as i64;
+        if value % 2 == 1 {
+            Ok((value + 1) / 2)
+        } else {
+            Ok(-(value / 2))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Parameter sets written by x264
+    const SPS_720P: &[u8] = &[
+        0x67, 0x64, 0x00, 0x1f, 0xac, 0xd9, 0x40, 0x50, 0x05, 0xbb, 0x01, 0x6a, 0x02, 0x02, 0x02,
+        0x80, 0x00, 0x00, 0x03, 0x00, 0x80, 0x00, 0x00, 0x1e, 0x07, 0x8c, 0x18, 0xcb,
+    ];
+    const SPS_1080P: &[u8] = &[
+        0x67, 0x64, 0x00, 0x28, 0xac, 0xd9, 0x40, 0x78, 0x02, 0x27, 0xe5, 0x84, 0x00, 0x00, 0x03,
+        0x00, 0x04, 0x00, 0x00, 0x03, 0x00, 0xc8, 0x3c, 0x60, 0xc6, 0x58,
+    ];
+    const PPS: &[u8] = &[0x68, 0xeb, 0xe3, 0xcb, 0x22, 0xc0];
+
+    #[test]
+    fn sps_of_high_profile() {
+        let sps = SpsSummary::parse(SPS_720P).unwrap();
+        assert_eq!(
+            sps,
+            SpsSummary {
+                profile_idc: 100,
+                constraint_set_flag: 0,
+                level_idc: 31,
+                width: 1280,
+                height: 720,
+            }
+        );
+    }
+
+    #[test]
+    fn sps_with_frame_cropping() {
+        // 1920x1088 cropped by 8 lines at the bottom
+        let sps = SpsSummary::parse(SPS_1080P).unwrap();
+        assert_eq!((sps.level_idc, sps.width, sps.height), (40, 1920, 1080));
+    }
+
+    #[test]
+    fn nal_units_of_byte_stream() {
+        let mut byte_stream = vec![0, 0, 0, 1];
+        byte_stream.extend_from_slice(SPS_720P);
+        byte_stream.extend_from_slice(&[0, 0, 1]);
+        byte_stream.extend_from_slice(PPS);
+        byte_stream.extend_from_slice(&[0, 0, 0, 1, 0x65, 0x88, 0x84, 0x00]);
+
+        let nal_units = nal_units(&byte_stream);
+        assert_eq!(nal_units, vec![SPS_720P, PPS, &[0x65, 0x88, 0x84, 0x00][..]]);
+        let types = nal_units.iter().map(|n| nal_unit_type(n)).collect::<Vec<_>>();
+        assert_eq!(types, [NAL_UNIT_TYPE_SPS, NAL_UNIT_TYPE_PPS, NAL_UNIT_TYPE_IDR_SLICE]);
+    }
+
+    #[test]
+    fn emulation_prevention_bytes() {
+        let rbsp = remove_emulation_prevention_bytes(SPS_720P);
+        assert_eq!(rbsp.len(), SPS_720P.len() - 1);
+        assert_eq!(rbsp[15..20], [0x80, 0x00, 0x00, 0x00, 0x80]);
+
+        let mut nal_unit = Vec::new();
+        add_emulation_prevention_bytes(&rbsp, &mut nal_unit);
+        assert_eq!(nal_unit, SPS_720P);
+    }
+}
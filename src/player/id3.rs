@@ -0,0 +1,262 @@
+//! ID3v2 tags carried as timed metadata.
+//!
+//! See: "Timed Metadata for HTTP Live Streaming" (Apple) and [ID3v2.4](http://id3.org/)
+use std::str;
+use trackable::error::ErrorKindExt;
+
+use {ErrorKind, Result};
+
+const HEADER_SIZE: usize = 10;
+const FLAG_EXTENDED_HEADER: u8 = 0b0100_0000;
+const FLAG_FOOTER: u8 = 0b0001_0000;
+
+/// ID3 frame.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "type")]
+pub enum Frame {
+    /// User defined text information frame.
+    #[serde(rename = "TXXX")]
+    Txxx { description: String, value: String },
+
+    /// Private frame.
+    #[serde(rename = "PRIV")]
+    Priv { owner: String, data: Vec<u8> },
+
+    /// Text information frame other than `TXXX` (e.g., `TIT2`).
+    Text { id: String, value: String },
+}
+
+/// ID3 tags presented at `time`.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct TimedMetadata {
+    /// Presentation time in seconds.
+    pub time: f64,
+
+    pub frames: Vec<Frame>,
+}
+
+/// Parses the ID3 tags concatenated in `data`, and returns the supported frames.
+pub fn parse_tags(mut data: &[u8]) -> Result<Vec<Frame>> {
+    let mut frames = Vec::new();
+    while data.starts_with(b"ID3") {
+        let tag_size = track!(parse_tag(data, &mut frames))?;
+        data = &data[tag_size..];
+    }
+    Ok(frames)
+}
+
+/// Parses an ID3 tag at the head of `data`, and returns its size.
+fn parse_tag(data: &[u8], frames: &mut Vec<Frame>) -> Result<usize> {
+    track_assert!(data.len() >= HEADER_SIZE, ErrorKind::InvalidInput, "Too short ID3 tag");
+    let version = data[3];
+    let flags = data[5];
+    track_assert!(
+        version == 3 || version == 4,
+        ErrorKind::Unsupported,
+        "Unsupported ID3 version: 2.{}",
+        version
+    );
+    let body_size = read_syncsafe_int(&data[6..10]);
+    let footer_size = if flags & FLAG_FOOTER != 0 { HEADER_SIZE } else { 0 };
+    let tag_size = HEADER_SIZE + body_size + footer_size;
+    track_assert!(data.len() >= tag_size, ErrorKind::InvalidInput, "Truncated ID3 tag");
+
+    let mut body = &data[HEADER_SIZE..HEADER_SIZE + body_size];
+    if flags & FLAG_EXTENDED_HEADER != 0 {
+        track_assert!(body.len() >= 4, ErrorKind::InvalidInput);
+        let size = if version == 4 {
+            read_syncsafe_int(&body[..4])
+        } else {
+            4 + read_u32(&body[..4])
+        };
+        track_assert!(body.len() >= size, ErrorKind::InvalidInput);
+        body = &body[size..];
+    }
+
+    while body.len() >= HEADER_SIZE && body[0] != 0 {
+        let id = track!(
+            str::from_utf8(&body[..4]).map_err(|e| ErrorKind::InvalidInput.cause(e))
+        )?;
+        let size = if version == 4 {
+            read_syncsafe_int(&body[4..8])
+        } else {
+            read_u32(&body[4..8])
+        };
+        track_assert!(
+            body.len() >= HEADER_SIZE + size,
+            ErrorKind::InvalidInput,
+            "Truncated ID3 frame: {:?}",
+            id
+        );
+        let content = &body[HEADER_SIZE..HEADER_SIZE + size];
+        if let Some(frame) = track!(parse_frame(id, content))? {
+            frames.push(frame);
+        }
+        body = &body[HEADER_SIZE + size..];
+    }
+    Ok(tag_size)
+}
+
+fn parse_frame(id: &str, content: &[u8]) -> Result<Option<Frame>> {
+    let frame = if id == "TXXX" {
+        track_assert!(!content.is_empty(), ErrorKind::InvalidInput);
+        let encoding = content[0];
+        let (description, value) = split_terminated(&content[1..], encoding);
+        Frame::Txxx {
+            description: track!(decode_text(description, encoding))?,
+            value: track!(decode_text(value, encoding))?,
+        }
+    } else if id == "PRIV" {
+        let (owner, data) = split_terminated(content, 0);
+        Frame::Priv {
+            owner: track!(decode_text(owner, 0))?,
+            data: data.to_owned(),
+        }
+    } else if id.starts_with('T') {
+        track_assert!(!content.is_empty(), ErrorKind::InvalidInput);
+        Frame::Text {
+            id: id.to_owned(),
+            value: track!(decode_text(&content[1..], content[0]))?,
+        }
+    } else {
+        return Ok(None);
+    };
+    Ok(Some(frame))
+}
+
+/// Splits `data` at the first string terminator of `encoding`.
+fn split_terminated(data: &[u8], encoding: u8) -> (&[u8], &[u8]) {
+    if encoding == 1 || encoding == 2 {
+        // UTF-16 strings are terminated by two zero bytes at a character boundary
+        let position = (0..data.len() / 2).find(|&i| data[i * 2] == 0 && data[i * 2 + 1] == 0);
+        if let Some(i) = position {
+            return (&data[..i * 2], &data[i * 2 + 2..]);
+        }
+    } else if let Some(i) = data.iter().position(|&b| b == 0) {
+        return (&data[..i], &data[i + 1..]);
+    }
+    (data, &[])
+}
+
+fn decode_text(data: &[u8], encoding: u8) -> Result<String> {
+    let text = match encoding {
+        0 => data.iter().map(|&b| b as char).collect(),
+        1 | 2 => {
+            let mut units = data.chunks(2)
+                .filter(|c| c.len() == 2)
+                .map(|c| (u16::from(c[0]) << 8) | u16::from(c[1]))
+                .collect::<Vec<_>>();
+            if encoding == 1 && units.first() == Some(&0xFFFE) {
+                // Little endian
+                for unit in &mut units {
+                    *unit = unit.swap_bytes();
+                }
+            }
+            if units.first() == Some(&0xFEFF) {
+                units.remove(0);
+            }
+            track!(String::from_utf16(&units).map_err(|e| ErrorKind::InvalidInput.cause(e)))?
+        }
+        3 => track!(
+            String::from_utf8(data.to_owned()).map_err(|e| ErrorKind::InvalidInput.cause(e))
+        )?,
+        _ => track_panic!(ErrorKind::InvalidInput, "Unknown text encoding: {}", encoding),
+    };
+    Ok(text.trim_end_matches('\0').to_owned())
+}
+
+fn read_syncsafe_int(bytes: &[u8]) -> usize {
+    bytes.iter().fold(0, |acc, &b| (acc << 7) | (b & 0x7F) as usize)
+}
+
+fn read_u32(bytes: &[u8]) -> usize {
+    bytes.iter().fold(0, |acc, &b| (acc << 8) | b as usize)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// ID3v2.4 tag of packed audio segments (the timestamp is 900000 in 90kHz units).
+    const TIMESTAMP_TAG: &[u8] = b"ID3\x04\x00\x00\x00\x00\x00\x3F\
+        PRIV\x00\x00\x00\x35\x00\x00\
+        com.apple.streaming.transportStreamTimestamp\x00\
+        \x00\x00\x00\x00\x00\x0D\xBB\xA0";
+
+    /// ID3v2.4 tag with a UTF-8 `TXXX` frame and an unsupported `WXXX` frame.
+    const TXXX_TAG: &[u8] = b"ID3\x04\x00\x00\x00\x00\x00\x27\
+        TXXX\x00\x00\x00\x0D\x00\x00\x03CUE\x00ad-start\
+        WXXX\x00\x00\x00\x04\x00\x00\x03\x00ab\
+        \x00\x00";
+
+    #[test]
+    fn priv_frame() {
+        let frames = parse_tags(TIMESTAMP_TAG).unwrap();
+        assert_eq!(
+            frames,
+            [Frame::Priv {
+                owner: "com.apple.streaming.transportStreamTimestamp".to_owned(),
+                data: vec![0, 0, 0, 0, 0, 0x0D, 0xBB, 0xA0],
+            }]
+        );
+    }
+
+    #[test]
+    fn txxx_frame_and_padding() {
+        let frames = parse_tags(TXXX_TAG).unwrap();
+        assert_eq!(
+            frames,
+            [Frame::Txxx {
+                description: "CUE".to_owned(),
+                value: "ad-start".to_owned(),
+            }]
+        );
+    }
+
+    #[test]
+    fn concatenated_tags() {
+        let mut data = TIMESTAMP_TAG.to_vec();
+        data.extend_from_slice(TXXX_TAG);
+        data.extend_from_slice(b"\xFF\xF1"); // Followed by ADTS frames
+        assert_eq!(parse_tags(&data).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn id3v23_tag_with_extended_header_and_utf16_frames() {
+        // The sizes of ID3v2.3 frames are not syncsafe integers
+        let mut tag = b"ID3\x03\x00\x40\x00\x00\x01\x00".to_vec();
+        tag.extend_from_slice(b"\x00\x00\x00\x06\x00\x00\x00\x00\x00\x00");
+        tag.extend_from_slice(b"TXXX\x00\x00\x00\x0B\x00\x00");
+        tag.extend_from_slice(b"\x01\xFF\xFEa\x00\x00\x00\xFF\xFEb\x00");
+        tag.extend_from_slice(b"TIT2\x00\x00\x00\x07\x00\x00\x02\x00T\x00i\x00\x00");
+        tag.resize(10 + 128, 0);
+
+        let frames = parse_tags(&tag).unwrap();
+        assert_eq!(
+            frames,
+            [
+                Frame::Txxx {
+                    description: "a".to_owned(),
+                    value: "b".to_owned(),
+                },
+                Frame::Text {
+                    id: "TIT2".to_owned(),
+                    value: "Ti".to_owned(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn malformed_tags_are_rejected() {
+        assert!(parse_tags(&TIMESTAMP_TAG[..TIMESTAMP_TAG.len() - 1]).is_err());
+
+        let mut tag = TIMESTAMP_TAG.to_vec();
+        tag[3] = 2;
+        assert!(parse_tags(&tag).is_err());
+
+        let mut tag = TXXX_TAG.to_vec();
+        tag[20] = 0x05; // Unknown text encoding
+        assert!(parse_tags(&tag).is_err());
+    }
+}
@@ -1,41 +1,545 @@
+use std::collections::{HashSet, VecDeque};
+use std::mem;
+use std::str;
+use std::time::Duration;
 use hls_m3u8::MasterPlaylist;
+use hls_m3u8::types::{ClosedCaptions, DecimalFloatingPoint, DecimalResolution, HdcpLevel,
+                       MediaType};
 use url::Url;
 
 use {Error, ErrorKind, Result};
-use super::{Action, ActionFactory, ActionId, MediaPlaylistHandler};
+use super::ad_break::AdEvent;
+use super::event::{Event, SwitchReason};
+use super::id3::TimedMetadata;
+use super::ts;
+use super::webvtt::{self, Cue};
+use super::{Action, ActionFactory, ActionId, BufferedSegment, HlsPlayerConfig,
+            MediaPlaylistHandler, StreamId};
+use super::abr::AbrController;
+use super::codecs;
+
+/// Stream ID of the actions issued by `MasterPlaylistHandler` itself.
+const MASTER_STREAM_ID: StreamId = StreamId::MAX;
+
+#[derive(Debug)]
+struct Variant {
+    /// The URLs of the primary stream and its redundant streams (i.e., backups).
+    urls: Vec<Url>,
+
+    /// The index of the URL in use.
+    url_index: usize,
+
+    bandwidth: u64,
+    codecs: Option<String>,
+    resolution: Option<DecimalResolution>,
+    frame_rate: Option<DecimalFloatingPoint>,
+    hdcp_level: Option<HdcpLevel>,
+    audio_group: Option<String>,
+    video_group: Option<String>,
+    subtitles_group: Option<String>,
+    closed_captions: Option<ClosedCaptions>,
+}
+impl Variant {
+    fn url(&self) -> &Url {
+        &self.urls[self.url_index]
+    }
+
+    /// Returns `true` if `other` differs from this variant only in its URI.
+    fn is_redundant_to(&self, other: &Variant) -> bool {
+        self.bandwidth == other.bandwidth && self.codecs == other.codecs
+            && self.resolution == other.resolution
+            && self.frame_rate == other.frame_rate
+            && self.hdcp_level == other.hdcp_level
+            && self.audio_group == other.audio_group
+            && self.video_group == other.video_group
+            && self.subtitles_group == other.subtitles_group
+            && self.closed_captions == other.closed_captions
+    }
+}
+
+/// Alternative rendition declared by an `EXT-X-MEDIA` tag.
+#[derive(Debug, Clone, Serialize)]
+pub struct Rendition {
+    pub group_id: String,
+    pub name: String,
+    pub language: Option<String>,
+    pub is_default: bool,
+    pub autoselect: bool,
+
+    #[serde(skip)]
+    media_type: MediaType,
+
+    /// `None` means that the rendition is contained in the variant streams.
+    #[serde(skip)]
+    url: Option<Url>,
+}
+
+/// A media playlist handler and the segments taken over from the handlers it replaced.
+#[derive(Debug)]
+struct Track {
+    handler: MediaPlaylistHandler,
+    buffered_segments: VecDeque<BufferedSegment>,
+}
+impl Track {
+    fn new(handler: MediaPlaylistHandler) -> Self {
+        Track {
+            handler,
+            buffered_segments: VecDeque::new(),
+        }
+    }
+
+    fn is_ended(&self) -> bool {
+        self.buffered_segments.is_empty() && self.handler.is_ended()
+    }
+
+    fn next_segment(&mut self) -> Option<BufferedSegment> {
+        self.buffered_segments
+            .pop_front()
+            .or_else(|| self.handler.next_segment())
+    }
+
+    fn is_discontinuity(&self) -> bool {
+        if let Some(segment) = self.buffered_segments.front() {
+            segment.is_discontinuity
+        } else {
+            self.handler.is_discontinuity()
+        }
+    }
+
+    fn timestamp_offset(&self) -> Option<f64> {
+        if let Some(segment) = self.buffered_segments.front() {
+            segment.timestamp_offset
+        } else {
+            self.handler.timestamp_offset()
+        }
+    }
+
+    fn next_codecs(&self) -> Option<&str> {
+        if let Some(segment) = self.buffered_segments.front() {
+            segment.codecs.as_deref()
+        } else {
+            self.handler.next_codecs()
+        }
+    }
+
+    fn set_buffer_level(&mut self, level: f64) -> Result<()> {
+        let taken_over = self.buffered_segments
+            .iter()
+            .map(|x| x.duration)
+            .sum::<f64>();
+        track!(self.handler.set_buffer_level(level + taken_over))
+    }
+
+    fn seek(&mut self, position: f64) -> Result<f64> {
+        self.buffered_segments.clear();
+        track!(self.handler.seek(position))
+    }
+
+    /// Replaces the handler with `handler`.
+    ///
+    /// The new handler resumes from the segment following the last fetched one,
+    /// and emits its own initialization segment before any media segments.
+    fn replace_handler(&mut self, mut handler: MediaPlaylistHandler) {
+        handler.resume_after(&self.handler);
+        let mut old = mem::replace(&mut self.handler, handler);
+        while let Some(segment) = old.next_segment() {
+            self.buffered_segments.push_back(segment);
+        }
+    }
+}
 
 #[derive(Debug)]
 pub struct MasterPlaylistHandler {
-    media_playlist_handler: MediaPlaylistHandler,
+    config: HlsPlayerConfig,
+    variants: Vec<Variant>,
+    renditions: Vec<Rendition>,
+    audio_preference: Option<String>,
+    subtitles_preference: Option<String>,
+    abr: AbrController,
+    current_variant: usize,
+    failovers: usize,
+    action_factory: ActionFactory,
+    action_queue: VecDeque<Action>,
+    primary_retry_action_id: Option<ActionId>,
+    main_track: Track,
+    audio_track: Option<Track>,
+    audio_url: Option<Url>,
+    subtitles_track: Option<Track>,
+    subtitles_url: Option<Url>,
+    subtitle_cues: VecDeque<Cue>,
+    caption_cues: VecDeque<Cue>,
+    timed_metadata: VecDeque<TimedMetadata>,
+    ad_events: VecDeque<AdEvent>,
+    events: VecDeque<Event>,
+    is_end_notified: bool,
+    timestamp_origin: Option<u64>,
+    last_stream_id: StreamId,
+
+    /// Actions of the replaced handlers that may still be responded.
+    retired_actions: HashSet<ActionId>,
 }
 impl MasterPlaylistHandler {
-    pub fn new(url: Url, m3u8: &str) -> Result<Self> {
+    pub fn new(url: Url, m3u8: &str, config: HlsPlayerConfig) -> Result<Self> {
         let master_playlist: MasterPlaylist = track!(m3u8.parse())?;
-
-        let stream_inf_tag = track_assert_some!(
-            master_playlist.stream_inf_tags().get(0),
+        track_assert!(
+            !master_playlist.stream_inf_tags().is_empty(),
             ErrorKind::InvalidInput
         );
-        let media_playlist_url = track!(
-            Url::options()
-                .base_url(Some(&url))
-                .parse(stream_inf_tag.uri())
-                .map_err(Error::from)
-        )?;
 
+        let mut variants: Vec<Variant> = Vec::new();
+        for stream_inf_tag in master_playlist.stream_inf_tags() {
+            let media_playlist_url = track!(parse_url(&url, stream_inf_tag.uri()))?;
+            let variant = Variant {
+                urls: vec![media_playlist_url],
+                url_index: 0,
+                bandwidth: stream_inf_tag.bandwidth(),
+                codecs: stream_inf_tag.codecs().map(|c| c.as_ref().to_owned()),
+                resolution: stream_inf_tag.resolution(),
+                frame_rate: stream_inf_tag.frame_rate(),
+                hdcp_level: stream_inf_tag.hdcp_level(),
+                audio_group: stream_inf_tag.audio().map(|c| c.as_ref().to_owned()),
+                video_group: stream_inf_tag.video().map(|c| c.as_ref().to_owned()),
+                subtitles_group: stream_inf_tag.subtitles().map(|c| c.as_ref().to_owned()),
+                closed_captions: stream_inf_tag.closed_captions().cloned(),
+            };
+
+            // Variants that differ only in their URIs are redundant streams
+            let primary = variants.iter_mut().find(|v| v.is_redundant_to(&variant));
+            if let Some(primary) = primary {
+                if !primary.urls.contains(&variant.urls[0]) {
+                    primary.urls.extend(variant.urls);
+                }
+                continue;
+            }
+            variants.push(variant);
+        }
+
+        let mut renditions = Vec::new();
+        for media_tag in master_playlist.media_tags() {
+            match media_tag.media_type() {
+                MediaType::Audio | MediaType::Subtitles => {}
+                _ => continue,
+            }
+            let rendition_url = if let Some(uri) = media_tag.uri() {
+                Some(track!(parse_url(&url, uri))?)
+            } else {
+                None
+            };
+            renditions.push(Rendition {
+                group_id: media_tag.group_id().as_ref().to_owned(),
+                name: media_tag.name().as_ref().to_owned(),
+                language: media_tag.language().map(|l| l.as_ref().to_owned()),
+                is_default: media_tag.default(),
+                autoselect: media_tag.autoselect(),
+                media_type: media_tag.media_type(),
+                url: rendition_url,
+            });
+        }
+
+        let bandwidths = variants.iter().map(|v| v.bandwidth).collect();
+        let abr = AbrController::new(bandwidths, &config);
+        let initial_variant = abr.current_variant();
         let action_factory = ActionFactory::new(0);
-        let media_playlist_handler = MediaPlaylistHandler::new(action_factory, media_playlist_url);
-        Ok(MasterPlaylistHandler {
-            media_playlist_handler,
-        })
+        let media_playlist_handler = MediaPlaylistHandler::new(
+            action_factory,
+            variants[initial_variant].url().clone(),
+            config.clone(),
+        );
+        let started = Event::Started { url: url.clone() };
+        let mut this = MasterPlaylistHandler {
+            config,
+            variants,
+            renditions,
+            audio_preference: None,
+            subtitles_preference: None,
+            abr,
+            current_variant: initial_variant,
+            failovers: 0,
+            action_factory: ActionFactory::new(MASTER_STREAM_ID),
+            action_queue: VecDeque::new(),
+            primary_retry_action_id: None,
+            main_track: Track::new(media_playlist_handler),
+            audio_track: None,
+            audio_url: None,
+            subtitles_track: None,
+            subtitles_url: None,
+            subtitle_cues: VecDeque::new(),
+            caption_cues: VecDeque::new(),
+            timed_metadata: VecDeque::new(),
+            ad_events: VecDeque::new(),
+            events: VecDeque::new(),
+            is_end_notified: false,
+            timestamp_origin: None,
+            last_stream_id: 0,
+            retired_actions: HashSet::new(),
+        };
+        this.events.push_back(started);
+        this.update_rendition_tracks();
+        Ok(this)
+    }
+
+    pub fn config(&self) -> &HlsPlayerConfig {
+        &self.config
+    }
+
+    pub fn is_ended(&self) -> bool {
+        self.main_track.is_ended() && self.audio_track.as_ref().is_none_or(|t| t.is_ended())
+    }
+
+    /// Returns the codecs string of the segments returned by `next_segment()`.
+    ///
+    /// The one derived from the generated initialization segment takes precedence over
+    /// the `CODECS` attribute of the master playlist.
+    pub fn codecs(&self) -> Option<String> {
+        if let Some(codecs) = self.main_track.handler.codecs() {
+            return Some(codecs.to_owned());
+        }
+        let codecs = self.variants[self.current_variant].codecs.as_ref()?;
+        if self.audio_track.is_some() {
+            codecs::video_codecs(codecs)
+        } else {
+            Some(codecs.clone())
+        }
+    }
+
+    /// Returns the codecs string of the segments returned by `next_audio_segment()`.
+    pub fn audio_codecs(&self) -> Option<String> {
+        let track = self.audio_track.as_ref()?;
+        if let Some(codecs) = track.handler.codecs() {
+            return Some(codecs.to_owned());
+        }
+        let codecs = self.variants[self.current_variant].codecs.as_ref()?;
+        codecs::audio_codecs(codecs)
+    }
+
+    /// Returns `true` if the audio is delivered by an alternate rendition
+    /// (i.e., `next_audio_segment()` should be used in addition to `next_segment()`).
+    pub fn has_alternate_audio(&self) -> bool {
+        self.audio_track.is_some()
+    }
+
+    /// Returns the audio renditions available for the current variant.
+    pub fn audio_renditions(&self) -> Vec<&Rendition> {
+        self.renditions(MediaType::Audio)
+    }
+
+    /// Returns the audio rendition being played.
+    pub fn selected_audio_rendition(&self) -> Option<&Rendition> {
+        self.selected_rendition(MediaType::Audio)
+    }
+
+    /// Selects the audio rendition by its name or language.
+    ///
+    /// The selection is kept across variant switches as long as the new variant
+    /// has a matching rendition.
+    pub fn select_audio_rendition(&mut self, name_or_language: &str) -> Result<()> {
+        track!(self.select_rendition(MediaType::Audio, name_or_language))
+    }
+
+    /// Returns the subtitle renditions available for the current variant.
+    pub fn subtitle_renditions(&self) -> Vec<&Rendition> {
+        self.renditions(MediaType::Subtitles)
+    }
+
+    /// Returns the subtitle rendition being fetched.
+    ///
+    /// Unless selected explicitly, the one having `DEFAULT=YES` is used if any.
+    pub fn selected_subtitle_rendition(&self) -> Option<&Rendition> {
+        self.selected_rendition(MediaType::Subtitles)
+    }
+
+    /// Selects the subtitle rendition by its name or language.
+    pub fn select_subtitle_rendition(&mut self, name_or_language: &str) -> Result<()> {
+        track!(self.select_rendition(MediaType::Subtitles, name_or_language))
+    }
+
+    /// Returns the subtitle cues parsed since the last call.
+    ///
+    /// The cue times are in the same timeline as the segments returned by `next_segment()`.
+    /// Cues are held until the timestamp of the first media segment is known.
+    pub fn take_subtitle_cues(&mut self) -> Vec<Cue> {
+        take_aligned_cues(&mut self.subtitle_cues, self.timestamp_origin)
+    }
+
+    /// Returns the closed caption cues decoded since the last call.
+    ///
+    /// The cue times are in the same timeline as the segments returned by `next_segment()`.
+    pub fn take_caption_cues(&mut self) -> Vec<Cue> {
+        take_aligned_cues(&mut self.caption_cues, self.timestamp_origin)
+    }
+
+    /// Returns the ID3 timed metadata extracted since the last call.
+    ///
+    /// The times are in the same timeline as the segments returned by `next_segment()`.
+    pub fn take_timed_metadata(&mut self) -> Vec<TimedMetadata> {
+        let origin = if let Some(origin) = self.timestamp_origin {
+            origin
+        } else {
+            return Vec::new();
+        };
+        self.timed_metadata
+            .drain(..)
+            .map(|mut m| {
+                m.time = ts::relative_time(m.time, origin);
+                m
+            })
+            .collect()
+    }
+
+    /// Returns the ad break events signaled since the last call.
+    ///
+    /// The times are in the same timeline as the segments returned by `next_segment()`.
+    pub fn take_ad_events(&mut self) -> Vec<AdEvent> {
+        self.ad_events.drain(..).collect()
+    }
+
+    /// Converts a position in the presentation timeline (in seconds) into the wall-clock time
+    /// (in milliseconds since the Unix epoch) given by `EXT-X-PROGRAM-DATE-TIME`.
+    pub fn position_to_date_time(&self, position: f64) -> Option<f64> {
+        self.main_track
+            .handler
+            .date_time_mapping()
+            .position_to_date_time(position)
+    }
+
+    /// The inverse of `position_to_date_time()`.
+    pub fn date_time_to_position(&self, date_time: f64) -> Option<f64> {
+        self.main_track
+            .handler
+            .date_time_mapping()
+            .date_time_to_position(date_time)
     }
 
     pub fn next_action(&mut self) -> Option<Action> {
-        self.media_playlist_handler.next_action()
+        if let Some(action) = self.action_queue.pop_front() {
+            return Some(action);
+        }
+        if let Some(action) = self.main_track.handler.next_action() {
+            return Some(action);
+        }
+        if let Some(action) = self.audio_track
+            .as_mut()
+            .and_then(|t| t.handler.next_action())
+        {
+            return Some(action);
+        }
+        self.subtitles_track
+            .as_mut()
+            .and_then(|t| t.handler.next_action())
+    }
+
+    pub fn next_segment(&mut self) -> Option<BufferedSegment> {
+        self.main_track.next_segment()
+    }
+
+    pub fn is_discontinuity(&self) -> bool {
+        self.main_track.is_discontinuity()
+    }
+
+    pub fn timestamp_offset(&self) -> Option<f64> {
+        self.main_track.timestamp_offset()
+    }
+
+    pub fn start_position(&self) -> Option<f64> {
+        self.main_track.handler.start_position()
+    }
+
+    pub fn next_codecs(&self) -> Option<&str> {
+        self.main_track.next_codecs()
+    }
+
+    pub fn next_audio_codecs(&self) -> Option<&str> {
+        self.audio_track.as_ref().and_then(|t| t.next_codecs())
+    }
+
+    pub fn next_audio_segment(&mut self) -> Option<BufferedSegment> {
+        self.audio_track.as_mut().and_then(|t| t.next_segment())
+    }
+
+    pub fn is_audio_discontinuity(&self) -> bool {
+        self.audio_track
+            .as_ref()
+            .is_some_and(|t| t.is_discontinuity())
+    }
+
+    pub fn audio_timestamp_offset(&self) -> Option<f64> {
+        self.audio_track
+            .as_ref()
+            .and_then(|t| t.timestamp_offset())
+    }
+
+    /// Updates the amount of media (in seconds) buffered ahead of the playback position
+    /// for the main track.
+    ///
+    /// Subtitles are fetched up to the same level, since they are not buffered by the caller.
+    pub fn set_buffer_level(&mut self, level: f64) -> Result<()> {
+        track!(self.main_track.set_buffer_level(level))?;
+        if let Some(ref mut track) = self.subtitles_track {
+            track!(track.set_buffer_level(level))?;
+        }
+        Ok(())
     }
 
-    pub fn next_segment(&mut self) -> Option<Vec<u8>> {
-        self.media_playlist_handler.next_segment()
+    pub fn set_audio_buffer_level(&mut self, level: f64) -> Result<()> {
+        if let Some(ref mut track) = self.audio_track {
+            track!(track.set_buffer_level(level))?;
+        }
+        Ok(())
+    }
+
+    /// Returns the next event to be notified.
+    pub fn next_event(&mut self) -> Option<Event> {
+        self.collect_track_events();
+        if let Some(event) = self.events.pop_front() {
+            return Some(event);
+        }
+        if !self.is_end_notified && self.is_ended() {
+            self.is_end_notified = true;
+            return Some(Event::Ended);
+        }
+        None
+    }
+
+    /// Notifies `Event::Error` on an error that stops playback.
+    pub fn notify_error(&mut self, error: &Error) {
+        self.events.push_back(Event::Error {
+            kind: *error.kind(),
+            reason: error.reason(),
+        });
+    }
+
+    /// Moves the events of the tracks into the queue of this handler.
+    ///
+    /// `Event::Ended` is notified once all of the tracks have ended, and subtitles tracks
+    /// never stall since they are not buffered by the caller.
+    fn collect_track_events(&mut self) {
+        let tracks = Some(&mut self.main_track)
+            .into_iter()
+            .chain(self.audio_track.iter_mut())
+            .map(|t| (t, false))
+            .chain(self.subtitles_track.iter_mut().map(|t| (t, true)));
+        for (track, is_subtitles) in tracks {
+            while let Some(event) = track.handler.next_event() {
+                match event {
+                    Event::Ended => {}
+                    Event::Stalled if is_subtitles => {}
+                    _ => self.events.push_back(event),
+                }
+            }
+        }
+    }
+
+    /// Restarts the main and rendition tracks from `position` (in seconds),
+    /// and returns the start position of the main segment containing it.
+    pub fn seek(&mut self, position: f64) -> Result<f64> {
+        let start = track!(self.main_track.seek(position))?;
+        for track in self.audio_track.iter_mut().chain(self.subtitles_track.iter_mut()) {
+            // Rendition playlists that have not been loaded yet start from the beginning
+            let _ = track.seek(position);
+        }
+        self.caption_cues.clear();
+        self.is_end_notified = false;
+        Ok(start)
     }
 
     pub fn handle_data(
@@ -44,13 +548,680 @@ impl MasterPlaylistHandler {
         data: &[u8],
         fetch_duration_ms: u32,
     ) -> Result<()> {
+        track_assert!(
+            !self.retired_actions.remove(&action_id),
+            ErrorKind::StaleResponse,
+            "Response to a request of a replaced handler: {:?}",
+            action_id
+        );
+        if let Some(track) = track_for(&mut self.audio_track, action_id) {
+            let result = track.handler.handle_data(action_id, data, fetch_duration_ms);
+            return track!(self.handle_rendition_result(MediaType::Audio, result));
+        }
+        if let Some(track) = track_for(&mut self.subtitles_track, action_id) {
+            let result = track
+                .handler
+                .handle_data(action_id, data, fetch_duration_ms)
+                .and_then(|()| read_subtitle_cues(track));
+            let result = result.map(|cues| self.subtitle_cues.extend(cues));
+            return track!(self.handle_rendition_result(MediaType::Subtitles, result));
+        }
+        track_assert!(
+            self.is_main_action(action_id),
+            ErrorKind::InvalidInput,
+            "Unknown action: {:?}",
+            action_id
+        );
+
+        let is_segment = self.main_track.handler.is_segment_fetch(action_id);
         track!(
-            self.media_playlist_handler
+            self.main_track
+                .handler
                 .handle_data(action_id, data, fetch_duration_ms)
-        )
+        )?;
+        if self.timestamp_origin.is_none() {
+            self.timestamp_origin = self.main_track.handler.first_timestamp();
+        }
+        self.caption_cues
+            .extend(self.main_track.handler.take_caption_cues());
+        self.timed_metadata
+            .extend(self.main_track.handler.take_timed_metadata());
+        self.ad_events
+            .extend(self.main_track.handler.take_ad_events());
+        if is_segment {
+            self.failovers = 0;
+            self.abr.handle_segment_fetched(data.len(), fetch_duration_ms);
+            if let Some(variant) = self.abr.select_variant() {
+                self.switch_variant(variant, SwitchReason::Bandwidth);
+            }
+        }
+        Ok(())
     }
 
     pub fn handle_timeout(&mut self, action_id: ActionId) -> Result<()> {
-        track!(self.media_playlist_handler.handle_timeout(action_id))
+        if self.primary_retry_action_id == Some(action_id) {
+            self.primary_retry_action_id = None;
+            self.return_to_primary();
+            return Ok(());
+        }
+        if self.retired_actions.remove(&action_id) {
+            return Ok(());
+        }
+        if let Some(track) = track_for(&mut self.audio_track, action_id) {
+            let result = track.handler.handle_timeout(action_id);
+            return track!(self.handle_rendition_result(MediaType::Audio, result));
+        }
+        if let Some(track) = track_for(&mut self.subtitles_track, action_id) {
+            let result = track.handler.handle_timeout(action_id);
+            return track!(self.handle_rendition_result(MediaType::Subtitles, result));
+        }
+        if !self.is_main_action(action_id) {
+            return Ok(());
+        }
+        track!(self.main_track.handler.handle_timeout(action_id))
+    }
+
+    /// Handles the failure of the fetch issued by `action_id`.
+    ///
+    /// If the main track cannot be fetched even after retries, playback fails over to
+    /// a redundant stream of the current variant, or falls back to another variant.
+    /// An error is returned if there is no stream to fall back to.
+    pub fn handle_fetch_error(&mut self, action_id: ActionId, status: Option<u16>) -> Result<()> {
+        if self.retired_actions.remove(&action_id) {
+            return Ok(());
+        }
+        if let Some(track) = track_for(&mut self.audio_track, action_id) {
+            let result = track.handler.handle_fetch_error(action_id, status);
+            return track!(self.handle_rendition_result(MediaType::Audio, result));
+        }
+        if let Some(track) = track_for(&mut self.subtitles_track, action_id) {
+            let result = track.handler.handle_fetch_error(action_id, status);
+            return track!(self.handle_rendition_result(MediaType::Subtitles, result));
+        }
+        if !self.is_main_action(action_id) {
+            return Ok(());
+        }
+        match self.main_track.handler.handle_fetch_error(action_id, status) {
+            Err(ref e) if *e.kind() == ErrorKind::FetchFailed => {
+                if self.fail_over() {
+                    return Ok(());
+                }
+                Err(track!(e.clone()))
+            }
+            result => track!(result),
+        }
+    }
+
+    /// Drops the audio or subtitles track if `result` is an error, so that the main track keeps
+    /// playing without the rendition.
+    ///
+    /// `Event::FetchFailed` is notified for the rendition unless its handler already did.
+    fn handle_rendition_result(&mut self, media_type: MediaType, result: Result<()>) -> Result<()> {
+        let e = match result {
+            Err(e) => e,
+            Ok(()) => return Ok(()),
+        };
+        if *e.kind() == ErrorKind::StaleResponse {
+            return Err(track!(e));
+        }
+        self.collect_track_events();
+        let (track, url) = if media_type == MediaType::Audio {
+            (self.audio_track.take(), self.audio_url.clone())
+        } else {
+            (self.subtitles_track.take(), self.subtitles_url.clone())
+        };
+        if let Some(track) = track {
+            self.retired_actions.extend(track.handler.in_flight_actions());
+        }
+        if *e.kind() != ErrorKind::FetchFailed {
+            if let Some(url) = url {
+                self.events.push_back(Event::FetchFailed {
+                    url,
+                    status: None,
+                    will_retry: false,
+                });
+            }
+        }
+        self.update_declared_codecs();
+        Ok(())
+    }
+
+    /// Switches to the next redundant stream of the current variant, or to another variant
+    /// if all of the redundant streams have failed.
+    ///
+    /// Returns `false` if there is no stream to switch to.
+    fn fail_over(&mut self) -> bool {
+        self.failovers += 1;
+        let variant = &mut self.variants[self.current_variant];
+        if self.failovers < variant.urls.len() {
+            variant.url_index = (variant.url_index + 1) % variant.urls.len();
+            if variant.url_index != 0 {
+                self.schedule_primary_retry();
+            }
+            let current = self.current_variant;
+            self.switch_variant(current, SwitchReason::Failover);
+            return true;
+        }
+
+        self.failovers = 0;
+        if let Some(variant) = self.abr.fallback_variant() {
+            self.schedule_primary_retry();
+            self.switch_variant(variant, SwitchReason::Failover);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Schedules `return_to_primary()` unless it has already been scheduled.
+    fn schedule_primary_retry(&mut self) {
+        if self.primary_retry_action_id.is_none() {
+            let delay = Duration::from_millis(self.config.primary_retry_interval_ms);
+            let action = self.action_factory.set_timeout(delay);
+            self.primary_retry_action_id = Some(action.id());
+            self.action_queue.push_back(action);
+        }
+    }
+
+    /// Makes the variants use their primary streams again,
+    /// and makes the variants that have failed selectable again.
+    fn return_to_primary(&mut self) {
+        self.abr.clear_failures();
+        self.failovers = 0;
+        let is_primary = self.variants[self.current_variant].url_index == 0;
+        for variant in &mut self.variants {
+            variant.url_index = 0;
+        }
+        if !is_primary {
+            // The handler resumes from the same media sequence number
+            let current = self.current_variant;
+            self.switch_variant(current, SwitchReason::Primary);
+        }
+    }
+
+    fn is_main_action(&self, action_id: ActionId) -> bool {
+        action_id.media_playlist_id() == self.main_track.handler.stream_id()
+    }
+
+    /// Returns the renditions of `media_type` in the group referred by the current variant.
+    fn renditions(&self, media_type: MediaType) -> Vec<&Rendition> {
+        let variant = &self.variants[self.current_variant];
+        let group_id = match media_type {
+            MediaType::Audio => variant.audio_group.as_ref(),
+            _ => variant.subtitles_group.as_ref(),
+        };
+        self.renditions
+            .iter()
+            .filter(|r| r.media_type == media_type && Some(&r.group_id) == group_id)
+            .collect()
+    }
+
+    fn selected_rendition(&self, media_type: MediaType) -> Option<&Rendition> {
+        let renditions = self.renditions(media_type);
+        let preference = match media_type {
+            MediaType::Audio => self.audio_preference.as_ref(),
+            _ => self.subtitles_preference.as_ref(),
+        };
+        let selected = preference
+            .and_then(|p| {
+                renditions
+                    .iter()
+                    .find(|r| r.name == *p)
+                    .or_else(|| renditions.iter().find(|r| r.language.as_ref() == Some(p)))
+            })
+            .or_else(|| renditions.iter().find(|r| r.is_default));
+        if media_type == MediaType::Audio {
+            // Some audio is needed even if no rendition is marked as default
+            selected.or_else(|| renditions.first()).cloned()
+        } else {
+            selected.cloned()
+        }
+    }
+
+    fn select_rendition(&mut self, media_type: MediaType, name_or_language: &str) -> Result<()> {
+        track_assert!(
+            self.renditions(media_type).iter().any(|r| {
+                r.name == name_or_language
+                    || r.language.as_ref().is_some_and(|l| l == name_or_language)
+            }),
+            ErrorKind::InvalidInput,
+            "No such rendition: {:?}",
+            name_or_language
+        );
+        let preference = Some(name_or_language.to_owned());
+        match media_type {
+            MediaType::Audio => self.audio_preference = preference,
+            _ => self.subtitles_preference = preference,
+        }
+        self.update_rendition_tracks();
+        Ok(())
+    }
+
+    /// Returns the action factory for a new handler.
+    ///
+    /// Stream ids are reused after wrapping around, except for those of the active handlers
+    /// and of the replaced ones with in-flight actions, so that no response is routed
+    /// to the wrong handler.
+    fn next_action_factory(&mut self) -> ActionFactory {
+        for _ in 0..StreamId::MAX {
+            self.last_stream_id = self.last_stream_id.wrapping_add(1);
+            if !self.is_stream_id_in_use(self.last_stream_id) {
+                break;
+            }
+        }
+        ActionFactory::new(self.last_stream_id)
+    }
+
+    fn is_stream_id_in_use(&self, stream_id: StreamId) -> bool {
+        let mut tracks = Some(&self.main_track)
+            .into_iter()
+            .chain(self.audio_track.iter())
+            .chain(self.subtitles_track.iter());
+        stream_id == MASTER_STREAM_ID
+            || tracks.any(|t| t.handler.stream_id() == stream_id)
+            || self.retired_actions
+                .iter()
+                .any(|a| a.media_playlist_id() == stream_id)
+    }
+
+    /// Replaces the active media playlist handler with the one for `variant`.
+    fn switch_variant(&mut self, variant: usize, reason: SwitchReason) {
+        self.collect_track_events();
+        self.events.push_back(Event::VariantSwitched {
+            from: self.current_variant,
+            to: variant,
+            bandwidth: self.variants[variant].bandwidth,
+            url: self.variants[variant].url().clone(),
+            reason,
+        });
+        self.current_variant = variant;
+        self.retired_actions
+            .extend(self.main_track.handler.in_flight_actions());
+        let action_factory = self.next_action_factory();
+        let url = self.variants[variant].url().clone();
+        let handler = MediaPlaylistHandler::new(action_factory, url, self.config.clone());
+        self.main_track.replace_handler(handler);
+        self.update_rendition_tracks();
+    }
+
+    /// Makes the audio and subtitles tracks follow the selected renditions.
+    fn update_rendition_tracks(&mut self) {
+        self.collect_track_events();
+        let audio_url = self.selected_rendition(MediaType::Audio)
+            .and_then(|r| r.url.clone());
+        if audio_url != self.audio_url {
+            self.audio_url = audio_url.clone();
+            let mut track = self.audio_track.take();
+            self.update_rendition_track(&mut track, audio_url);
+            self.audio_track = track;
+        }
+
+        let subtitles_url = self.selected_rendition(MediaType::Subtitles)
+            .and_then(|r| r.url.clone());
+        if subtitles_url != self.subtitles_url {
+            self.subtitles_url = subtitles_url.clone();
+            let mut track = self.subtitles_track.take();
+            self.update_rendition_track(&mut track, subtitles_url);
+            self.subtitles_track = track;
+        }
+        self.update_declared_codecs();
+    }
+
+    /// Tells the handlers the codecs declared by the current variant stream.
+    fn update_declared_codecs(&mut self) {
+        let codecs = self.variants[self.current_variant].codecs.clone();
+        if let Some(ref mut track) = self.audio_track {
+            let main_codecs = codecs.as_ref().and_then(|c| codecs::video_codecs(c));
+            self.main_track.handler.set_declared_codecs(main_codecs);
+            let audio_codecs = codecs.as_ref().and_then(|c| codecs::audio_codecs(c));
+            track.handler.set_declared_codecs(audio_codecs);
+        } else {
+            self.main_track.handler.set_declared_codecs(codecs);
+        }
+    }
+
+    fn update_rendition_track(&mut self, track: &mut Option<Track>, url: Option<Url>) {
+        let url = if let Some(url) = url {
+            url
+        } else {
+            // The rendition is contained in the variant streams (or not selected)
+            if let Some(track) = track.take() {
+                self.retired_actions.extend(track.handler.in_flight_actions());
+            }
+            return;
+        };
+        if let Some(ref track) = *track {
+            // Responses to the replaced handler may still arrive
+            self.retired_actions.extend(track.handler.in_flight_actions());
+        }
+        let action_factory = self.next_action_factory();
+        let handler = MediaPlaylistHandler::new(action_factory, url, self.config.clone());
+        if let Some(ref mut track) = *track {
+            track.replace_handler(handler);
+        } else {
+            let mut new_track = Track::new(handler);
+            new_track.handler.resume_after(&self.main_track.handler);
+            *track = Some(new_track);
+        }
+    }
+}
+
+/// Parses the WebVTT segments delivered by the subtitles `track`.
+fn read_subtitle_cues(track: &mut Track) -> Result<Vec<Cue>> {
+    let mut cues = Vec::new();
+    while let Some(segment) = track.next_segment() {
+        let text = track!(str::from_utf8(&segment.data).map_err(Error::from))?;
+        cues.extend(track!(webvtt::parse(text))?);
+    }
+    Ok(cues)
+}
+
+fn track_for(track: &mut Option<Track>, action_id: ActionId) -> Option<&mut Track> {
+    track
+        .as_mut()
+        .filter(|t| t.handler.stream_id() == action_id.media_playlist_id())
+}
+
+/// Takes the cues out of `cues` if the timestamp corresponding to the time zero is known.
+fn take_aligned_cues(cues: &mut VecDeque<Cue>, origin: Option<u64>) -> Vec<Cue> {
+    let origin = if let Some(origin) = origin {
+        origin
+    } else {
+        return Vec::new();
+    };
+    cues.drain(..)
+        .map(|mut cue| {
+            cue.align_to(origin);
+            cue
+        })
+        .collect()
+}
+
+fn parse_url(base_url: &Url, uri: &str) -> Result<Url> {
+    track!(
+        Url::options()
+            .base_url(Some(base_url))
+            .parse(uri)
+            .map_err(Error::from)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use ErrorKind;
+    use super::*;
+
+    const MASTER: &str = "#EXTM3U
+#EXT-X-STREAM-INF:BANDWIDTH=2000000
+hi.m3u8
+#EXT-X-STREAM-INF:BANDWIDTH=1000000
+lo.m3u8
+";
+
+    const MEDIA: &str = "#EXTM3U
+#EXT-X-TARGETDURATION:2
+#EXTINF:2,
+s0.ts
+";
+
+    fn fetches(handler: &mut MasterPlaylistHandler) -> Vec<ActionId> {
+        let mut ids = Vec::new();
+        while let Some(action) = handler.next_action() {
+            if let Action::FetchData { action_id, .. } = action {
+                ids.push(action_id);
+            }
+        }
+        ids
+    }
+
+    /// Returns the paths of the URLs to be fetched, and the ids of the timeouts.
+    fn take_actions(
+        handler: &mut MasterPlaylistHandler,
+    ) -> (Vec<(ActionId, String)>, Vec<ActionId>) {
+        let mut fetches = Vec::new();
+        let mut timeouts = Vec::new();
+        while let Some(action) = handler.next_action() {
+            match action {
+                Action::FetchData { action_id, url, .. } => {
+                    fetches.push((action_id, url.path().to_owned()))
+                }
+                Action::SetTimeout { action_id, .. } => timeouts.push(action_id),
+            }
+        }
+        (fetches, timeouts)
+    }
+
+    fn switches(handler: &mut MasterPlaylistHandler) -> Vec<(usize, usize, String, SwitchReason)> {
+        let mut switches = Vec::new();
+        while let Some(event) = handler.next_event() {
+            if let Event::VariantSwitched { from, to, url, reason, .. } = event {
+                switches.push((from, to, url.path().to_owned(), reason));
+            }
+        }
+        switches
+    }
+
+    /// Responds to the actions with `respond` (`None` for a 404 error) until none is left,
+    /// and returns the errors.
+    fn respond_all(
+        handler: &mut MasterPlaylistHandler,
+        respond: &dyn Fn(&str) -> Option<Vec<u8>>,
+    ) -> Vec<Error> {
+        let mut errors = Vec::new();
+        while let Some(action) = handler.next_action() {
+            let result = match action {
+                Action::FetchData { action_id, url, .. } => match respond(url.path()) {
+                    Some(data) => handler.handle_data(action_id, &data, 10),
+                    None => handler.handle_fetch_error(action_id, Some(404)),
+                },
+                Action::SetTimeout { action_id, .. } => handler.handle_timeout(action_id),
+            };
+            errors.extend(result.err());
+        }
+        errors
+    }
+
+    #[test]
+    fn redundant_streams_are_grouped_by_all_attributes() {
+        let master = "#EXTM3U
+#EXT-X-STREAM-INF:BANDWIDTH=2000000,RESOLUTION=1280x720
+a.m3u8
+#EXT-X-STREAM-INF:BANDWIDTH=2000000,RESOLUTION=1280x720
+a-backup.m3u8
+#EXT-X-STREAM-INF:BANDWIDTH=2000000,RESOLUTION=640x360
+b.m3u8
+#EXT-X-STREAM-INF:BANDWIDTH=2000000,RESOLUTION=1280x720,FRAME-RATE=60
+c.m3u8
+#EXT-X-STREAM-INF:BANDWIDTH=2000000,RESOLUTION=1280x720,FRAME-RATE=60
+c-backup.m3u8
+";
+        let url = "http://example.com/master.m3u8".parse().unwrap();
+        let handler = MasterPlaylistHandler::new(url, master, Default::default()).unwrap();
+        let urls = handler
+            .variants
+            .iter()
+            .map(|v| v.urls.iter().map(|u| u.path()).collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            urls,
+            [
+                vec!["/a.m3u8", "/a-backup.m3u8"],
+                vec!["/b.m3u8"],
+                vec!["/c.m3u8", "/c-backup.m3u8"],
+            ]
+        );
+    }
+
+    #[test]
+    fn failover_and_return_to_primary() {
+        let master = "#EXTM3U
+#EXT-X-STREAM-INF:BANDWIDTH=2000000
+hi.m3u8
+#EXT-X-STREAM-INF:BANDWIDTH=2000000
+hi-backup.m3u8
+#EXT-X-STREAM-INF:BANDWIDTH=1000000
+lo.m3u8
+";
+        let url = "http://example.com/master.m3u8".parse().unwrap();
+        let config = HlsPlayerConfig {
+            max_fetch_retries: 0,
+            ..Default::default()
+        };
+        let mut handler = MasterPlaylistHandler::new(url, master, config).unwrap();
+        let (fetches, _) = take_actions(&mut handler);
+        assert_eq!(fetches[0].1, "/hi.m3u8");
+
+        // The redundant stream is used while the primary one is unavailable
+        handler.handle_fetch_error(fetches[0].0, Some(404)).unwrap();
+        assert_eq!(
+            switches(&mut handler),
+            [(0, 0, "/hi-backup.m3u8".to_owned(), SwitchReason::Failover)]
+        );
+        let (fetches, timeouts) = take_actions(&mut handler);
+        assert_eq!(fetches[0].1, "/hi-backup.m3u8");
+        assert_eq!(timeouts.len(), 1);
+        handler.handle_data(fetches[0].0, MEDIA.as_bytes(), 10).unwrap();
+
+        // The primary stream is retried after the interval
+        handler.handle_timeout(timeouts[0]).unwrap();
+        assert_eq!(
+            switches(&mut handler),
+            [(0, 0, "/hi.m3u8".to_owned(), SwitchReason::Primary)]
+        );
+        let (fetches, _) = take_actions(&mut handler);
+        assert_eq!(fetches[0].1, "/hi.m3u8");
+
+        // Falls back to the lower variant if all of the redundant streams fail
+        handler.handle_fetch_error(fetches[0].0, Some(404)).unwrap();
+        let (fetches, timeouts) = take_actions(&mut handler);
+        assert_eq!(fetches[0].1, "/hi-backup.m3u8");
+        assert_eq!(timeouts.len(), 1);
+        handler.handle_fetch_error(fetches[0].0, Some(404)).unwrap();
+        assert_eq!(
+            switches(&mut handler),
+            [
+                (0, 0, "/hi-backup.m3u8".to_owned(), SwitchReason::Failover),
+                (0, 1, "/lo.m3u8".to_owned(), SwitchReason::Failover),
+            ]
+        );
+        let (fetches, _) = take_actions(&mut handler);
+        assert_eq!(fetches[0].1, "/lo.m3u8");
+
+        // The failed variant is selectable again after the interval
+        handler.handle_timeout(timeouts[0]).unwrap();
+        assert_eq!(switches(&mut handler), []);
+        handler.handle_fetch_error(fetches[0].0, Some(404)).unwrap();
+        assert_eq!(
+            switches(&mut handler),
+            [(1, 0, "/hi.m3u8".to_owned(), SwitchReason::Failover)]
+        );
+    }
+
+    #[test]
+    fn failed_renditions_are_dropped() {
+        let master = r#"#EXTM3U
+#EXT-X-MEDIA:TYPE=SUBTITLES,GROUP-ID="subs",NAME="en",DEFAULT=YES,URI="subs.m3u8"
+#EXT-X-MEDIA:TYPE=AUDIO,GROUP-ID="aud",NAME="en",DEFAULT=YES,URI="audio.m3u8"
+#EXT-X-STREAM-INF:BANDWIDTH=1000000,CODECS="avc1.64001f,mp4a.40.2",AUDIO="aud",SUBTITLES="subs"
+video.m3u8
+"#;
+        let media = r#"#EXTM3U
+#EXT-X-TARGETDURATION:2
+#EXT-X-MAP:URI="init.mp4"
+#EXTINF:2,
+s0.m4s
+#EXTINF:2,
+s1.m4s
+#EXT-X-ENDLIST
+"#;
+        let url = "http://example.com/master.m3u8".parse().unwrap();
+        let mut handler = MasterPlaylistHandler::new(url, master, Default::default()).unwrap();
+        assert!(handler.has_alternate_audio());
+        let errors = respond_all(&mut handler, &|path| match path {
+            "/subs.m3u8" => None,
+            "/audio.m3u8" => Some(b"not a playlist".to_vec()),
+            "/video.m3u8" => Some(media.as_bytes().to_vec()),
+            "/init.mp4" => Some(b"\0\0\0\x08moov".to_vec()),
+            _ => Some(b"\0\0\0\x08moof".to_vec()),
+        });
+        assert!(errors.is_empty(), "{:?}", errors);
+        assert!(!handler.has_alternate_audio());
+
+        let mut failures = Vec::new();
+        while let Some(event) = handler.next_event() {
+            if let Event::FetchFailed { url, status, will_retry } = event {
+                failures.push((url.path().to_owned(), status, will_retry));
+            }
+        }
+        let subs_failure = |will_retry| ("/subs.m3u8".to_owned(), Some(404), will_retry);
+        assert_eq!(
+            failures,
+            [
+                ("/audio.m3u8".to_owned(), None, false),
+                subs_failure(true),
+                subs_failure(true),
+                subs_failure(true),
+                subs_failure(false),
+            ]
+        );
+
+        // The video keeps playing
+        let mut segments = 0;
+        while handler.next_segment().is_some() {
+            segments += 1;
+        }
+        assert_eq!(segments, 3);
+        assert!(handler.is_ended());
+    }
+
+    #[test]
+    fn stream_ids_of_in_flight_actions_are_not_reused() {
+        let url = "http://example.com/master.m3u8".parse().unwrap();
+        let mut handler = MasterPlaylistHandler::new(url, MASTER, Default::default()).unwrap();
+        let first = fetches(&mut handler)[0];
+
+        let mut late = VecDeque::new();
+        for i in 0..600 {
+            handler.switch_variant(i % 2, SwitchReason::Bandwidth);
+            let id = fetches(&mut handler)[0];
+            assert_ne!(id.media_playlist_id(), first.media_playlist_id());
+
+            // Responses to the replaced handlers arrive a few switches later
+            late.push_back(id);
+            if late.len() > 8 {
+                let id = late.pop_front().unwrap();
+                let e = handler.handle_data(id, MEDIA.as_bytes(), 10).err().unwrap();
+                assert_eq!(*e.kind(), ErrorKind::StaleResponse);
+            }
+        }
+        let id = late.pop_back().unwrap();
+        handler.handle_data(id, MEDIA.as_bytes(), 10).unwrap();
+
+        let e = handler
+            .handle_data(first, MEDIA.as_bytes(), 10)
+            .err()
+            .unwrap();
+        assert_eq!(*e.kind(), ErrorKind::StaleResponse);
+    }
+
+    #[test]
+    fn responses_to_unknown_actions_are_rejected() {
+        let url = "http://example.com/master.m3u8".parse().unwrap();
+        let mut handler = MasterPlaylistHandler::new(url, MASTER, Default::default()).unwrap();
+        let retired = fetches(&mut handler)[0];
+        handler.switch_variant(1, SwitchReason::Bandwidth);
+        let active = fetches(&mut handler)[0];
+
+        let e = handler.handle_data(retired, MEDIA.as_bytes(), 10).err().unwrap();
+        assert_eq!(*e.kind(), ErrorKind::StaleResponse);
+
+        // Neither issued by the active handlers nor waiting for a response
+        let e = handler.handle_data(retired, MEDIA.as_bytes(), 10).err().unwrap();
+        assert_eq!(*e.kind(), ErrorKind::InvalidInput);
+        let unknown = ActionId::from(0xff << 24);
+        let e = handler.handle_data(unknown, MEDIA.as_bytes(), 10).err().unwrap();
+        assert_eq!(*e.kind(), ErrorKind::InvalidInput);
+
+        handler.handle_data(active, MEDIA.as_bytes(), 10).unwrap();
     }
 }
@@ -1,45 +1,284 @@
 use std::cmp;
-use std::collections::VecDeque;
+use std::mem;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
 use std::time::Duration;
 use hls_m3u8::MediaPlaylist;
-use mpeg2ts::ts::TsPacketReader;
-use mse_fmp4::mpeg2_ts;
+use hls_m3u8::tags::ExtXMap;
+use hls_m3u8::types::{DecryptionKey, EncryptionMethod, PlaylistType};
+use mse_fmp4::fmp4::{InitializationSegment, Mp4Box};
 use mse_fmp4::io::WriteTo;
 use url::Url;
 
-use {Error, Result};
-use super::{Action, ActionFactory, ActionId};
+use {Error, ErrorKind, Result};
+use super::{Action, ActionFactory, ActionId, ByteRange, HlsPlayerConfig, StreamId};
+use super::ad_break::{AdEvent, AdMarkerSource};
+use super::cea608::{self, Cea608Decoder};
+use super::codecs;
+use super::crypto::{self, Iv, Key};
+use super::date_time::{self, DateTimeMapping};
+use super::event::Event;
+use super::h264;
+use super::id3::{self, TimedMetadata};
+use super::playlist_tags::{self, ParsedMediaPlaylist, PreloadHint};
+use super::remux;
+use super::sample_aes;
+use super::scte35;
+use super::ts::{self, TsSegment};
+use super::webvtt::{self, Cue};
 
-type SequenceNumber = u64;
+pub type SequenceNumber = u64;
+
+/// The sequence number and the part index (`None` for a whole segment) of a segment.
+type SegmentId = (SequenceNumber, Option<usize>);
+
+/// Segment to be appended to the `SourceBuffer`.
+#[derive(Debug)]
+pub struct BufferedSegment {
+    pub data: Vec<u8>,
+
+    /// `true` if this is the first segment after a discontinuity (`EXT-X-DISCONTINUITY`).
+    ///
+    /// The timestamps of the segments following a discontinuity are not continuous with
+    /// the preceding ones, so the player should reset its timestamp offset.
+    pub is_discontinuity: bool,
+
+    /// The position (in seconds) at which this segment should be placed, if it is the first one
+    /// after a seek (or a start from the middle of the playlist).
+    ///
+    /// The player should set the `timestampOffset` of the `SourceBuffer` to this value
+    /// before appending the segment.
+    pub timestamp_offset: Option<f64>,
+
+    /// Duration of the media in this segment (in seconds), or zero for initialization segments.
+    pub duration: f64,
+
+    /// Codecs string of this segment if it is an initialization segment.
+    ///
+    /// The player should change the type of the `SourceBuffer` if this differs from
+    /// the current one (e.g., after a variant switch).
+    pub codecs: Option<String>,
+}
+
+#[derive(Debug)]
+struct Segment {
+    seq: SequenceNumber,
+    url: Url,
+    range: Option<ByteRange>,
+    discontinuity_seq: SequenceNumber,
+    key: Option<SegmentKey>,
+    init_section: Option<InitSection>,
+    position: SegmentPosition,
+    ad_events: Vec<AdEvent>,
+
+    /// The index of the partial segment (`EXT-X-PART`), or `None` for a whole segment.
+    part_index: Option<usize>,
+
+    ongoing: bool,
+
+    /// The fetched data waiting for the preceding segments to be fetched.
+    data: Option<Vec<u8>>,
+}
+impl Segment {
+    fn id(&self) -> SegmentId {
+        (self.seq, self.part_index)
+    }
+}
+
+/// State inherited from the preceding tags of a segment.
+#[derive(Debug)]
+struct SegmentContext {
+    discontinuity_seq: SequenceNumber,
+    key: Option<SegmentKey>,
+    init_section: Option<InitSection>,
+}
+
+/// State given by the tags preceding a segment (and those of the previous segments).
+///
+/// This is kept to resolve the segments following the ones skipped in Playlist Delta Updates.
+#[derive(Debug, Clone)]
+struct PlaylistState {
+    key: Option<DecryptionKey>,
+    map: Option<ExtXMap>,
+    discontinuity_seq: SequenceNumber,
+    prev_range: Option<(Url, ByteRange)>,
+}
+
+/// Partial segment fetched in advance by `EXT-X-PRELOAD-HINT`.
+#[derive(Debug)]
+struct Preload {
+    action_id: ActionId,
+    url: Url,
+    range: Option<ByteRange>,
+    data: Option<Vec<u8>>,
+
+    /// The queued segment waiting for the data.
+    claimed_by: Option<SegmentId>,
+}
+
+#[derive(Debug)]
+struct FetchedSegment {
+    discontinuity_seq: SequenceNumber,
+    key: Option<SegmentKey>,
+    init_section: Option<InitSection>,
+    start: f64,
+    duration: f64,
+    ad_events: Vec<AdEvent>,
+    data: Vec<u8>,
+}
+
+/// Resource requested by a `FetchData` action.
+#[derive(Debug, Clone)]
+struct FetchRequest {
+    url: Url,
+    range: Option<ByteRange>,
+
+    /// The number of the failed attempts so far.
+    failures: u32,
+}
+
+/// Position of a segment in the presentation timeline (in seconds).
+#[derive(Debug, Clone, Copy)]
+struct SegmentPosition {
+    start: f64,
+    duration: f64,
+}
+
+#[derive(Debug, Clone)]
+struct SegmentKey {
+    method: EncryptionMethod,
+    url: Url,
+    iv: Iv,
+}
+
+/// Media initialization section of fMP4 segments (`EXT-X-MAP`).
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct InitSection {
+    url: Url,
+    range: Option<ByteRange>,
+
+    /// The URL of the AES-128 key and the IV, if the section is encrypted.
+    key: Option<(Url, Iv)>,
+}
 
 #[derive(Debug)]
 pub struct MediaPlaylistHandler {
+    config: HlsPlayerConfig,
     media_playlist_url: Url,
     action_factory: ActionFactory,
     action_queue: VecDeque<Action>,
-    segment_queue: VecDeque<(SequenceNumber, bool, Url)>,
-    buffered_segments: VecDeque<Vec<u8>>,
-    last_media_sequence: SequenceNumber,
-    is_initialized: bool,
+    fetch_requests: HashMap<ActionId, FetchRequest>,
+    retry_timeouts: HashMap<ActionId, (ActionId, FetchRequest)>,
+    segment_queue: VecDeque<Segment>,
+    pending_segments: VecDeque<FetchedSegment>,
+    buffered_segments: VecDeque<BufferedSegment>,
+    keys: HashMap<Url, Key>,
+    key_fetches: HashMap<ActionId, Url>,
+    init_sections: HashMap<InitSection, Vec<u8>>,
+    init_section_fetches: HashMap<ActionId, InitSection>,
+    current_init_section: Option<InitSection>,
+    last_media_sequence: Option<SequenceNumber>,
+    last_fetched_sequence: Option<SequenceNumber>,
+    last_fetched_part: Option<(SequenceNumber, usize)>,
+    last_queued_part: Option<(SequenceNumber, usize)>,
+    last_fetched_end: f64,
+    next_segment_start: f64,
+    preload: Option<Preload>,
+    playlist_states: BTreeMap<SequenceNumber, PlaylistState>,
+    segment_positions: BTreeMap<SequenceNumber, SegmentPosition>,
+    can_skip_segments: bool,
+    date_time_mapping: DateTimeMapping,
+    last_discontinuity_seq: Option<SequenceNumber>,
+    last_track_configuration: Option<Vec<u8>>,
+    codecs: Option<String>,
+    declared_codecs: Option<String>,
+    first_timestamp: Option<u64>,
+    caption_decoder: Cea608Decoder,
+    timed_metadata: Vec<TimedMetadata>,
+    ad_events: Vec<AdEvent>,
+    is_discontinuity: bool,
+    timestamp_offset: Option<f64>,
+    start_position: Option<f64>,
+    is_end_of_playlist: bool,
     fetch_playlist_action_id: ActionId,
+    reload_timeout_action_id: Option<ActionId>,
+    segment_fetches: HashMap<ActionId, SegmentId>,
+    buffer_level: f64,
+    events: VecDeque<Event>,
+
+    /// `true` once a media segment has been buffered.
+    ///
+    /// Until then, a zero buffer level means that playback has not started yet.
+    is_started: bool,
+    is_stalled: bool,
+    is_end_notified: bool,
     segments_total: u32,
     segment_durations_total: Duration,
 }
 impl MediaPlaylistHandler {
-    pub fn new(mut action_factory: ActionFactory, media_playlist_url: Url) -> Self {
+    pub fn new(
+        mut action_factory: ActionFactory,
+        media_playlist_url: Url,
+        config: HlsPlayerConfig,
+    ) -> Self {
         let mut action_queue = VecDeque::new();
         let action = action_factory.fetch_data(media_playlist_url.clone());
         let action_id = action.id();
         action_queue.push_back(action);
+        let mut fetch_requests = HashMap::new();
+        fetch_requests.insert(
+            action_id,
+            FetchRequest {
+                url: media_playlist_url.clone(),
+                range: None,
+                failures: 0,
+            },
+        );
         MediaPlaylistHandler {
+            config,
             media_playlist_url,
             action_factory,
             action_queue,
+            fetch_requests,
+            retry_timeouts: HashMap::new(),
             segment_queue: VecDeque::new(),
+            pending_segments: VecDeque::new(),
             buffered_segments: VecDeque::new(),
-            last_media_sequence: 0,
-            is_initialized: false,
+            keys: HashMap::new(),
+            key_fetches: HashMap::new(),
+            init_sections: HashMap::new(),
+            init_section_fetches: HashMap::new(),
+            current_init_section: None,
+            last_media_sequence: None,
+            last_fetched_sequence: None,
+            last_fetched_part: None,
+            last_queued_part: None,
+            last_fetched_end: 0.0,
+            next_segment_start: 0.0,
+            preload: None,
+            playlist_states: BTreeMap::new(),
+            segment_positions: BTreeMap::new(),
+            can_skip_segments: false,
+            date_time_mapping: DateTimeMapping::new(),
+            last_discontinuity_seq: None,
+            last_track_configuration: None,
+            codecs: None,
+            declared_codecs: None,
+            first_timestamp: None,
+            caption_decoder: Cea608Decoder::new(),
+            timed_metadata: Vec::new(),
+            ad_events: Vec::new(),
+            is_discontinuity: false,
+            timestamp_offset: None,
+            start_position: None,
+            is_end_of_playlist: false,
             fetch_playlist_action_id: action_id,
+            reload_timeout_action_id: None,
+            segment_fetches: HashMap::new(),
+            buffer_level: 0.0,
+            events: VecDeque::new(),
+            is_started: false,
+            is_stalled: false,
+            is_end_notified: false,
             segments_total: 0,
             segment_durations_total: Duration::from_secs(0),
         }
@@ -48,28 +287,339 @@ impl MediaPlaylistHandler {
     pub fn with_m3u8(
         action_factory: ActionFactory,
         media_playlist_url: Url,
+        config: HlsPlayerConfig,
         m3u8: &str,
     ) -> Result<Self> {
-        let mut this = Self::new(action_factory, media_playlist_url);
-        let _ = this.next_action();
+        let mut this = Self::new(action_factory, media_playlist_url, config);
+        this.events.push_back(Event::Started {
+            url: this.media_playlist_url.clone(),
+        });
+        if let Some(action) = this.next_action() {
+            this.fetch_requests.remove(&action.id());
+        }
         track!(this.handle_playlist(m3u8, 0))?;
         Ok(this)
     }
 
+    /// Makes the handler start fetching from the segment following the one most recently
+    /// fetched by `prev`, and continue the presentation timeline of `prev`.
+    ///
+    /// This is used to take over the playback position when switching variant streams.
+    pub fn resume_after(&mut self, prev: &MediaPlaylistHandler) {
+        self.last_media_sequence = prev.last_fetched_sequence;
+        self.last_fetched_sequence = prev.last_fetched_sequence;
+        self.last_fetched_part = prev.last_fetched_part;
+        self.last_queued_part = prev.last_fetched_part;
+        self.last_fetched_end = prev.last_fetched_end;
+        self.next_segment_start = prev.last_fetched_end;
+        self.date_time_mapping = prev.date_time_mapping.clone();
+        self.is_started = prev.is_started;
+    }
+
+    pub fn config(&self) -> &HlsPlayerConfig {
+        &self.config
+    }
+
+    /// Sets the codecs declared by the `CODECS` attribute of the variant stream.
+    ///
+    /// These are used for fMP4 initialization sections whose sample entries are unknown.
+    pub fn set_declared_codecs(&mut self, codecs: Option<String>) {
+        self.declared_codecs = codecs;
+    }
+
+    pub fn stream_id(&self) -> StreamId {
+        self.action_factory.media_playlist_id()
+    }
+
+    /// Returns the actions taken by `next_action()` whose results have not been handled yet.
+    pub fn in_flight_actions(&self) -> Vec<ActionId> {
+        let queued = self.action_queue
+            .iter()
+            .map(|a| a.id())
+            .collect::<HashSet<_>>();
+        self.fetch_requests
+            .keys()
+            .chain(self.retry_timeouts.keys())
+            .chain(self.reload_timeout_action_id.iter())
+            .filter(|id| !queued.contains(id))
+            .cloned()
+            .collect()
+    }
+
+    /// Returns the sequence number of the most recently fetched segment.
+    pub fn last_fetched_sequence(&self) -> Option<SequenceNumber> {
+        self.last_fetched_sequence
+    }
+
+    /// Returns the codecs string of the segment returned by the next `next_segment()`,
+    /// if it is an initialization segment.
+    pub fn next_codecs(&self) -> Option<&str> {
+        self.buffered_segments
+            .front()
+            .and_then(|x| x.codecs.as_deref())
+    }
+
+    /// Returns the codecs string of the last initialization segment.
+    ///
+    /// This is `None` until the first initialization segment is ready.
+    pub fn codecs(&self) -> Option<&str> {
+        self.codecs.as_deref()
+    }
+
+    /// Returns the MPEG-2 TS timestamp (in 90kHz units) corresponding to the time zero of
+    /// the delivered segments.
+    ///
+    /// This is the earliest presentation time stamp in the first TS segment.
+    /// The timestamps of fMP4 and packed audio segments are not inspected,
+    /// so zero is used for them.
+    pub fn first_timestamp(&self) -> Option<u64> {
+        self.first_timestamp
+    }
+
+    /// Returns the closed caption cues decoded since the last call.
+    ///
+    /// The cue times are in seconds in the MPEG-2 TS timeline.
+    pub fn take_caption_cues(&mut self) -> Vec<Cue> {
+        self.caption_decoder.take_cues()
+    }
+
+    /// Returns the ID3 timed metadata extracted since the last call.
+    ///
+    /// The times are in seconds in the MPEG-2 TS timeline.
+    pub fn take_timed_metadata(&mut self) -> Vec<TimedMetadata> {
+        mem::take(&mut self.timed_metadata)
+    }
+
+    /// Returns the ad break events signaled since the last call.
+    ///
+    /// The times are in seconds in the presentation timeline,
+    /// whose origin is the start of the first segment delivered by this handler
+    /// (or the one taken over by `resume_after()`).
+    pub fn take_ad_events(&mut self) -> Vec<AdEvent> {
+        mem::take(&mut self.ad_events)
+    }
+
+    /// Returns the mapping between the presentation timeline and the wall-clock times
+    /// given by `EXT-X-PROGRAM-DATE-TIME`.
+    pub fn date_time_mapping(&self) -> &DateTimeMapping {
+        &self.date_time_mapping
+    }
+
+    /// Returns `true` if `action_id` is the identifier of a segment fetch.
+    pub fn is_segment_fetch(&self, action_id: ActionId) -> bool {
+        self.segment_fetches.contains_key(&action_id)
+    }
+
+    /// Returns `true` if the playlist has ended and all of its segments have been delivered.
+    pub fn is_ended(&self) -> bool {
+        self.is_end_of_playlist && self.segment_queue.is_empty()
+            && self.pending_segments.is_empty() && self.buffered_segments.is_empty()
+    }
+
     pub fn next_action(&mut self) -> Option<Action> {
         self.action_queue.pop_front()
     }
 
-    pub fn next_segment(&mut self) -> Option<Vec<u8>> {
+    pub fn next_segment(&mut self) -> Option<BufferedSegment> {
         self.buffered_segments.pop_front()
     }
 
-    pub fn handle_timeout(&mut self, _action_id: ActionId) -> Result<()> {
-        let action = self.action_factory
-            .fetch_data(self.media_playlist_url.clone());
-        let action_id = action.id();
+    /// Returns the next event to be notified.
+    ///
+    /// `Event::Ended` is returned once after all of the segments have been taken.
+    pub fn next_event(&mut self) -> Option<Event> {
+        if let Some(event) = self.events.pop_front() {
+            return Some(event);
+        }
+        if !self.is_end_notified && self.is_ended() {
+            self.is_end_notified = true;
+            return Some(Event::Ended);
+        }
+        None
+    }
+
+    /// Notifies `Event::Error` on an error that stops playback.
+    pub fn notify_error(&mut self, error: &Error) {
+        self.events.push_back(Event::Error {
+            kind: *error.kind(),
+            reason: error.reason(),
+        });
+    }
+
+    /// Returns `true` if the segment returned by the next `next_segment()` starts a discontinuity.
+    pub fn is_discontinuity(&self) -> bool {
+        self.buffered_segments
+            .front()
+            .is_some_and(|x| x.is_discontinuity)
+    }
+
+    /// Returns the position (in seconds) from which playback should start,
+    /// if the playlist requests a precise start (`EXT-X-START:PRECISE=YES`).
+    ///
+    /// Otherwise, playback starts from the beginning of the first segment delivered.
+    /// This is cleared by `seek()`.
+    pub fn start_position(&self) -> Option<f64> {
+        self.start_position
+    }
+
+    /// Returns the `timestamp_offset` of the segment returned by the next `next_segment()`.
+    pub fn timestamp_offset(&self) -> Option<f64> {
+        self.buffered_segments
+            .front()
+            .and_then(|x| x.timestamp_offset)
+    }
+
+    /// Returns the duration (in seconds) of the media fetched or being fetched,
+    /// but not yet taken by `next_segment()`.
+    pub fn forward_buffer(&self) -> f64 {
+        let buffered = self.buffered_segments
+            .iter()
+            .map(|x| x.duration)
+            .sum::<f64>();
+        let pending = self.pending_segments
+            .iter()
+            .map(|x| x.duration)
+            .sum::<f64>();
+        let in_flight = self.segment_queue
+            .iter()
+            .filter(|x| x.ongoing)
+            .map(|x| x.position.duration)
+            .sum::<f64>();
+        buffered + pending + in_flight
+    }
+
+    /// Updates the amount of media (in seconds) buffered by the caller ahead of the playback
+    /// position, and resumes fetching segments if the forward buffer has room.
+    ///
+    /// `Event::Stalled` is notified if the level is zero while no segments are ready,
+    /// once playback has started.
+    pub fn set_buffer_level(&mut self, level: f64) -> Result<()> {
+        self.buffer_level = level;
+        if level <= 0.0 && self.buffered_segments.is_empty() && !self.is_ended()
+            && self.is_started && !self.is_stalled
+        {
+            self.is_stalled = true;
+            self.events.push_back(Event::Stalled);
+        }
+        track!(self.fetch_next_segments())
+    }
+
+    /// Restarts fetching from the segment containing `position` (in seconds),
+    /// and returns the start position of the segment.
+    ///
+    /// The queued segments are discarded, and the playlist is reloaded to locate the segments.
+    /// The first segment delivered after this has `timestamp_offset` set to the returned value.
+    /// Positions outside the segments in the playlist are clamped.
+    pub fn seek(&mut self, position: f64) -> Result<f64> {
+        let target = self.segment_positions
+            .iter()
+            .rev()
+            .find(|x| x.1.start <= position)
+            .or_else(|| self.segment_positions.iter().next())
+            .map(|(&seq, &position)| (seq, position));
+        let (seq, target) =
+            track_assert_some!(target, ErrorKind::InvalidInput, "No segments to seek to");
+
+        if self.buffered_segments.iter().any(|x| x.codecs.is_some()) {
+            // The initialization segment has not been taken yet, so it is emitted again
+            self.current_init_section = None;
+            self.last_track_configuration = None;
+        }
+        self.segment_queue.clear();
+        self.pending_segments.clear();
+        self.buffered_segments.clear();
+        let mut discarded = self.segment_fetches.drain().map(|x| x.0).collect::<Vec<_>>();
+        discarded.extend(self.preload.take().map(|p| p.action_id));
+        for action in &self.action_queue {
+            if discarded.contains(&action.id()) {
+                // Not issued yet (the responses to the issued ones are rejected as stale)
+                self.fetch_requests.remove(&action.id());
+            }
+        }
+        self.action_queue.retain(|a| !discarded.contains(&a.id()));
+
+        self.last_media_sequence = seq.checked_sub(1);
+        self.last_fetched_sequence = seq.checked_sub(1);
+        self.last_queued_part = None;
+        self.last_fetched_part = None;
+        self.last_fetched_end = target.start;
+        self.next_segment_start = target.start;
+        self.caption_decoder = Cea608Decoder::new();
+        self.is_discontinuity = true;
+        self.timestamp_offset = Some(target.start);
+        self.start_position = None;
+        self.is_end_of_playlist = false;
+        self.is_end_notified = false;
+
+        // Delta updates cannot be used, since the target segments may be skipped
+        let url = self.media_playlist_url.clone();
+        self.fetch_playlist_action_id = self.push_fetch(url, None);
+        self.reload_timeout_action_id = None;
+        Ok(target.start)
+    }
+
+    pub fn handle_timeout(&mut self, action_id: ActionId) -> Result<()> {
+        if let Some((failed_action_id, request)) = self.retry_timeouts.remove(&action_id) {
+            self.retry_fetch(failed_action_id, request);
+            return Ok(());
+        }
+        if self.reload_timeout_action_id != Some(action_id) {
+            // A timer set before a seek
+            return Ok(());
+        }
+        self.reload_timeout_action_id = None;
+        let url = self.playlist_reload_url(None);
+        self.fetch_playlist_action_id = self.push_fetch(url, None);
+        Ok(())
+    }
+
+    /// Handles the failure of the fetch issued by `action_id`.
+    ///
+    /// `status` is the HTTP status code of the response, or `None` if no response was received.
+    /// The fetch is retried with exponential backoff, and an error of the kind
+    /// `ErrorKind::FetchFailed` is returned once `HlsPlayerConfig::max_fetch_retries` is exceeded.
+    pub fn handle_fetch_error(&mut self, action_id: ActionId, status: Option<u16>) -> Result<()> {
+        let mut request = if let Some(request) = self.fetch_requests.remove(&action_id) {
+            request
+        } else {
+            // Unknown or already handled
+            return Ok(());
+        };
+        if !self.is_active_fetch(action_id) {
+            // Discarded by a seek
+            return Ok(());
+        }
+        let is_preload = self.preload.as_ref().is_some_and(|p| p.action_id == action_id);
+        self.events.push_back(Event::FetchFailed {
+            url: request.url.clone(),
+            status,
+            will_retry: is_preload || request.failures < self.config.max_fetch_retries,
+        });
+        if is_preload {
+            // Preload hints are speculative, so the segment is fetched in the usual way instead
+            let preload = self.preload.take().expect("Never fails");
+            if let Some(id) = preload.claimed_by {
+                if let Some(segment) = self.segment_queue.iter_mut().find(|s| s.id() == id) {
+                    segment.ongoing = false;
+                }
+                track!(self.fetch_next_segments())?;
+            }
+            return Ok(());
+        }
+        track_assert!(
+            request.failures < self.config.max_fetch_retries,
+            ErrorKind::FetchFailed,
+            "Cannot fetch {} (status: {:?})",
+            request.url,
+            status
+        );
+
+        let delay = self.config.retry_delay(request.failures);
+        request.failures += 1;
+        let action = self.action_factory.set_timeout(delay);
+        self.retry_timeouts
+            .insert(action.id(), (action_id, request));
         self.action_queue.push_back(action);
-        self.fetch_playlist_action_id = action_id;
         Ok(())
     }
 
@@ -79,50 +629,285 @@ impl MediaPlaylistHandler {
         data: &[u8],
         fetch_duration_ms: u32,
     ) -> Result<()> {
+        let request = track_assert_some!(
+            self.fetch_requests.remove(&action_id),
+            ErrorKind::InvalidInput,
+            "Unknown action: {:?}",
+            action_id
+        );
+        track_assert!(
+            self.is_active_fetch(action_id),
+            ErrorKind::StaleResponse,
+            "Response to a cancelled request: {:?} ({})",
+            action_id,
+            request.url
+        );
         if action_id == self.fetch_playlist_action_id {
             use std::str;
 
             let m3u8 = track!(str::from_utf8(data).map_err(Error::from))?;
             track!(self.handle_playlist(m3u8, fetch_duration_ms))?;
-        } else {
-            track!(self.handle_segment(data))?;
+        } else if let Some(key_url) = self.key_fetches.remove(&action_id) {
+            track!(self.handle_key(key_url, data))?;
+        } else if let Some(init_section) = self.init_section_fetches.remove(&action_id) {
+            self.init_sections.insert(init_section, data.to_owned());
+            track!(self.handle_pending_segments())?;
+        } else if self.preload.as_ref().is_some_and(|p| p.action_id == action_id) {
+            self.push_segment_fetched_event(request.url, data.len(), fetch_duration_ms);
+            track!(self.handle_preload(data))?;
+        } else if let Some(id) = self.segment_fetches.remove(&action_id) {
+            self.push_segment_fetched_event(request.url, data.len(), fetch_duration_ms);
+            track!(self.handle_segment(id, data))?;
         }
         Ok(())
     }
 
+    fn push_segment_fetched_event(&mut self, url: Url, bytes: usize, fetch_duration_ms: u32) {
+        self.events.push_back(Event::SegmentFetched {
+            url,
+            bytes,
+            fetch_duration_ms,
+        });
+    }
+
     fn handle_playlist(&mut self, m3u8: &str, fetch_duration_ms: u32) -> Result<()> {
-        let playlist: MediaPlaylist = track!(m3u8.parse())?;
+        let parsed = track!(playlist_tags::parse_media_playlist(m3u8))?;
+        let playlist = &parsed.playlist;
         let media_sequence = playlist.media_sequence_tag().map_or(0, |t| t.seq_num());
+        self.can_skip_segments = parsed.server_control.can_skip_until.is_some();
+
+        // The state at the first segment listed in the playlist
+        let first_seq = media_sequence + parsed.skipped_segments;
+        let state = if parsed.skipped_segments > 0 {
+            // Playlist Delta Update
+            let state = self.playlist_states.get(&(first_seq - 1)).cloned();
+            track_assert_some!(
+                state,
+                ErrorKind::InvalidInput,
+                "Unknown skipped segment: {}",
+                first_seq - 1
+            )
+        } else {
+            // The preceding segment may have left the live window since the last load,
+            // yet the first segment listed can still continue its sub-range
+            let prev_range = first_seq
+                .checked_sub(1)
+                .and_then(|seq| self.playlist_states.get(&seq))
+                .and_then(|state| state.prev_range.clone());
+            PlaylistState {
+                key: None,
+                map: None,
+                discontinuity_seq: parsed.discontinuity_seq,
+                prev_range,
+            }
+        };
+        let PlaylistState {
+            mut key,
+            mut map,
+            mut discontinuity_seq,
+            mut prev_range,
+        } = state;
+
         while self.segment_queue
             .front()
-            .map_or(false, |x| x.0 < media_sequence)
+            .is_some_and(|x| !x.ongoing && x.seq < media_sequence)
         {
             self.segment_queue.pop_front();
         }
 
+        // When the playlist is loaded for the first time, playback may start in the middle of it
+        let start = if self.last_media_sequence.is_none() && self.segment_positions.is_empty() {
+            self.start_sequence(&parsed, first_seq)
+        } else {
+            None
+        };
+        let start_seq = start.map(|x| x.0);
+        if let Some((_, position)) = start {
+            let is_precise = self.config.start_position.is_none()
+                && playlist.start_tag().is_some_and(|t| t.precise());
+            if is_precise {
+                self.start_position = Some(self.next_segment_start + position);
+            }
+        }
+
         let mut is_updated = false;
         let mut polling_interval = playlist.target_duration_tag().duration();
         for (i, segment) in playlist.segments().iter().enumerate() {
-            let seq = media_sequence + i as u64;
-            if seq <= self.last_media_sequence {
+            if let Some(tag) = segment.key_tags().last() {
+                key = tag.key().cloned();
+            }
+            if let Some(tag) = segment.map_tag() {
+                map = Some(tag.clone());
+            }
+            if segment.discontinuity_tag().is_some() {
+                discontinuity_seq += 1;
+            }
+
+            let segment_url = track!(self.parse_segment_url(segment.uri()))?;
+            let segment_range = if let Some((length, offset)) = parsed.byte_ranges[i] {
+                let offset = if let Some(offset) = offset {
+                    offset
+                } else {
+                    // The sub-range begins at the next byte following that of the previous segment
+                    let prev_end = prev_range
+                        .as_ref()
+                        .filter(|x| x.0 == segment_url)
+                        .map(|x| x.1.end());
+                    track_assert_some!(
+                        prev_end,
+                        ErrorKind::InvalidInput,
+                        "No previous sub-range of the same resource"
+                    )
+                };
+                let range = ByteRange { offset, length };
+                prev_range = Some((segment_url.clone(), range));
+                Some(range)
+            } else {
+                prev_range = None;
+                None
+            };
+
+            let seq = first_seq + i as u64;
+            self.playlist_states.insert(
+                seq,
+                PlaylistState {
+                    key: key.clone(),
+                    map: map.clone(),
+                    discontinuity_seq,
+                    prev_range: prev_range.clone(),
+                },
+            );
+            if self.last_media_sequence.is_some_and(|last| seq <= last) {
                 continue;
             }
             is_updated = true;
 
-            self.last_media_sequence = seq;
+            self.last_media_sequence = Some(seq);
             self.segments_total += 1;
             self.segment_durations_total += segment.inf_tag().duration();
+            polling_interval = cmp::min(polling_interval, segment.inf_tag().duration());
 
-            let segment_url = track!(self.parse_segment_url(segment.uri()))?;
-            let ongoing = if self.segment_queue.is_empty() {
-                self.action_queue
-                    .push_back(self.action_factory.fetch_data(segment_url.clone()));
-                true
-            } else {
-                false
+            if start_seq.is_some_and(|start| seq < start) {
+                // Precedes the start position, so it is only placed on the timeline
+                let position = SegmentPosition {
+                    start: self.next_segment_start,
+                    duration: duration_secs(segment.inf_tag().duration()),
+                };
+                self.next_segment_start += position.duration;
+                self.segment_positions.insert(seq, position);
+                if let Some(tag) = segment.program_date_time_tag() {
+                    let date_time = track!(date_time::parse(tag.date_time()))?;
+                    self.date_time_mapping.insert(position.start, date_time);
+                }
+                continue;
+            }
+            if start_seq == Some(seq) && seq != first_seq {
+                self.timestamp_offset = Some(self.next_segment_start);
+            }
+
+            let context = track!(self.segment_context(seq, discontinuity_seq, &key, &map))?;
+            if self.last_queued_part.is_some_and(|p| p.0 == seq) {
+                // The segment has been partially queued, so the rest of its parts are queued
+                track!(self.queue_parts(&parsed, i, seq, &context, None))?;
+                self.last_queued_part = None;
+                continue;
+            }
+            self.last_queued_part = None;
+
+            let position = SegmentPosition {
+                start: self.next_segment_start,
+                duration: duration_secs(segment.inf_tag().duration()),
             };
-            self.segment_queue.push_back((seq, ongoing, segment_url));
-            polling_interval = cmp::min(polling_interval, segment.inf_tag().duration());
+            self.next_segment_start += position.duration;
+            self.segment_positions.insert(seq, position);
+            if let Some(tag) = segment.program_date_time_tag() {
+                let date_time = track!(date_time::parse(tag.date_time()))?;
+                self.date_time_mapping.insert(position.start, date_time);
+            }
+            let ad_events = segment_ad_events(&parsed, i, position.start);
+            self.segment_queue.push_back(Segment {
+                seq,
+                url: segment_url,
+                range: segment_range,
+                discontinuity_seq: context.discontinuity_seq,
+                key: context.key,
+                init_section: context.init_section,
+                position,
+                ad_events,
+                part_index: None,
+                ongoing: false,
+                data: None,
+            });
+        }
+
+        self.playlist_states = self.playlist_states.split_off(&media_sequence);
+        self.segment_positions = self.segment_positions.split_off(&media_sequence);
+        self.prune_init_sections();
+        self.prune_keys();
+
+        // The partial segments of the segment being produced (Low-Latency HLS)
+        let next_index = playlist.segments().len();
+        let next_seq = first_seq + next_index as u64;
+        let is_caught_up = self.last_media_sequence
+            .map_or(next_seq == media_sequence, |last| last + 1 == next_seq);
+        if is_caught_up && parsed.parts.iter().any(|p| p.segment_index == next_index) {
+            let tags = &parsed.next_segment_tags;
+            if let Some(ref tag) = tags.key {
+                key = tag.key().cloned();
+            }
+            if let Some(ref tag) = tags.map {
+                map = Some(tag.clone());
+            }
+            if tags.discontinuity {
+                discontinuity_seq += 1;
+            }
+            let context = track!(self.segment_context(next_seq, discontinuity_seq, &key, &map))?;
+            let program_date_time = tags.program_date_time.as_ref().map(|x| &x[..]);
+            let is_queued = track!(self.queue_parts(
+                &parsed,
+                next_index,
+                next_seq,
+                &context,
+                program_date_time
+            ))?;
+            is_updated |= is_queued;
+        }
+        self.events.push_back(Event::PlaylistLoaded {
+            url: self.media_playlist_url.clone(),
+            media_sequence,
+            segments: parsed.skipped_segments as usize + playlist.segments().len(),
+            is_updated,
+            is_ended: is_ended_playlist(playlist),
+        });
+        track!(self.fetch_next_segments())?;
+
+        // `EVENT` playlists may still be appended, so they are reloaded until `#EXT-X-ENDLIST`
+        if is_ended_playlist(playlist) {
+            self.is_end_of_playlist = true;
+            return Ok(());
+        }
+
+        if parsed.part_target.is_some() {
+            track!(self.preload_hinted_part(&parsed.preload_hints))?;
+        }
+        if parsed.server_control.can_block_reload && is_updated {
+            // The server holds the request until the next segment (or part) is available
+            let next_part = parsed.part_target.map(|_| {
+                parsed
+                    .parts
+                    .iter()
+                    .filter(|p| p.segment_index == next_index)
+                    .count()
+            });
+            let url = self.playlist_reload_url(Some((next_seq, next_part)));
+            self.fetch_playlist_action_id = self.push_fetch(url, None);
+            return Ok(());
+        }
+
+        if let Some(part_target) = parsed.part_target {
+            if let Ok(part_target) = Duration::try_from_secs_f64(part_target) {
+                polling_interval = cmp::min(polling_interval, part_target);
+            }
         }
         if self.segments_total > 0 {
             let average_segment_duration = self.segment_durations_total / self.segments_total;
@@ -141,39 +926,735 @@ impl MediaPlaylistHandler {
             polling_interval /= 2;
         }
 
-        self.action_queue
-            .push_back(self.action_factory.set_timeout(polling_interval));
+        let action = self.action_factory.set_timeout(polling_interval);
+        self.reload_timeout_action_id = Some(action.id());
+        self.action_queue.push_back(action);
         Ok(())
     }
 
-    fn handle_segment(&mut self, ts_segment: &[u8]) -> Result<()> {
-        if let Some(x) = self.segment_queue.pop_front() {
-            if !x.1 {
-                self.segment_queue.push_front(x);
+    /// Returns the URL to reload the playlist.
+    ///
+    /// If `next` is specified, the reload is blocked until the segment (and the partial segment)
+    /// is available. If the server supports Playlist Delta Updates, a delta update is requested.
+    fn playlist_reload_url(&self, next: Option<(SequenceNumber, Option<usize>)>) -> Url {
+        let mut url = self.media_playlist_url.clone();
+        if next.is_none() && !self.can_skip_segments {
+            return url;
+        }
+        {
+            let mut query = url.query_pairs_mut();
+            if let Some((msn, part)) = next {
+                query.append_pair("_HLS_msn", &msn.to_string());
+                if let Some(part) = part {
+                    query.append_pair("_HLS_part", &part.to_string());
+                }
+            }
+            if self.can_skip_segments {
+                query.append_pair("_HLS_skip", "YES");
             }
         }
-        if let Some((_, _, url)) = self.segment_queue.pop_front() {
-            self.action_queue
-                .push_back(self.action_factory.fetch_data(url));
+        url
+    }
+
+    /// Returns the sequence number of the segment from which playback starts,
+    /// and the start position relative to the first segment in the playlist.
+    ///
+    /// This is the segment containing `HlsPlayerConfig::start_position` or the `EXT-X-START`
+    /// offset, or the one `HlsPlayerConfig::live_start_target_durations` from the end
+    /// for live playlists.
+    fn start_sequence(
+        &self,
+        parsed: &ParsedMediaPlaylist,
+        first_seq: SequenceNumber,
+    ) -> Option<(SequenceNumber, f64)> {
+        let playlist = &parsed.playlist;
+        let durations = playlist
+            .segments()
+            .iter()
+            .map(|s| duration_secs(s.inf_tag().duration()))
+            .collect::<Vec<_>>();
+        let total = durations.iter().sum::<f64>();
+        let offset = self.config
+            .start_position
+            .or_else(|| playlist.start_tag().map(|t| t.time_offset().as_f64()));
+        let start = if let Some(offset) = offset {
+            // Negative offsets are relative to the end of the playlist
+            if offset < 0.0 {
+                total + offset
+            } else {
+                offset
+            }
+        } else if !is_ended_playlist(playlist) {
+            let target_duration = duration_secs(playlist.target_duration_tag().duration());
+            let distance = f64::from(self.config.live_start_target_durations) * target_duration;
+            let hold_back = parsed.server_control.hold_back.unwrap_or(0.0);
+            total - distance.max(hold_back)
+        } else {
+            return None;
+        };
+
+        let start = start.max(0.0).min(total);
+
+        let mut end = 0.0;
+        for (i, duration) in durations.iter().enumerate() {
+            end += duration;
+            if start < end {
+                return Some((first_seq + i as u64, start));
+            }
+        }
+        durations
+            .len()
+            .checked_sub(1)
+            .map(|i| (first_seq + i as u64, start))
+    }
+
+    fn segment_context(
+        &mut self,
+        seq: SequenceNumber,
+        discontinuity_seq: SequenceNumber,
+        key: &Option<DecryptionKey>,
+        map: &Option<ExtXMap>,
+    ) -> Result<SegmentContext> {
+        let map_iv = key.as_ref().and_then(|k| k.iv).map(|iv| iv.0);
+        let key = if let Some(ref key) = *key {
+            Some(track!(self.segment_key(key, seq))?)
+        } else {
+            None
+        };
+        let init_section = if let Some(ref map) = *map {
+            // The section is encrypted with the AES-128 key in effect (if any)
+            let init_key = match key {
+                Some(ref k) if k.method == EncryptionMethod::Aes128 => {
+                    // `IV` is required for encrypted initialization sections,
+                    // which have no sequence numbers to derive it from
+                    let iv = track_assert_some!(
+                        map_iv,
+                        ErrorKind::Unsupported,
+                        "Encrypted EXT-X-MAP without IV"
+                    );
+                    Some((k.url.clone(), iv))
+                }
+                _ => None,
+            };
+            Some(track!(self.init_section(map, init_key))?)
+        } else {
+            None
+        };
+        Ok(SegmentContext {
+            discontinuity_seq,
+            key,
+            init_section,
+        })
+    }
+
+    /// Queues the partial segments of the `segment_index`-th segment that are not queued yet,
+    /// and returns `true` if any.
+    ///
+    /// Partial segments with `GAP=YES` are skipped.
+    fn queue_parts(
+        &mut self,
+        parsed: &ParsedMediaPlaylist,
+        segment_index: usize,
+        seq: SequenceNumber,
+        context: &SegmentContext,
+        program_date_time: Option<&str>,
+    ) -> Result<bool> {
+        let first = self.last_queued_part
+            .filter(|p| p.0 == seq)
+            .map_or(0, |p| p.1 + 1);
+        let parts = parsed
+            .parts
+            .iter()
+            .filter(|p| p.segment_index == segment_index);
+        let mut prev_range: Option<(Url, ByteRange)> = None;
+        let mut is_queued = false;
+        for (part_index, part) in parts.enumerate() {
+            let url = track!(self.parse_segment_url(&part.uri))?;
+            let range = if let Some((length, offset)) = part.byte_range {
+                let offset = if let Some(offset) = offset {
+                    offset
+                } else {
+                    let prev_end = prev_range
+                        .as_ref()
+                        .filter(|x| x.0 == url)
+                        .map(|x| x.1.end());
+                    track_assert_some!(
+                        prev_end,
+                        ErrorKind::InvalidInput,
+                        "No previous sub-range of the same resource"
+                    )
+                };
+                let range = ByteRange { offset, length };
+                prev_range = Some((url.clone(), range));
+                Some(range)
+            } else {
+                prev_range = None;
+                None
+            };
+            if part_index < first {
+                continue;
+            }
+
+            let position = SegmentPosition {
+                start: self.next_segment_start,
+                duration: part.duration,
+            };
+            self.next_segment_start += part.duration;
+            self.last_queued_part = Some((seq, part_index));
+            let segment_position = self.segment_positions.entry(seq).or_insert(SegmentPosition {
+                start: position.start,
+                duration: 0.0,
+            });
+            segment_position.duration = self.next_segment_start - segment_position.start;
+            let ad_events = if part_index == 0 {
+                if let Some(program_date_time) = program_date_time {
+                    let date_time = track!(date_time::parse(program_date_time))?;
+                    self.date_time_mapping.insert(position.start, date_time);
+                }
+                segment_ad_events(parsed, segment_index, position.start)
+            } else {
+                Vec::new()
+            };
+            if part.gap {
+                continue;
+            }
+            self.segment_queue.push_back(Segment {
+                seq,
+                url,
+                range,
+                discontinuity_seq: context.discontinuity_seq,
+                key: context.key.clone(),
+                init_section: context.init_section.clone(),
+                position,
+                ad_events,
+                part_index: Some(part_index),
+                ongoing: false,
+                data: None,
+            });
+            is_queued = true;
+        }
+        Ok(is_queued)
+    }
+
+    /// Starts fetching the partial segment given by `EXT-X-PRELOAD-HINT` in advance.
+    fn preload_hinted_part(&mut self, hints: &[PreloadHint]) -> Result<()> {
+        if self.preload.as_ref().is_some_and(|p| p.data.is_none()) {
+            // Waits for the response to the previous hint
+            return Ok(());
+        }
+        let hint = if let Some(hint) = hints.iter().find(|h| h.hint_type == "PART") {
+            hint
+        } else {
+            return Ok(());
+        };
+        let range = match hint.byte_range_length {
+            Some(length) => Some(ByteRange {
+                offset: hint.byte_range_start,
+                length,
+            }),
+            None if hint.byte_range_start == 0 => None,
+            None => return Ok(()),
+        };
+        let url = track!(self.parse_segment_url(&hint.uri))?;
+        let is_requested = self.preload
+            .as_ref()
+            .is_some_and(|p| p.url == url && p.range == range)
+            || self.segment_queue
+                .iter()
+                .any(|s| s.url == url && s.range == range);
+        if is_requested {
+            return Ok(());
         }
 
-        let fmp4_segments = track!(mpeg2_ts::to_fmp4(TsPacketReader::new(ts_segment)))?;
+        let action_id = self.push_fetch(url.clone(), range);
+        self.preload = Some(Preload {
+            action_id,
+            url,
+            range,
+            data: None,
+            claimed_by: None,
+        });
+        Ok(())
+    }
+
+    /// Starts fetching the queued segments in order,
+    /// keeping up to `HlsPlayerConfig::max_segment_fetches` segments in flight.
+    ///
+    /// Fetched segments waiting for the preceding ones also count as in flight,
+    /// so a stalled fetch does not make the following segments pile up.
+    /// If a segment has been preloaded, the preloaded data is used instead.
+    fn fetch_next_segments(&mut self) -> Result<()> {
+        let max_fetches = cmp::max(self.config.max_segment_fetches, 1);
+        loop {
+            let in_flight = self.segment_queue.iter().filter(|x| x.ongoing).count();
+            if in_flight >= max_fetches {
+                return Ok(());
+            }
+            if self.buffer_level + self.forward_buffer() >= self.config.max_forward_buffer_secs {
+                // Resumed by `set_buffer_level()`
+                return Ok(());
+            }
+            let (id, url, range) = match self.segment_queue.iter_mut().find(|x| !x.ongoing) {
+                Some(x) => {
+                    x.ongoing = true;
+                    (x.id(), x.url.clone(), x.range)
+                }
+                None => return Ok(()),
+            };
+            if let Some(mut preload) = self.preload
+                .take_if(|p| p.url == url && p.range == range)
+            {
+                if let Some(data) = preload.data.take() {
+                    return track!(self.handle_segment(id, &data));
+                }
+                preload.claimed_by = Some(id);
+                self.preload = Some(preload);
+                continue;
+            }
+            let action_id = self.push_fetch(url, range);
+            self.segment_fetches.insert(action_id, id);
+        }
+    }
 
-        if !self.is_initialized {
+    /// Queues a `FetchData` action, and returns its identifier.
+    fn push_fetch(&mut self, url: Url, range: Option<ByteRange>) -> ActionId {
+        let action = self.action_factory.fetch_data_range(url.clone(), range);
+        let action_id = action.id();
+        self.fetch_requests.insert(
+            action_id,
+            FetchRequest {
+                url,
+                range,
+                failures: 0,
+            },
+        );
+        self.action_queue.push_back(action);
+        action_id
+    }
+
+    /// Returns `true` if the response to `action_id` is still awaited.
+    fn is_active_fetch(&self, action_id: ActionId) -> bool {
+        action_id == self.fetch_playlist_action_id || self.key_fetches.contains_key(&action_id)
+            || self.init_section_fetches.contains_key(&action_id)
+            || self.preload.as_ref().is_some_and(|p| p.action_id == action_id)
+            || self.segment_fetches.contains_key(&action_id)
+    }
+
+    /// Fetches the resource of the failed `failed_action_id` again with a new action.
+    fn retry_fetch(&mut self, failed_action_id: ActionId, request: FetchRequest) {
+        if !self.is_active_fetch(failed_action_id) {
+            // Discarded by a seek
+            return;
+        }
+        let action_id = self.push_fetch(request.url.clone(), request.range);
+        self.fetch_requests.insert(action_id, request);
+        if self.fetch_playlist_action_id == failed_action_id {
+            self.fetch_playlist_action_id = action_id;
+        }
+        if let Some(url) = self.key_fetches.remove(&failed_action_id) {
+            self.key_fetches.insert(action_id, url);
+        }
+        if let Some(init_section) = self.init_section_fetches.remove(&failed_action_id) {
+            self.init_section_fetches.insert(action_id, init_section);
+        }
+        if let Some(id) = self.segment_fetches.remove(&failed_action_id) {
+            self.segment_fetches.insert(action_id, id);
+        }
+    }
+
+    fn handle_preload(&mut self, data: &[u8]) -> Result<()> {
+        let mut preload = self.preload.take().expect("Never fails");
+        if let Some(id) = preload.claimed_by {
+            track!(self.handle_segment(id, data))
+        } else {
+            preload.data = Some(data.to_owned());
+            self.preload = Some(preload);
+            Ok(())
+        }
+    }
+
+    fn segment_key(&mut self, key: &DecryptionKey, seq: SequenceNumber) -> Result<SegmentKey> {
+        if let Some(ref key_format) = key.key_format {
+            track_assert_eq!(
+                &key_format[..],
+                "identity",
+                ErrorKind::Unsupported,
+                "Unsupported key format"
+            );
+        }
+
+        let url = track!(self.parse_segment_url(&key.uri))?;
+        let iv = key.iv.map_or_else(|| crypto::iv_from_sequence_number(seq), |iv| iv.0);
+        if !self.keys.contains_key(&url) && !self.key_fetches.values().any(|u| *u == url) {
+            let action_id = self.push_fetch(url.clone(), None);
+            self.key_fetches.insert(action_id, url.clone());
+        }
+        Ok(SegmentKey {
+            method: key.method,
+            url,
+            iv,
+        })
+    }
+
+    fn init_section(&mut self, map: &ExtXMap, key: Option<(Url, Iv)>) -> Result<InitSection> {
+        let init_section = InitSection {
+            url: track!(self.parse_segment_url(map.uri()))?,
+            range: map.range().map(|r| ByteRange {
+                offset: r.start.unwrap_or(0) as u64,
+                length: r.length as u64,
+            }),
+            key,
+        };
+        if !self.init_sections.contains_key(&init_section)
+            && !self.init_section_fetches.values().any(|x| *x == init_section)
+        {
+            let action_id = self.push_fetch(init_section.url.clone(), init_section.range);
+            self.init_section_fetches
+                .insert(action_id, init_section.clone());
+        }
+        Ok(init_section)
+    }
+
+    /// Drops the initialization sections that no segments to be processed refer to
+    /// (e.g., those of the segments that have left the window of a live playlist).
+    fn prune_init_sections(&mut self) {
+        let in_use = self.segment_queue
+            .iter()
+            .filter_map(|s| s.init_section.as_ref())
+            .chain(self.pending_segments.iter().filter_map(|s| s.init_section.as_ref()))
+            .chain(self.current_init_section.iter())
+            .cloned()
+            .collect::<HashSet<_>>();
+        self.init_sections.retain(|x, _| in_use.contains(x));
+    }
+
+    /// Drops the keys used by neither the segments in the live window nor the pending ones.
+    fn prune_keys(&mut self) {
+        let mut in_use = self.playlist_states
+            .values()
+            .filter_map(|s| s.key.as_ref())
+            .filter_map(|k| self.parse_segment_url(&k.uri).ok())
+            .collect::<HashSet<_>>();
+        in_use.extend(
+            self.segment_queue
+                .iter()
+                .filter_map(|s| s.key.as_ref())
+                .chain(self.pending_segments.iter().filter_map(|s| s.key.as_ref()))
+                .map(|k| k.url.clone()),
+        );
+        in_use.extend(
+            self.segment_queue
+                .iter()
+                .filter_map(|s| s.init_section.as_ref())
+                .chain(self.pending_segments.iter().filter_map(|s| s.init_section.as_ref()))
+                .filter_map(|x| x.key.as_ref())
+                .map(|k| k.0.clone()),
+        );
+        self.keys.retain(|url, _| in_use.contains(url));
+    }
+
+    fn handle_key(&mut self, key_url: Url, data: &[u8]) -> Result<()> {
+        let key = track!(crypto::key_from_bytes(data))?;
+        self.keys.insert(key_url, key);
+        track!(self.handle_pending_segments())
+    }
+
+    /// Stores the fetched data of the segment `id`, and processes the segments at the head of
+    /// the queue whose data are available (i.e., the segments are processed in sequence order).
+    fn handle_segment(&mut self, id: SegmentId, data: &[u8]) -> Result<()> {
+        {
+            let segment = self.segment_queue
+                .iter_mut()
+                .find(|x| x.ongoing && x.id() == id);
+            let segment =
+                track_assert_some!(segment, ErrorKind::InvalidInput, "Unknown segment: {:?}", id);
+            segment.data = Some(data.to_owned());
+        }
+
+        while self.segment_queue
+            .front()
+            .is_some_and(|x| x.data.is_some())
+        {
+            let segment = self.segment_queue.pop_front().expect("Never fails");
+            if let Some(part_index) = segment.part_index {
+                self.last_fetched_sequence = segment.seq.checked_sub(1);
+                self.last_fetched_part = Some((segment.seq, part_index));
+            } else {
+                self.last_fetched_sequence = Some(segment.seq);
+                self.last_fetched_part = None;
+            }
+            self.last_fetched_end = segment.position.start + segment.position.duration;
+            self.pending_segments.push_back(FetchedSegment {
+                discontinuity_seq: segment.discontinuity_seq,
+                key: segment.key,
+                init_section: segment.init_section,
+                start: segment.position.start,
+                duration: segment.position.duration,
+                ad_events: segment.ad_events,
+                data: segment.data.expect("Never fails"),
+            });
+        }
+        track!(self.handle_pending_segments())?;
+        track!(self.fetch_next_segments())
+    }
+
+    /// Processes the fetched segments in order,
+    /// as long as their decryption keys and initialization sections are available.
+    fn handle_pending_segments(&mut self) -> Result<()> {
+        while let Some(segment) = self.pending_segments.pop_front() {
+            let is_ready = segment
+                .key
+                .as_ref()
+                .is_none_or(|k| self.keys.contains_key(&k.url))
+                && segment.init_section.as_ref().is_none_or(|x| {
+                    self.init_sections.contains_key(x)
+                        && x.key.as_ref().is_none_or(|k| self.keys.contains_key(&k.0))
+                });
+            if !is_ready {
+                self.pending_segments.push_front(segment);
+                break;
+            }
+
+            if self.last_discontinuity_seq
+                .is_some_and(|x| x != segment.discontinuity_seq)
+            {
+                self.is_discontinuity = true;
+            }
+            self.last_discontinuity_seq = Some(segment.discontinuity_seq);
+            self.ad_events.extend(segment.ad_events);
+
+            let mut data = segment.data;
+            let mut sample_aes_key = None;
+            if let Some(ref key) = segment.key {
+                let k = self.keys[&key.url];
+                match key.method {
+                    EncryptionMethod::Aes128 => {
+                        data = track!(crypto::decrypt_aes128_cbc(&k, &key.iv, &data))?;
+                    }
+                    EncryptionMethod::SampleAes => {
+                        track_assert!(
+                            segment.init_section.is_none(),
+                            ErrorKind::Unsupported,
+                            "SAMPLE-AES encrypted fMP4 segments are not supported"
+                        );
+                        sample_aes_key = Some((k, key.iv));
+                    }
+                }
+            }
+
+            if let Some(init_section) = segment.init_section {
+                track!(self.push_fmp4_segment(init_section, data, segment.duration))?;
+            } else if webvtt::is_webvtt(&data) {
+                // Subtitle segments are not appended to `SourceBuffer`s, so no remuxing is needed
+                self.push_buffered_segment(data, segment.duration);
+            } else {
+                let position = SegmentPosition {
+                    start: segment.start,
+                    duration: segment.duration,
+                };
+                track!(self.remux_segment(&data, sample_aes_key, position))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Passes an fMP4 media segment through, preceded by its initialization section if changed.
+    fn push_fmp4_segment(
+        &mut self,
+        init_section: InitSection,
+        media_segment: Vec<u8>,
+        duration: f64,
+    ) -> Result<()> {
+        self.first_timestamp.get_or_insert(0);
+        self.last_track_configuration = None;
+        if self.current_init_section.as_ref() != Some(&init_section) {
+            let mut data = self.init_sections[&init_section].clone();
+            if let Some((ref key_url, ref iv)) = init_section.key {
+                data = track!(crypto::decrypt_aes128_cbc(&self.keys[key_url], iv, &data))?;
+            }
+            let codecs = codecs::from_init_section(&data).or_else(|| self.declared_codecs.clone());
+            let codecs = track_assert_some!(
+                codecs,
+                ErrorKind::InvalidInput,
+                "Unknown codecs of the initialization section: {}",
+                init_section.url
+            );
+            self.push_initialization_segment(data, codecs);
+            self.current_init_section = Some(init_section);
+        }
+        self.push_buffered_segment(media_segment, duration);
+        Ok(())
+    }
+
+    /// Remuxes a TS or packed audio segment into fMP4.
+    ///
+    /// An initialization segment is emitted before the first media segment,
+    /// and again whenever the track configuration changes (e.g., at a discontinuity).
+    fn remux_segment(
+        &mut self,
+        data: &[u8],
+        sample_aes_key: Option<(Key, Iv)>,
+        position: SegmentPosition,
+    ) -> Result<()> {
+        let fmp4_segments = if data.first() == Some(&ts::SYNC_BYTE) {
+            let mut ts_segment = track!(TsSegment::demux(data))?;
+            if let Some((key, iv)) = sample_aes_key {
+                track!(sample_aes::decrypt_ts_segment(&key, &iv, &mut ts_segment))?;
+            }
+            track!(self.decode_captions(&ts_segment))?;
+            track!(self.extract_timed_metadata(&ts_segment))?;
+            track!(self.extract_splice_signals(&ts_segment, position.start))?;
+            if self.first_timestamp.is_none() {
+                self.first_timestamp = ts_segment
+                    .pes_packets
+                    .iter()
+                    .filter_map(|p| p.pts().ok().and_then(|x| x))
+                    .min();
+            }
+            track!(remux::ts_to_fmp4(&ts_segment))?
+        } else {
+            track_assert!(
+                sample_aes_key.is_none(),
+                ErrorKind::Unsupported,
+                "SAMPLE-AES encrypted packed audio is not supported"
+            );
+            self.first_timestamp.get_or_insert(0);
+            track!(remux::packed_audio_to_fmp4(data))?
+        };
+
+        self.current_init_section = None;
+        let track_configuration = track!(track_configuration(&fmp4_segments.0))?;
+        if self.last_track_configuration.as_ref() != Some(&track_configuration) {
             let mut initialization_segment = Vec::new();
             track!(fmp4_segments.0.write_to(&mut initialization_segment))?;
-            self.buffered_segments.push_back(initialization_segment);
+            let codecs = codecs::from_initialization_segment(&fmp4_segments.0);
+            self.push_initialization_segment(initialization_segment, codecs);
+            self.last_track_configuration = Some(track_configuration);
+        }
 
-            self.is_initialized = true;
+        self.push_buffered_segment(fmp4_segments.1, position.duration);
+
+        Ok(())
+    }
+
+    /// Feeds the CEA-608 captions in the SEI NAL units of `ts_segment` to the decoder.
+    fn decode_captions(&mut self, ts_segment: &TsSegment) -> Result<()> {
+        let mut cc_data = Vec::new();
+        for pes in &ts_segment.pes_packets {
+            if ts_segment.stream_type(pes.pid) != Some(ts::STREAM_TYPE_H264) {
+                continue;
+            }
+            let pts = if let Some(pts) = track!(pes.pts())? {
+                pts
+            } else {
+                continue;
+            };
+            for nal_unit in h264::nal_units(track!(pes.payload())?) {
+                if h264::nal_unit_type(nal_unit) == h264::NAL_UNIT_TYPE_SEI {
+                    cc_data.extend(cea608::extract_cc_data(nal_unit).into_iter().map(|x| (pts, x)));
+                }
+            }
         }
 
-        let mut media_segment = Vec::new();
-        track!(fmp4_segments.1.write_to(&mut media_segment))?;
-        self.buffered_segments.push_back(media_segment);
+        // Captions are encoded in decoding order, but must be decoded in presentation order
+        cc_data.sort_by_key(|x| x.0);
+        for (pts, (cc_data_1, cc_data_2)) in cc_data {
+            let time = ts::timestamp_to_seconds(pts);
+            self.caption_decoder.handle_cc_data(time, cc_data_1, cc_data_2);
+        }
+        Ok(())
+    }
 
+    /// Extracts the ID3 tags carried by the metadata streams of `ts_segment`.
+    ///
+    /// Malformed tags are ignored, since they do not affect the playback.
+    fn extract_timed_metadata(&mut self, ts_segment: &TsSegment) -> Result<()> {
+        for pes in &ts_segment.pes_packets {
+            if ts_segment.stream_type(pes.pid) != Some(ts::STREAM_TYPE_METADATA) {
+                continue;
+            }
+            let pts = if let Some(pts) = track!(pes.pts())? {
+                pts
+            } else {
+                continue;
+            };
+            match id3::parse_tags(track!(pes.payload())?) {
+                Ok(ref frames) if frames.is_empty() => {}
+                Ok(frames) => self.timed_metadata.push(TimedMetadata {
+                    time: ts::timestamp_to_seconds(pts),
+                    frames,
+                }),
+                Err(_) => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Extracts the SCTE-35 break signals carried by `ts_segment` starting at `start`.
+    ///
+    /// Malformed sections are ignored, since they do not affect the playback.
+    fn extract_splice_signals(&mut self, ts_segment: &TsSegment, start: f64) -> Result<()> {
+        let origin = ts_segment
+            .pes_packets
+            .iter()
+            .filter(|p| ts_segment.stream_type(p.pid) != Some(scte35::STREAM_TYPE_SCTE35))
+            .filter_map(|p| p.pts().ok().and_then(|x| x))
+            .min();
+        for packet in &ts_segment.pes_packets {
+            if ts_segment.stream_type(packet.pid) != Some(scte35::STREAM_TYPE_SCTE35) {
+                continue;
+            }
+
+            // SCTE-35 streams carry sections (preceded by a `pointer_field`), not PES packets
+            let data = &packet.bytes;
+            let section = match data.first() {
+                Some(&pointer) if data.len() > 1 + pointer as usize => {
+                    &data[1 + pointer as usize..]
+                }
+                _ => continue,
+            };
+            let signals = if let Ok(signals) = scte35::parse_splice_info_section(section) {
+                signals
+            } else {
+                continue;
+            };
+            for signal in signals {
+                let time = match (signal.pts, origin) {
+                    (Some(pts), Some(origin)) => {
+                        start + ts::relative_time(ts::timestamp_to_seconds(pts), origin)
+                    }
+                    _ => start,
+                };
+                let event = AdEvent::from_splice_signal(&signal, time, AdMarkerSource::Scte35);
+                self.ad_events.push(event);
+            }
+        }
         Ok(())
     }
 
+    fn push_initialization_segment(&mut self, data: Vec<u8>, codecs: String) {
+        self.push_buffered_segment(data, 0.0);
+        if let Some(segment) = self.buffered_segments.back_mut() {
+            segment.codecs = Some(codecs.clone());
+        }
+        self.codecs = Some(codecs);
+    }
+
+    fn push_buffered_segment(&mut self, data: Vec<u8>, duration: f64) {
+        if duration > 0.0 {
+            self.is_started = true;
+        }
+        self.is_stalled = false;
+        self.buffered_segments.push_back(BufferedSegment {
+            data,
+            is_discontinuity: self.is_discontinuity,
+            timestamp_offset: self.timestamp_offset.take(),
+            duration,
+            codecs: None,
+        });
+        self.is_discontinuity = false;
+    }
+
     fn parse_segment_url(&self, segment_url: &str) -> Result<Url> {
         track!(
             Url::options()
@@ -183,3 +1664,733 @@ impl MediaPlaylistHandler {
         )
     }
 }
+
+fn duration_secs(duration: Duration) -> f64 {
+    duration.as_secs() as f64 + f64::from(duration.subsec_nanos()) / 1e9
+}
+
+/// Returns `true` if no segments will be added to `playlist`.
+fn is_ended_playlist(playlist: &MediaPlaylist) -> bool {
+    let is_vod = playlist
+        .playlist_type_tag()
+        .is_some_and(|t| t.playlist_type() == PlaylistType::Vod);
+    playlist.end_list_tag().is_some() || is_vod
+}
+
+fn segment_ad_events(
+    parsed: &ParsedMediaPlaylist,
+    segment_index: usize,
+    position: f64,
+) -> Vec<AdEvent> {
+    parsed
+        .segment_tags
+        .iter()
+        .filter(|t| t.segment_index == segment_index)
+        .flat_map(|t| AdEvent::from_tag(&t.tag, position))
+        .collect()
+}
+
+/// Returns the encoded sample descriptions of the tracks in `initialization_segment`.
+///
+/// Unlike the whole initialization segment, this does not depend on the segment duration.
+fn track_configuration(initialization_segment: &InitializationSegment) -> Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    for trak in &initialization_segment.moov_box.trak_boxes {
+        track!(trak.mdia_box.minf_box.stbl_box.stsd_box.write_box(&mut buf))?;
+    }
+    Ok(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::crypto::tests::encrypt_aes128_cbc;
+    use super::super::remux::tests::{adts_frames, pes, ts_segment, video_pes, AUDIO_PID};
+    use super::super::ts::tests::mux;
+
+    const KEY: Key = [7; 16];
+    const INIT_SECTION: &[u8] = b"\0\0\0\x08moov";
+    const MEDIA_SEGMENT: &[u8] = b"\0\0\0\x08moof";
+
+    fn handler(m3u8: &str) -> MediaPlaylistHandler {
+        handler_with_config(m3u8, Default::default())
+    }
+
+    fn handler_with_config(m3u8: &str, config: HlsPlayerConfig) -> MediaPlaylistHandler {
+        let url = "http://example.com/media.m3u8".parse().unwrap();
+        let mut handler =
+            MediaPlaylistHandler::with_m3u8(ActionFactory::new(0), url, config, m3u8).unwrap();
+
+        // The sample entries of `INIT_SECTION` are unknown
+        handler.set_declared_codecs(Some("avc1.64001f".to_owned()));
+        handler
+    }
+
+    /// Responds to the fetches with `respond`, and returns the id of the reload timer (if any).
+    fn respond_all(
+        handler: &mut MediaPlaylistHandler,
+        respond: &dyn Fn(&str) -> Vec<u8>,
+    ) -> Option<ActionId> {
+        let mut timeout = None;
+        while let Some(action) = handler.next_action() {
+            match action {
+                Action::FetchData { action_id, url, .. } => {
+                    let data = respond(url.path().trim_start_matches('/'));
+                    handler.handle_data(action_id, &data, 10).unwrap();
+                }
+                Action::SetTimeout { action_id, .. } => timeout = Some(action_id),
+            }
+        }
+        timeout
+    }
+
+    /// Takes the queued actions, and returns the ids and paths of the fetches without responding.
+    fn take_fetches(handler: &mut MediaPlaylistHandler) -> Vec<(ActionId, String)> {
+        let mut fetches = Vec::new();
+        while let Some(action) = handler.next_action() {
+            if let Action::FetchData { action_id, url, .. } = action {
+                fetches.push((action_id, url.path().trim_start_matches('/').to_owned()));
+            }
+        }
+        fetches
+    }
+
+    fn take_events(handler: &mut MediaPlaylistHandler) -> Vec<Event> {
+        let mut events = Vec::new();
+        while let Some(event) = handler.next_event() {
+            events.push(event);
+        }
+        events
+    }
+
+    fn take_segments(handler: &mut MediaPlaylistHandler) -> Vec<BufferedSegment> {
+        let mut segments = Vec::new();
+        while let Some(segment) = handler.next_segment() {
+            segments.push(segment);
+        }
+        segments
+    }
+
+    /// Returns an fMP4 playlist of the segments `s{seq}.m4s` starting from `seq`.
+    fn fmp4_playlist(seq: u64, segments: u64, tags: &str) -> String {
+        let mut m3u8 = format!(
+            "#EXTM3U\n#EXT-X-TARGETDURATION:2\n#EXT-X-MEDIA-SEQUENCE:{}\n{}\n",
+            seq, "#EXT-X-MAP:URI=\"init.mp4\""
+        );
+        for i in seq..seq + segments {
+            m3u8 += &format!("#EXTINF:2,\ns{}.m4s\n", i);
+        }
+        m3u8 + tags
+    }
+
+    fn respond_fmp4(path: &str) -> Vec<u8> {
+        if path.starts_with("init") {
+            INIT_SECTION.to_vec()
+        } else {
+            MEDIA_SEGMENT.to_vec()
+        }
+    }
+
+    #[test]
+    fn ended_playlist_is_not_reloaded() {
+        let mut handler = handler(&fmp4_playlist(0, 2, "#EXT-X-ENDLIST\n"));
+        assert_eq!(respond_all(&mut handler, &respond_fmp4), None);
+        assert!(!handler.is_ended());
+        match take_events(&mut handler).last() {
+            Some(&Event::SegmentFetched { .. }) => {}
+            event => panic!("{:?}", event),
+        }
+
+        assert_eq!(take_segments(&mut handler).len(), 3);
+        assert!(handler.is_ended());
+        assert_eq!(take_events(&mut handler), [Event::Ended]);
+        assert_eq!(take_events(&mut handler), []);
+    }
+
+    #[test]
+    fn live_playlist_is_reloaded_until_endlist() {
+        let mut handler = handler(&fmp4_playlist(0, 2, ""));
+        let timeout = respond_all(&mut handler, &respond_fmp4);
+        take_segments(&mut handler);
+        assert!(!handler.is_ended());
+
+        handler.handle_timeout(timeout.unwrap()).unwrap();
+        let timeout = respond_all(&mut handler, &|path| {
+            if path == "media.m3u8" {
+                fmp4_playlist(0, 3, "#EXT-X-ENDLIST\n").into_bytes()
+            } else {
+                respond_fmp4(path)
+            }
+        });
+        assert_eq!(timeout, None);
+        let events = take_events(&mut handler);
+        assert!(events.contains(&Event::PlaylistLoaded {
+            url: "http://example.com/media.m3u8".parse().unwrap(),
+            media_sequence: 0,
+            segments: 3,
+            is_updated: true,
+            is_ended: true,
+        }));
+        assert!(!events.contains(&Event::Ended));
+
+        assert_eq!(take_segments(&mut handler).len(), 1);
+        assert_eq!(take_events(&mut handler), [Event::Ended]);
+    }
+
+    #[test]
+    fn encrypted_init_section_is_decrypted() {
+        let iv = [1; 16];
+        let m3u8 = r#"#EXTM3U
+#EXT-X-TARGETDURATION:2
+#EXT-X-KEY:METHOD=AES-128,URI="key",IV=0x01010101010101010101010101010101
+#EXT-X-MAP:URI="init.mp4"
+#EXTINF:2,
+s0.m4s
+#EXT-X-ENDLIST
+"#;
+        let mut handler = handler(m3u8);
+        respond_all(&mut handler, &|path| match path {
+            "key" => KEY.to_vec(),
+            "init.mp4" => encrypt_aes128_cbc(&KEY, &iv, INIT_SECTION),
+            _ => encrypt_aes128_cbc(&KEY, &iv, MEDIA_SEGMENT),
+        });
+        let segment = handler.next_segment().unwrap();
+        assert_eq!(segment.data, INIT_SECTION);
+        assert!(segment.codecs.is_some());
+        assert_eq!(handler.next_segment().unwrap().data, MEDIA_SEGMENT);
+
+        // Without `IV`, there is no sequence number to derive the IV of the section from
+        let m3u8 = m3u8.replace(",IV=0x01010101010101010101010101010101", "");
+        let url = "http://example.com/media.m3u8".parse().unwrap();
+        let result =
+            MediaPlaylistHandler::with_m3u8(ActionFactory::new(0), url, Default::default(), &m3u8);
+        assert_eq!(result.err().map(|e| *e.kind()), Some(ErrorKind::Unsupported));
+    }
+
+    #[test]
+    fn init_sections_leaving_live_window_are_dropped() {
+        let playlist = |seq: u64| {
+            let mut m3u8 = format!(
+                "#EXTM3U\n#EXT-X-TARGETDURATION:2\n#EXT-X-MEDIA-SEQUENCE:{}\n",
+                seq
+            );
+            for i in seq..seq + 3 {
+                m3u8 += &format!("#EXT-X-MAP:URI=\"init{}.mp4\"\n#EXTINF:2,\ns{}.m4s\n", i, i);
+            }
+            m3u8
+        };
+        let mut handler = handler(&playlist(0));
+        let mut timeout = respond_all(&mut handler, &respond_fmp4);
+        for seq in 1..20 {
+            while handler.next_segment().is_some() {}
+            handler.handle_timeout(timeout.unwrap()).unwrap();
+            let m3u8 = playlist(seq);
+            timeout = respond_all(&mut handler, &|path| {
+                if path == "media.m3u8" {
+                    m3u8.clone().into_bytes()
+                } else {
+                    respond_fmp4(path)
+                }
+            });
+            assert!(handler.init_sections.len() <= 3);
+        }
+        assert_eq!(handler.last_fetched_sequence(), Some(21));
+    }
+
+    #[test]
+    fn keys_leaving_live_window_are_dropped() {
+        let playlist = |seq: u64| {
+            let mut m3u8 = format!(
+                "#EXTM3U\n#EXT-X-TARGETDURATION:2\n#EXT-X-MEDIA-SEQUENCE:{}\n{}\n",
+                seq, "#EXT-X-MAP:URI=\"init.mp4\""
+            );
+            for i in seq..seq + 3 {
+                // Each key is used by two segments
+                m3u8 += &format!(
+                    "#EXT-X-KEY:METHOD=AES-128,URI=\"key{}\",IV=0x{}\n",
+                    i / 2,
+                    "01".repeat(16)
+                );
+                m3u8 += &format!("#EXTINF:2,\ns{}.m4s\n", i);
+            }
+            m3u8
+        };
+        let respond = |path: &str| {
+            if path.starts_with("key") {
+                KEY.to_vec()
+            } else {
+                encrypt_aes128_cbc(&KEY, &[1; 16], &respond_fmp4(path))
+            }
+        };
+
+        let mut handler = handler(&playlist(0));
+        let mut timeout = respond_all(&mut handler, &respond);
+        for seq in 1..20 {
+            while handler.next_segment().is_some() {}
+            handler.handle_timeout(timeout.unwrap()).unwrap();
+            let m3u8 = playlist(seq);
+            let key_fetches = std::cell::Cell::new(0);
+            timeout = respond_all(&mut handler, &|path| {
+                if path == "media.m3u8" {
+                    m3u8.clone().into_bytes()
+                } else {
+                    key_fetches.set(key_fetches.get() + usize::from(path.starts_with("key")));
+                    respond(path)
+                }
+            });
+            assert!(handler.keys.len() <= 2);
+            let last_key = format!("http://example.com/key{}", (seq + 2) / 2);
+            assert!(handler.keys.contains_key(&last_key.parse().unwrap()));
+
+            // Keys still in the live window are not fetched again
+            assert!(key_fetches.get() <= 1);
+        }
+        assert_eq!(handler.last_fetched_sequence(), Some(21));
+    }
+
+    #[test]
+    fn implicit_byte_range_offsets_continue_across_reloads() {
+        let playlist = |seq: u64| {
+            let mut m3u8 = format!(
+                "#EXTM3U\n#EXT-X-TARGETDURATION:2\n#EXT-X-MEDIA-SEQUENCE:{}\n{}\n",
+                seq, "#EXT-X-MAP:URI=\"init.mp4\""
+            );
+            for i in seq..seq + 3 {
+                let offset = if i == 0 { "@0" } else { "" };
+                m3u8 += &format!("#EXT-X-BYTERANGE:100{}\n#EXTINF:2,\nmedia.m4s\n", offset);
+            }
+            m3u8
+        };
+
+        let mut handler = handler(&playlist(0));
+        let mut offsets = Vec::new();
+        for seq in 1..5 {
+            let mut timeout = None;
+            while let Some(action) = handler.next_action() {
+                match action {
+                    Action::FetchData { action_id, url, range } => {
+                        let data = match url.path() {
+                            "/media.m3u8" => playlist(seq).into_bytes(),
+                            "/init.mp4" => INIT_SECTION.to_vec(),
+                            _ => {
+                                offsets.push(range.unwrap().offset);
+                                MEDIA_SEGMENT.to_vec()
+                            }
+                        };
+                        handler.handle_data(action_id, &data, 10).unwrap();
+                    }
+                    Action::SetTimeout { action_id, .. } => timeout = Some(action_id),
+                }
+            }
+            while handler.next_segment().is_some() {}
+            handler.handle_timeout(timeout.unwrap()).unwrap();
+        }
+        assert_eq!(offsets, vec![0, 100, 200, 300, 400, 500, 600]);
+    }
+
+    #[test]
+    fn discontinuities_are_flagged_and_regenerate_init_segments() {
+        let m3u8 = r#"#EXTM3U
+#EXT-X-TARGETDURATION:2
+#EXTINF:2,
+s0.ts
+#EXT-X-DISCONTINUITY
+#EXTINF:2,
+s1.ts
+#EXT-X-DISCONTINUITY
+#EXTINF:2,
+s2.ts
+#EXT-X-ENDLIST
+"#;
+        let mut handler = handler(m3u8);
+        respond_all(&mut handler, &|path| {
+            let audio = pes(AUDIO_PID, Some(0), None, &adts_frames(2, 8));
+            let mut segment = match path {
+                "s0.ts" => ts_segment(vec![video_pes(3000, 0, true), audio]),
+                "s1.ts" => ts_segment(vec![video_pes(90_000, 87_000, true), audio]),
+                _ => ts_segment(vec![audio]),
+            };
+            if path == "s2.ts" {
+                segment.streams.retain(|s| s.pid == AUDIO_PID);
+            }
+            mux(&segment)
+        });
+
+        let segments = take_segments(&mut handler)
+            .into_iter()
+            .map(|s| (s.is_discontinuity, s.codecs))
+            .collect::<Vec<_>>();
+        let codecs = |x: &str| Some(x.to_owned());
+        assert_eq!(
+            segments,
+            [
+                (false, codecs("avc1.4d401f,mp4a.40.2")),
+                (false, None),
+                (true, None),
+                (true, codecs("mp4a.40.2")),
+                (false, None),
+            ]
+        );
+    }
+
+    #[test]
+    fn program_date_times_are_mapped_to_positions() {
+        // 2026-01-01T00:00:00Z
+        const DATE_TIME: f64 = 1_767_225_600_000.0;
+        let playlist = |seq: u64, segments: u64| {
+            let mut m3u8 = format!(
+                "#EXTM3U\n#EXT-X-TARGETDURATION:2\n#EXT-X-MEDIA-SEQUENCE:{}\n{}\n",
+                seq, "#EXT-X-MAP:URI=\"init.mp4\""
+            );
+            for i in seq..seq + segments {
+                match i {
+                    0 => m3u8 += "#EXT-X-PROGRAM-DATE-TIME:2026-01-01T00:00:00Z\n",
+                    2 => {
+                        m3u8 += "#EXT-X-DISCONTINUITY\n";
+                        m3u8 += "#EXT-X-PROGRAM-DATE-TIME:2026-01-01T01:00:00.500Z\n";
+                    }
+                    _ => {}
+                }
+                m3u8 += &format!("#EXTINF:2,\ns{}.m4s\n", i);
+            }
+            m3u8
+        };
+        let mut handler = handler(&playlist(0, 3));
+        let timeout = respond_all(&mut handler, &respond_fmp4);
+
+        let mapping = handler.date_time_mapping();
+        assert_eq!(mapping.position_to_date_time(0.0), Some(DATE_TIME));
+        assert_eq!(mapping.position_to_date_time(3.0), Some(DATE_TIME + 3000.0));
+        assert_eq!(mapping.position_to_date_time(5.0), Some(DATE_TIME + 3_601_500.0));
+        assert_eq!(mapping.date_time_to_position(DATE_TIME + 1500.0), Some(1.5));
+        assert_eq!(mapping.date_time_to_position(DATE_TIME + 3_601_000.0), Some(4.5));
+
+        // Segments added by reloads are placed after the existing ones
+        take_segments(&mut handler);
+        handler.handle_timeout(timeout.unwrap()).unwrap();
+        respond_all(&mut handler, &|path| {
+            if path == "media.m3u8" {
+                playlist(1, 3).into_bytes()
+            } else {
+                respond_fmp4(path)
+            }
+        });
+        let mapping = handler.date_time_mapping();
+        assert_eq!(mapping.position_to_date_time(7.0), Some(DATE_TIME + 3_603_500.0));
+        assert_eq!(mapping.date_time_to_position(DATE_TIME + 3_604_500.0), Some(8.0));
+    }
+
+    #[test]
+    fn delta_updates_are_merged() {
+        let iv = [1; 16];
+        let m3u8 = r#"#EXTM3U
+#EXT-X-TARGETDURATION:2
+#EXT-X-SERVER-CONTROL:CAN-SKIP-UNTIL=12.0
+#EXT-X-KEY:METHOD=AES-128,URI="key",IV=0x01010101010101010101010101010101
+#EXT-X-MAP:URI="init.mp4"
+#EXTINF:2,
+s0.m4s
+#EXTINF:2,
+s1.m4s
+#EXT-X-DISCONTINUITY
+#EXTINF:2,
+s2.m4s
+#EXTINF:2,
+s3.m4s
+#EXTINF:2,
+s4.m4s
+"#;
+        // The key, the map and the discontinuity above are all skipped
+        let delta_update = r#"#EXTM3U
+#EXT-X-TARGETDURATION:2
+#EXT-X-MEDIA-SEQUENCE:1
+#EXT-X-SERVER-CONTROL:CAN-SKIP-UNTIL=12.0
+#EXT-X-SKIP:SKIPPED-SEGMENTS=3
+#EXTINF:2,
+s4.m4s
+#EXTINF:2,
+s5.m4s
+"#;
+        let respond = |path: &str| match path {
+            "key" => KEY.to_vec(),
+            "init.mp4" => encrypt_aes128_cbc(&KEY, &iv, INIT_SECTION),
+            _ => encrypt_aes128_cbc(&KEY, &iv, MEDIA_SEGMENT),
+        };
+        let mut handler = handler(m3u8);
+        let timeout = respond_all(&mut handler, &respond);
+        let segments = take_segments(&mut handler).len();
+        assert!(segments > 1);
+
+        handler.handle_timeout(timeout.unwrap()).unwrap();
+        match handler.next_action() {
+            Some(Action::FetchData { action_id, url, .. }) => {
+                assert_eq!(url.as_str(), "http://example.com/media.m3u8?_HLS_skip=YES");
+                handler
+                    .handle_data(action_id, delta_update.as_bytes(), 10)
+                    .unwrap();
+            }
+            action => panic!("{:?}", action),
+        }
+        respond_all(&mut handler, &respond);
+        assert_eq!(handler.last_fetched_sequence(), Some(5));
+        match &take_segments(&mut handler)[..] {
+            [segment] => {
+                assert_eq!(segment.data, MEDIA_SEGMENT);
+                assert!(!segment.is_discontinuity);
+                assert_eq!(segment.codecs, None);
+            }
+            segments => panic!("{:?}", segments),
+        }
+        assert!(take_events(&mut handler).contains(&Event::PlaylistLoaded {
+            url: "http://example.com/media.m3u8".parse().unwrap(),
+            media_sequence: 1,
+            segments: 5,
+            is_updated: true,
+            is_ended: false,
+        }));
+    }
+
+    #[test]
+    fn seek_within_playlist_cancels_in_flight_fetches() {
+        let mut handler = handler(&fmp4_playlist(0, 5, "#EXT-X-ENDLIST\n"));
+        let fetches = take_fetches(&mut handler);
+        let paths = fetches.iter().map(|f| &f.1[..]).collect::<Vec<_>>();
+        assert_eq!(paths, ["init.mp4", "s0.m4s"]);
+        for (action_id, path) in fetches {
+            handler.handle_data(action_id, &respond_fmp4(&path), 10).unwrap();
+        }
+        let in_flight = take_fetches(&mut handler);
+        assert_eq!(in_flight[0].1, "s1.m4s");
+
+        // The initialization segment not taken yet is emitted again
+        assert_eq!(handler.seek(5.0).unwrap(), 4.0);
+        assert!(handler.next_segment().is_none());
+        let result = handler.handle_data(in_flight[0].0, MEDIA_SEGMENT, 10);
+        assert_eq!(result.err().map(|e| *e.kind()), Some(ErrorKind::StaleResponse));
+
+        // The playlist is reloaded to locate the target segment
+        let fetches = take_fetches(&mut handler);
+        assert_eq!(fetches.len(), 1);
+        assert_eq!(fetches[0].1, "media.m3u8");
+        let m3u8 = fmp4_playlist(0, 5, "#EXT-X-ENDLIST\n");
+        handler.handle_data(fetches[0].0, m3u8.as_bytes(), 10).unwrap();
+        let fetches = take_fetches(&mut handler);
+        assert_eq!(fetches.len(), 1);
+        assert_eq!(fetches[0].1, "s2.m4s");
+        handler.handle_data(fetches[0].0, MEDIA_SEGMENT, 10).unwrap();
+        let segments = take_segments(&mut handler);
+        assert_eq!(segments[0].data, INIT_SECTION);
+        assert_eq!(segments[0].timestamp_offset, Some(4.0));
+        assert_eq!(segments[1].data, MEDIA_SEGMENT);
+    }
+
+    #[test]
+    fn seek_restarts_from_target_segment() {
+        let m3u8 = fmp4_playlist(0, 5, "#EXT-X-ENDLIST\n");
+        let mut handler = handler(&m3u8);
+        respond_all(&mut handler, &respond_fmp4);
+        assert_eq!(take_segments(&mut handler).len(), 6);
+
+        let respond = |path: &str| {
+            if path == "media.m3u8" {
+                m3u8.clone().into_bytes()
+            } else {
+                respond_fmp4(path)
+            }
+        };
+        for &(position, start, segments) in &[(5.0, 4.0, 3), (-1.0, 0.0, 5), (100.0, 8.0, 1)] {
+            assert_eq!(handler.seek(position).unwrap(), start);
+            respond_all(&mut handler, &respond);
+            let segments_after_seek = take_segments(&mut handler);
+            assert_eq!(segments_after_seek.len(), segments);
+            assert_eq!(segments_after_seek[0].timestamp_offset, Some(start));
+            assert!(segments_after_seek[0].is_discontinuity);
+            assert!(segments_after_seek.iter().all(|s| s.codecs.is_none()));
+            assert!(segments_after_seek[1..]
+                .iter()
+                .all(|s| s.timestamp_offset.is_none() && !s.is_discontinuity));
+        }
+        assert!(handler.is_ended());
+    }
+
+    #[test]
+    fn playback_starts_from_start_position() {
+        // Returns the first segment fetched, the timestamp offset and the start position
+        let start = |m3u8: &str| {
+            let mut handler = handler(m3u8);
+            let fetches = take_fetches(&mut handler);
+            let first = fetches.iter().find(|f| f.1.ends_with(".m4s")).unwrap().1.clone();
+            for (action_id, path) in fetches {
+                handler.handle_data(action_id, &respond_fmp4(&path), 10).unwrap();
+            }
+            let segments = take_segments(&mut handler);
+            (first, segments[0].timestamp_offset, handler.start_position())
+        };
+        let vod = |tags: &str| fmp4_playlist(0, 5, &format!("{}#EXT-X-ENDLIST\n", tags));
+        let live = |tags: &str| fmp4_playlist(0, 10, tags);
+
+        let cases = [
+            (vod(""), "s0.m4s", None, None),
+            (vod("#EXT-X-START:TIME-OFFSET=5.0\n"), "s2.m4s", Some(4.0), None),
+            (vod("#EXT-X-START:TIME-OFFSET=-3.0\n"), "s3.m4s", Some(6.0), None),
+            (vod("#EXT-X-START:TIME-OFFSET=-100\n"), "s0.m4s", None, None),
+            (vod("#EXT-X-START:TIME-OFFSET=100\n"), "s4.m4s", Some(8.0), None),
+            (
+                vod("#EXT-X-START:TIME-OFFSET=5.0,PRECISE=YES\n"),
+                "s2.m4s",
+                Some(4.0),
+                Some(5.0),
+            ),
+            (
+                vod("#EXT-X-START:TIME-OFFSET=-3.5,PRECISE=YES\n"),
+                "s3.m4s",
+                Some(6.0),
+                Some(6.5),
+            ),
+            // Three target durations from the live edge
+            (live(""), "s7.m4s", Some(14.0), None),
+            (live("#EXT-X-SERVER-CONTROL:HOLD-BACK=9.0\n"), "s5.m4s", Some(10.0), None),
+            (live("#EXT-X-START:TIME-OFFSET=-1.0\n"), "s9.m4s", Some(18.0), None),
+        ];
+        for &(ref m3u8, first, timestamp_offset, start_position) in &cases {
+            assert_eq!(
+                start(m3u8),
+                (first.to_owned(), timestamp_offset, start_position),
+                "{}",
+                m3u8
+            );
+        }
+    }
+
+    #[test]
+    fn failed_fetches_are_retried_with_backoff() {
+        let config = HlsPlayerConfig {
+            max_fetch_retries: 4,
+            retry_delay_ms: 100,
+            max_retry_delay_ms: 500,
+            ..Default::default()
+        };
+        let m3u8 = fmp4_playlist(0, 1, "#EXT-X-ENDLIST\n");
+
+        // Fails to fetch `s0.m4s` `failures` times, and returns the retry delays and the result
+        let fetch = |failures: usize| {
+            let mut handler = handler_with_config(&m3u8, config.clone());
+            let mut delays = Vec::new();
+            let mut result = Ok(());
+            while let Some(action) = handler.next_action() {
+                result = match action {
+                    Action::FetchData { action_id, ref url, .. }
+                        if url.path() == "/s0.m4s" && delays.len() < failures =>
+                    {
+                        handler.handle_fetch_error(action_id, Some(503))
+                    }
+                    Action::FetchData { action_id, url, .. } => {
+                        let data = respond_fmp4(url.path().trim_start_matches('/'));
+                        handler.handle_data(action_id, &data, 10)
+                    }
+                    Action::SetTimeout { action_id, duration } => {
+                        delays.push(duration);
+                        handler.handle_timeout(action_id)
+                    }
+                };
+                if result.is_err() {
+                    break;
+                }
+            }
+            let will_retry = take_events(&mut handler)
+                .into_iter()
+                .filter_map(|e| match e {
+                    Event::FetchFailed { will_retry, .. } => Some(will_retry),
+                    _ => None,
+                })
+                .collect::<Vec<_>>();
+            let segments = take_segments(&mut handler).len();
+            (delays, will_retry, result.map(|()| segments))
+        };
+
+        let (delays, will_retry, result) = fetch(2);
+        assert_eq!(delays, [100, 200]);
+        assert_eq!(will_retry, [true, true]);
+        assert_eq!(result.unwrap(), 2);
+
+        // The delays are capped by `max_retry_delay_ms`
+        let (delays, will_retry, result) = fetch(100);
+        assert_eq!(delays, [100, 200, 400, 500]);
+        assert_eq!(will_retry, [true, true, true, true, false]);
+        assert_eq!(result.err().map(|e| *e.kind()), Some(ErrorKind::FetchFailed));
+    }
+
+    #[test]
+    fn segments_fetched_in_parallel_are_delivered_in_order() {
+        let config = HlsPlayerConfig {
+            max_segment_fetches: 3,
+            ..Default::default()
+        };
+        let mut handler = handler_with_config(&fmp4_playlist(0, 5, "#EXT-X-ENDLIST\n"), config);
+        let fetches = take_fetches(&mut handler);
+        let paths = fetches.iter().map(|f| &f.1[..]).collect::<Vec<_>>();
+        assert_eq!(paths, ["init.mp4", "s0.m4s", "s1.m4s", "s2.m4s"]);
+
+        // The segments following a missing one wait for it
+        for &i in &[0, 3, 2] {
+            let (action_id, ref path) = fetches[i];
+            let data = if path == "init.mp4" { INIT_SECTION } else { path.as_bytes() };
+            handler.handle_data(action_id, data, 10).unwrap();
+        }
+        assert!(handler.next_segment().is_none());
+        assert!(take_fetches(&mut handler).is_empty());
+
+        handler.handle_data(fetches[1].0, b"s0.m4s", 10).unwrap();
+        let segments = take_segments(&mut handler);
+        let data = segments.iter().map(|s| &s.data[..]).collect::<Vec<_>>();
+        assert_eq!(data, [INIT_SECTION, b"s0.m4s", b"s1.m4s", b"s2.m4s"]);
+        let fetches = take_fetches(&mut handler);
+        let paths = fetches.iter().map(|f| &f.1[..]).collect::<Vec<_>>();
+        assert_eq!(paths, ["s3.m4s", "s4.m4s"]);
+    }
+
+    #[test]
+    fn fetching_pauses_while_forward_buffer_is_full() {
+        let config = HlsPlayerConfig {
+            max_segment_fetches: 4,
+            max_forward_buffer_secs: 5.0,
+            ..Default::default()
+        };
+        let mut handler = handler_with_config(&fmp4_playlist(0, 10, "#EXT-X-ENDLIST\n"), config);
+        let fetches = take_fetches(&mut handler);
+        let paths = fetches.iter().map(|f| &f.1[..]).collect::<Vec<_>>();
+        assert_eq!(paths, ["init.mp4", "s0.m4s", "s1.m4s", "s2.m4s"]);
+        for (action_id, path) in fetches {
+            handler.handle_data(action_id, &respond_fmp4(&path), 10).unwrap();
+        }
+        assert!(take_fetches(&mut handler).is_empty());
+
+        // The media taken by the caller still counts as buffered until it is played
+        assert_eq!(take_segments(&mut handler).len(), 4);
+        handler.set_buffer_level(6.0).unwrap();
+        assert!(take_fetches(&mut handler).is_empty());
+        handler.set_buffer_level(2.0).unwrap();
+        let fetches = take_fetches(&mut handler);
+        let paths = fetches.iter().map(|f| &f.1[..]).collect::<Vec<_>>();
+        assert_eq!(paths, ["s3.m4s", "s4.m4s"]);
+    }
+
+    #[test]
+    fn stall_is_notified_after_playback_starts() {
+        let mut handler = handler(&fmp4_playlist(0, 2, "#EXT-X-ENDLIST\n"));
+        let fetches = take_fetches(&mut handler);
+
+        // The buffer is empty before the first segment arrives
+        handler.set_buffer_level(0.0).unwrap();
+        assert!(!take_events(&mut handler).contains(&Event::Stalled));
+
+        handler.handle_data(fetches[0].0, INIT_SECTION, 10).unwrap();
+        handler.set_buffer_level(0.0).unwrap();
+        assert!(!take_events(&mut handler).contains(&Event::Stalled));
+
+        handler.handle_data(fetches[1].0, MEDIA_SEGMENT, 10).unwrap();
+        assert_eq!(take_segments(&mut handler).len(), 2);
+        handler.set_buffer_level(0.0).unwrap();
+        assert!(take_events(&mut handler).contains(&Event::Stalled));
+    }
+}
@@ -1,54 +1,389 @@
 use url::Url;
 
-pub use self::action::{Action, ActionFactory, ActionId};
-pub use self::master_playlist_handler::MasterPlaylistHandler;
-pub use self::media_playlist_handler::MediaPlaylistHandler;
+pub use self::action::{Action, ActionFactory, ActionId, ByteRange};
+pub use self::ad_break::{AdEvent, AdMarkerSource};
+pub use self::config::{HlsPlayerConfig, LogLevel};
+pub use self::event::{Event, SwitchReason};
+pub use self::id3::{Frame as Id3Frame, TimedMetadata};
+pub use self::master_playlist_handler::{MasterPlaylistHandler, Rendition};
+pub use self::media_playlist_handler::{BufferedSegment, MediaPlaylistHandler};
+pub use self::webvtt::Cue;
 
+mod aac;
+mod abr;
 mod action;
+mod ad_break;
+mod cea608;
+mod codecs;
+mod config;
+mod crypto;
+mod date_time;
+mod event;
+mod h264;
+mod id3;
 mod master_playlist_handler;
 mod media_playlist_handler;
+mod playlist_tags;
+mod remux;
+mod sample_aes;
+mod scte35;
+mod ts;
+mod webvtt;
 
-use Result;
+use {ErrorKind, Result};
 
 pub type StreamId = u8;
 
+// A player is created only once, so its size does not matter
+#[allow(clippy::large_enum_variant)]
 #[derive(Debug)]
 pub enum HlsPlayer {
-    NotStarted,
+    NotStarted(HlsPlayerConfig),
     MasterPlaylist(MasterPlaylistHandler),
     MediaPlayilst(MediaPlaylistHandler),
 }
 impl HlsPlayer {
     pub fn new() -> Self {
-        HlsPlayer::NotStarted
+        Self::with_config(HlsPlayerConfig::default())
+    }
+
+    pub fn with_config(config: HlsPlayerConfig) -> Self {
+        HlsPlayer::NotStarted(config)
     }
 
     pub fn play_master_playlist(&mut self, url: Url, m3u8: &str) -> Result<()> {
-        let handler = track!(MasterPlaylistHandler::new(url, m3u8))?;
+        let config = self.config().clone();
+        let handler = track!(MasterPlaylistHandler::new(url, m3u8, config))?;
         *self = HlsPlayer::MasterPlaylist(handler);
         Ok(())
     }
 
     pub fn play_media_playlist(&mut self, url: Url, m3u8: &str) -> Result<()> {
+        let config = self.config().clone();
         let action_factory = ActionFactory::new(0);
-        let handler = track!(MediaPlaylistHandler::with_m3u8(action_factory, url, m3u8))?;
+        let handler = track!(MediaPlaylistHandler::with_m3u8(action_factory, url, config, m3u8))?;
         *self = HlsPlayer::MediaPlayilst(handler);
         Ok(())
     }
 
+    /// Returns the MIME type (including the codecs parameter) of the segments being played.
+    ///
+    /// This is `None` until the codecs of the stream are known.
+    pub fn mime_type(&self) -> Option<String> {
+        let codecs = match *self {
+            HlsPlayer::NotStarted(_) => None,
+            HlsPlayer::MasterPlaylist(ref x) => x.codecs(),
+            HlsPlayer::MediaPlayilst(ref x) => x.codecs().map(|c| c.to_owned()),
+        };
+        codecs.map(|c| codecs::mime_type(&c))
+    }
+
+    /// Returns the MIME type of the segments returned by `next_audio_segment()`.
+    ///
+    /// This is `None` if the audio is not delivered by an alternate rendition
+    /// or its codecs are not known yet.
+    pub fn audio_mime_type(&self) -> Option<String> {
+        if let HlsPlayer::MasterPlaylist(ref x) = *self {
+            x.audio_codecs().map(|c| codecs::mime_type(&c))
+        } else {
+            None
+        }
+    }
+
+    /// Returns the MIME type of the segment returned by the next `next_segment()`,
+    /// if it is an initialization segment.
+    ///
+    /// The caller should change the type of the `SourceBuffer` (`changeType()`)
+    /// if this differs from the current one.
+    pub fn next_mime_type(&self) -> Option<String> {
+        let codecs = match *self {
+            HlsPlayer::NotStarted(_) => None,
+            HlsPlayer::MasterPlaylist(ref x) => x.next_codecs(),
+            HlsPlayer::MediaPlayilst(ref x) => x.next_codecs(),
+        };
+        codecs.map(codecs::mime_type)
+    }
+
+    /// Same as `next_mime_type()`, but for `next_audio_segment()`.
+    pub fn next_audio_mime_type(&self) -> Option<String> {
+        if let HlsPlayer::MasterPlaylist(ref x) = *self {
+            x.next_audio_codecs().map(codecs::mime_type)
+        } else {
+            None
+        }
+    }
+
+    /// Returns `true` if the audio is delivered separately from the video
+    /// (i.e., the caller needs a dedicated `SourceBuffer` for `next_audio_segment()`).
+    pub fn has_alternate_audio(&self) -> bool {
+        if let HlsPlayer::MasterPlaylist(ref x) = *self {
+            x.has_alternate_audio()
+        } else {
+            false
+        }
+    }
+
+    /// Returns the audio renditions selectable by `select_audio_rendition()`.
+    pub fn audio_renditions(&self) -> Vec<Rendition> {
+        if let HlsPlayer::MasterPlaylist(ref x) = *self {
+            x.audio_renditions().into_iter().cloned().collect()
+        } else {
+            Vec::new()
+        }
+    }
+
+    /// Selects the audio rendition that has the given name or language.
+    pub fn select_audio_rendition(&mut self, name_or_language: &str) -> Result<()> {
+        if let HlsPlayer::MasterPlaylist(ref mut x) = *self {
+            track!(x.select_audio_rendition(name_or_language))
+        } else {
+            track_panic!(ErrorKind::InvalidInput, "No alternate audio renditions");
+        }
+    }
+
+    /// Returns the subtitle renditions selectable by `select_subtitle_rendition()`.
+    pub fn subtitle_renditions(&self) -> Vec<Rendition> {
+        if let HlsPlayer::MasterPlaylist(ref x) = *self {
+            x.subtitle_renditions().into_iter().cloned().collect()
+        } else {
+            Vec::new()
+        }
+    }
+
+    /// Selects the subtitle rendition that has the given name or language.
+    pub fn select_subtitle_rendition(&mut self, name_or_language: &str) -> Result<()> {
+        if let HlsPlayer::MasterPlaylist(ref mut x) = *self {
+            track!(x.select_subtitle_rendition(name_or_language))
+        } else {
+            track_panic!(ErrorKind::InvalidInput, "No subtitle renditions");
+        }
+    }
+
+    /// Returns the subtitle cues parsed since the last call.
+    ///
+    /// The cue times are in seconds, and are aligned to the timeline of the segments
+    /// returned by `next_segment()`.
+    pub fn take_subtitle_cues(&mut self) -> Vec<Cue> {
+        if let HlsPlayer::MasterPlaylist(ref mut x) = *self {
+            x.take_subtitle_cues()
+        } else {
+            Vec::new()
+        }
+    }
+
+    /// Returns the closed caption (CEA-608) cues decoded since the last call.
+    ///
+    /// The cue times are in seconds, and are aligned to the timeline of the segments
+    /// returned by `next_segment()`.
+    pub fn take_caption_cues(&mut self) -> Vec<Cue> {
+        match *self {
+            HlsPlayer::NotStarted(_) => Vec::new(),
+            HlsPlayer::MasterPlaylist(ref mut x) => x.take_caption_cues(),
+            HlsPlayer::MediaPlayilst(ref mut x) => {
+                let origin = x.first_timestamp().unwrap_or(0);
+                let mut cues = x.take_caption_cues();
+                for cue in &mut cues {
+                    cue.align_to(origin);
+                }
+                cues
+            }
+        }
+    }
+
+    /// Returns the ID3 timed metadata extracted since the last call.
+    ///
+    /// The times are in seconds, and are aligned to the timeline of the segments
+    /// returned by `next_segment()`.
+    pub fn take_timed_metadata(&mut self) -> Vec<TimedMetadata> {
+        match *self {
+            HlsPlayer::NotStarted(_) => Vec::new(),
+            HlsPlayer::MasterPlaylist(ref mut x) => x.take_timed_metadata(),
+            HlsPlayer::MediaPlayilst(ref mut x) => {
+                let origin = x.first_timestamp().unwrap_or(0);
+                let mut metadata = x.take_timed_metadata();
+                for m in &mut metadata {
+                    m.time = ts::relative_time(m.time, origin);
+                }
+                metadata
+            }
+        }
+    }
+
+    /// Returns the ad break events signaled since the last call.
+    ///
+    /// The times are in seconds, and are aligned to the timeline of the segments
+    /// returned by `next_segment()`.
+    pub fn take_ad_events(&mut self) -> Vec<AdEvent> {
+        match *self {
+            HlsPlayer::NotStarted(_) => Vec::new(),
+            HlsPlayer::MasterPlaylist(ref mut x) => x.take_ad_events(),
+            HlsPlayer::MediaPlayilst(ref mut x) => x.take_ad_events(),
+        }
+    }
+
+    /// Returns the next notification about the lifecycle, playlists, segments, variant switches,
+    /// fetch failures and fatal errors of the player.
+    pub fn next_event(&mut self) -> Option<Event> {
+        match *self {
+            HlsPlayer::NotStarted(_) => None,
+            HlsPlayer::MasterPlaylist(ref mut x) => x.next_event(),
+            HlsPlayer::MediaPlayilst(ref mut x) => x.next_event(),
+        }
+    }
+
+    /// Converts a position in the presentation timeline (in seconds) into the wall-clock time
+    /// (in milliseconds since the Unix epoch).
+    ///
+    /// This returns `None` if the stream has no `EXT-X-PROGRAM-DATE-TIME` tags.
+    pub fn position_to_date_time(&self, position: f64) -> Option<f64> {
+        match *self {
+            HlsPlayer::NotStarted(_) => None,
+            HlsPlayer::MasterPlaylist(ref x) => x.position_to_date_time(position),
+            HlsPlayer::MediaPlayilst(ref x) => {
+                x.date_time_mapping().position_to_date_time(position)
+            }
+        }
+    }
+
+    /// Converts a wall-clock time (in milliseconds since the Unix epoch) into a position
+    /// in the presentation timeline (in seconds).
+    ///
+    /// This returns `None` if the stream has no `EXT-X-PROGRAM-DATE-TIME` tags.
+    pub fn date_time_to_position(&self, date_time: f64) -> Option<f64> {
+        match *self {
+            HlsPlayer::NotStarted(_) => None,
+            HlsPlayer::MasterPlaylist(ref x) => x.date_time_to_position(date_time),
+            HlsPlayer::MediaPlayilst(ref x) => {
+                x.date_time_mapping().date_time_to_position(date_time)
+            }
+        }
+    }
+
+    /// Returns `true` if the stream has ended and all of its segments have been delivered.
+    ///
+    /// Once this returns `true`, the caller can call `MediaSource.endOfStream()`.
+    pub fn is_ended(&self) -> bool {
+        match *self {
+            HlsPlayer::NotStarted(_) => false,
+            HlsPlayer::MasterPlaylist(ref x) => x.is_ended(),
+            HlsPlayer::MediaPlayilst(ref x) => x.is_ended(),
+        }
+    }
+
     pub fn next_action(&mut self) -> Option<Action> {
         match *self {
-            HlsPlayer::NotStarted => None,
+            HlsPlayer::NotStarted(_) => None,
             HlsPlayer::MasterPlaylist(ref mut x) => x.next_action(),
             HlsPlayer::MediaPlayilst(ref mut x) => x.next_action(),
         }
     }
 
     pub fn next_segment(&mut self) -> Option<Vec<u8>> {
-        match *self {
-            HlsPlayer::NotStarted => None,
+        let segment = match *self {
+            HlsPlayer::NotStarted(_) => None,
             HlsPlayer::MasterPlaylist(ref mut x) => x.next_segment(),
             HlsPlayer::MediaPlayilst(ref mut x) => x.next_segment(),
+        };
+        segment.map(|s| s.data)
+    }
+
+    /// Returns `true` if the segment returned by the next `next_segment()` follows a discontinuity.
+    ///
+    /// In that case, the caller should reset the timestamp offset of the `SourceBuffer`
+    /// before appending the segment.
+    pub fn is_discontinuity(&self) -> bool {
+        match *self {
+            HlsPlayer::NotStarted(_) => false,
+            HlsPlayer::MasterPlaylist(ref x) => x.is_discontinuity(),
+            HlsPlayer::MediaPlayilst(ref x) => x.is_discontinuity(),
+        }
+    }
+
+    pub fn next_audio_segment(&mut self) -> Option<Vec<u8>> {
+        if let HlsPlayer::MasterPlaylist(ref mut x) = *self {
+            x.next_audio_segment().map(|s| s.data)
+        } else {
+            None
+        }
+    }
+
+    /// Same as `is_discontinuity()` except that this is for `next_audio_segment()`.
+    pub fn is_audio_discontinuity(&self) -> bool {
+        if let HlsPlayer::MasterPlaylist(ref x) = *self {
+            x.is_audio_discontinuity()
+        } else {
+            false
+        }
+    }
+
+    /// Returns the position (in seconds) at which the segment returned by the next
+    /// `next_segment()` should be placed, if it is the first one after a seek
+    /// (or a start from the middle of the playlist).
+    ///
+    /// In that case, the caller should set the `timestampOffset` of the `SourceBuffer`
+    /// to this value before appending the segment.
+    pub fn timestamp_offset(&self) -> Option<f64> {
+        match *self {
+            HlsPlayer::NotStarted(_) => None,
+            HlsPlayer::MasterPlaylist(ref x) => x.timestamp_offset(),
+            HlsPlayer::MediaPlayilst(ref x) => x.timestamp_offset(),
+        }
+    }
+
+    /// Returns the position (in seconds) from which playback should start,
+    /// if the playlist requests a precise start (`EXT-X-START:PRECISE=YES`).
+    ///
+    /// The caller should set the playback position to this value when the first segment
+    /// is appended. Otherwise, playback starts from the beginning of the first segment.
+    pub fn start_position(&self) -> Option<f64> {
+        match *self {
+            HlsPlayer::NotStarted(_) => None,
+            HlsPlayer::MasterPlaylist(ref x) => x.start_position(),
+            HlsPlayer::MediaPlayilst(ref x) => x.start_position(),
+        }
+    }
+
+    /// The `next_audio_segment()` version of `timestamp_offset()`.
+    pub fn audio_timestamp_offset(&self) -> Option<f64> {
+        if let HlsPlayer::MasterPlaylist(ref x) = *self {
+            x.audio_timestamp_offset()
+        } else {
+            None
+        }
+    }
+
+    /// Reports the amount of media (in seconds) buffered ahead of the playback position
+    /// (e.g., in the video `SourceBuffer`).
+    ///
+    /// Segment fetching pauses while this plus the segments not yet taken exceeds
+    /// `HlsPlayerConfig::max_forward_buffer_secs`, and resumes when a lower level is reported.
+    pub fn set_buffer_level(&mut self, level: f64) -> Result<()> {
+        match *self {
+            HlsPlayer::NotStarted(_) => Ok(()),
+            HlsPlayer::MasterPlaylist(ref mut x) => track!(x.set_buffer_level(level)),
+            HlsPlayer::MediaPlayilst(ref mut x) => track!(x.set_buffer_level(level)),
+        }
+    }
+
+    /// Same as `set_buffer_level()`, but for the alternate audio track.
+    pub fn set_audio_buffer_level(&mut self, level: f64) -> Result<()> {
+        if let HlsPlayer::MasterPlaylist(ref mut x) = *self {
+            track!(x.set_audio_buffer_level(level))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Restarts fetching from the segment containing `position` (in seconds),
+    /// and returns the start position of the segment.
+    ///
+    /// The segments queued so far are discarded. The first segment delivered after this
+    /// has `timestamp_offset()` set to the returned value.
+    pub fn seek(&mut self, position: f64) -> Result<f64> {
+        match *self {
+            HlsPlayer::NotStarted(_) => track_panic!(ErrorKind::InvalidInput, "Not started"),
+            HlsPlayer::MasterPlaylist(ref mut x) => track!(x.seek(position)),
+            HlsPlayer::MediaPlayilst(ref mut x) => track!(x.seek(position)),
         }
     }
 
@@ -58,22 +393,207 @@ impl HlsPlayer {
         data: &[u8],
         fetch_duration_ms: u32,
     ) -> Result<()> {
-        match *self {
-            HlsPlayer::NotStarted => Ok(()),
+        let result = match *self {
+            HlsPlayer::NotStarted(_) => Ok(()),
             HlsPlayer::MasterPlaylist(ref mut x) => {
                 track!(x.handle_data(action_id, data, fetch_duration_ms))
             }
             HlsPlayer::MediaPlayilst(ref mut x) => {
                 track!(x.handle_data(action_id, data, fetch_duration_ms))
             }
-        }
+        };
+        self.notify_if_fatal(result)
     }
 
     pub fn handle_timeout(&mut self, action_id: ActionId) -> Result<()> {
-        match *self {
-            HlsPlayer::NotStarted => Ok(()),
+        let result = match *self {
+            HlsPlayer::NotStarted(_) => Ok(()),
             HlsPlayer::MasterPlaylist(ref mut x) => track!(x.handle_timeout(action_id)),
             HlsPlayer::MediaPlayilst(ref mut x) => track!(x.handle_timeout(action_id)),
+        };
+        self.notify_if_fatal(result)
+    }
+
+    /// Handles the failure of the `FetchData` action identified by `action_id`.
+    ///
+    /// `status` is the HTTP status code of the response, or `None` on network errors.
+    /// The fetch is retried according to `HlsPlayerConfig`, and an error is returned
+    /// if playback cannot be continued.
+    pub fn handle_fetch_error(&mut self, action_id: ActionId, status: Option<u16>) -> Result<()> {
+        let result = match *self {
+            HlsPlayer::NotStarted(_) => Ok(()),
+            HlsPlayer::MasterPlaylist(ref mut x) => track!(x.handle_fetch_error(action_id, status)),
+            HlsPlayer::MediaPlayilst(ref mut x) => track!(x.handle_fetch_error(action_id, status)),
+        };
+        self.notify_if_fatal(result)
+    }
+
+    pub fn log_level(&self) -> LogLevel {
+        self.config().log_level
+    }
+
+    /// Notifies `Event::Error` unless `result` is a stale response, which does not stop playback.
+    fn notify_if_fatal(&mut self, result: Result<()>) -> Result<()> {
+        if let Err(ref e) = result {
+            if *e.kind() != ErrorKind::StaleResponse {
+                match *self {
+                    HlsPlayer::NotStarted(_) => {}
+                    HlsPlayer::MasterPlaylist(ref mut x) => x.notify_error(e),
+                    HlsPlayer::MediaPlayilst(ref mut x) => x.notify_error(e),
+                }
+            }
+        }
+        result
+    }
+
+    fn config(&self) -> &HlsPlayerConfig {
+        match *self {
+            HlsPlayer::NotStarted(ref config) => config,
+            HlsPlayer::MasterPlaylist(ref x) => x.config(),
+            HlsPlayer::MediaPlayilst(ref x) => x.config(),
+        }
+    }
+}
+impl Default for HlsPlayer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn respond_all(player: &mut HlsPlayer, respond: &dyn Fn(&Url) -> Vec<u8>) {
+        while let Some(action) = player.next_action() {
+            if let Action::FetchData { action_id, url, .. } = action {
+                player.handle_data(action_id, &respond(&url), 10).unwrap();
+            }
+        }
+    }
+
+    fn next_fetch(player: &mut HlsPlayer) -> ActionId {
+        match player.next_action() {
+            Some(Action::FetchData { action_id, .. }) => action_id,
+            action => panic!("{:?}", action),
+        }
+    }
+
+    #[test]
+    fn mime_type_of_fmp4_media_playlist() {
+        let m3u8 = r#"#EXTM3U
+#EXT-X-TARGETDURATION:2
+#EXT-X-MAP:URI="init.mp4"
+#EXTINF:2,
+s0.m4s
+#EXT-X-ENDLIST
+"#;
+        let mut player = HlsPlayer::new();
+        let url = "http://example.com/media.m3u8".parse().unwrap();
+        player.play_media_playlist(url, m3u8).unwrap();
+        assert_eq!(player.mime_type(), None);
+
+        let init_section = codecs::tests::init_section(&[
+            codecs::tests::avc1_entry(),
+            codecs::tests::mp4a_entry(),
+        ]);
+        respond_all(&mut player, &|url| {
+            if url.path().ends_with("init.mp4") {
+                init_section.clone()
+            } else {
+                b"\0\0\0\x08moof".to_vec()
+            }
+        });
+        let expected = r#"video/mp4; codecs="avc1.64001f,mp4a.40.2""#;
+        assert_eq!(player.mime_type(), Some(expected.to_owned()));
+        assert_eq!(player.next_mime_type(), Some(expected.to_owned()));
+        assert_eq!(player.next_segment(), Some(init_section));
+        assert_eq!(player.next_mime_type(), None);
+    }
+
+    #[test]
+    fn mime_type_from_codecs_attribute() {
+        let master_m3u8 = r#"#EXTM3U
+#EXT-X-STREAM-INF:BANDWIDTH=1000000,CODECS="avc1.4d401e,mp4a.40.2"
+media.m3u8
+"#;
+        let media_m3u8 = r#"#EXTM3U
+#EXT-X-TARGETDURATION:2
+#EXT-X-MAP:URI="init.mp4"
+#EXTINF:2,
+s0.m4s
+#EXT-X-ENDLIST
+"#;
+        // The sample entries of this initialization section are unknown
+        let respond = |url: &Url| match url.path() {
+            "/media.m3u8" => media_m3u8.as_bytes().to_vec(),
+            "/init.mp4" => b"\0\0\0\x08moov".to_vec(),
+            _ => b"\0\0\0\x08moof".to_vec(),
+        };
+
+        let mut player = HlsPlayer::new();
+        let url = "http://example.com/master.m3u8".parse().unwrap();
+        player.play_master_playlist(url, master_m3u8).unwrap();
+        respond_all(&mut player, &respond);
+        let expected = r#"video/mp4; codecs="avc1.4d401e,mp4a.40.2""#;
+        assert_eq!(player.next_mime_type(), Some(expected.to_owned()));
+
+        // Without the attribute, the codecs are not guessed
+        let mut player = HlsPlayer::new();
+        let url = "http://example.com/media.m3u8".parse().unwrap();
+        player.play_media_playlist(url, media_m3u8).unwrap();
+        let mut result = Ok(());
+        while let Some(action) = player.next_action() {
+            if let Action::FetchData { action_id, url, .. } = action {
+                result = result.and(player.handle_data(action_id, &respond(&url), 10));
+            }
+        }
+        assert_eq!(result.err().map(|e| *e.kind()), Some(ErrorKind::InvalidInput));
+        assert_eq!(player.mime_type(), None);
+    }
+
+    #[test]
+    fn fatal_errors_are_notified() {
+        let m3u8 = r#"#EXTM3U
+#EXT-X-TARGETDURATION:2
+#EXTINF:2,
+s0.ts
+#EXTINF:2,
+s1.ts
+#EXT-X-ENDLIST
+"#;
+        let config = HlsPlayerConfig {
+            max_fetch_retries: 0,
+            ..HlsPlayerConfig::default()
+        };
+        let mut player = HlsPlayer::with_config(config);
+        let url = "http://example.com/media.m3u8".parse().unwrap();
+        player.play_media_playlist(url, m3u8).unwrap();
+        let cancelled = next_fetch(&mut player);
+
+        // Responses to cancelled requests do not stop playback
+        player.seek(2.0).unwrap();
+        let e = player.handle_data(cancelled, b"", 10).unwrap_err();
+        assert_eq!(*e.kind(), ErrorKind::StaleResponse);
+        while let Some(event) = player.next_event() {
+            if let Event::Error { .. } = event {
+                panic!("{:?}", event);
+            }
+        }
+
+        let failed = next_fetch(&mut player);
+        let e = player.handle_fetch_error(failed, Some(404)).unwrap_err();
+        assert_eq!(*e.kind(), ErrorKind::FetchFailed);
+        let mut events = Vec::new();
+        while let Some(event) = player.next_event() {
+            events.push(event);
+        }
+        match events.last() {
+            Some(&Event::Error { kind, ref reason }) => {
+                assert_eq!(kind, ErrorKind::FetchFailed);
+                assert!(reason.contains("Cannot fetch"), "{}", reason);
+            }
+            event => panic!("{:?}", event),
         }
     }
 }
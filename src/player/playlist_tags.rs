@@ -0,0 +1,523 @@
+//! Media playlist tags that `hls_m3u8` does not handle (or mishandles).
+use hls_m3u8::MediaPlaylist;
+use hls_m3u8::tags::{ExtXKey, ExtXMap};
+use trackable::error::ErrorKindExt;
+
+use {ErrorKind, Result};
+use super::media_playlist_handler::SequenceNumber;
+
+/// `EXT-X-DATERANGE` tag.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DateRange {
+    pub id: String,
+    pub class: Option<String>,
+    pub start_date: String,
+    pub duration: Option<f64>,
+    pub planned_duration: Option<f64>,
+    pub scte35_cmd: Option<Vec<u8>>,
+    pub scte35_out: Option<Vec<u8>>,
+    pub scte35_in: Option<Vec<u8>>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Tag {
+    DateRange(DateRange),
+
+    /// `EXT-X-CUE-OUT` (a de facto standard tag marking the start of an ad break).
+    CueOut { duration: Option<f64> },
+
+    /// `EXT-X-CUE-IN` (a de facto standard tag marking the end of an ad break).
+    CueIn,
+}
+
+/// Tag followed by the `segment_index`-th segment of the playlist.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SegmentTag {
+    pub segment_index: usize,
+    pub tag: Tag,
+}
+
+/// `EXT-X-SERVER-CONTROL` tag.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ServerControl {
+    pub can_skip_until: Option<f64>,
+    pub can_skip_dateranges: bool,
+    pub hold_back: Option<f64>,
+    pub part_hold_back: Option<f64>,
+    pub can_block_reload: bool,
+}
+
+/// `EXT-X-PART` tag of a partial segment of the `segment_index`-th segment.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PartialSegment {
+    pub segment_index: usize,
+    pub uri: String,
+    pub duration: f64,
+    pub independent: bool,
+
+    /// Length and offset (if specified) of the sub-range.
+    pub byte_range: Option<(u64, Option<u64>)>,
+
+    pub gap: bool,
+}
+
+/// `EXT-X-PRELOAD-HINT` tag.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PreloadHint {
+    /// `"PART"` or `"MAP"`.
+    pub hint_type: String,
+    pub uri: String,
+    pub byte_range_start: u64,
+    pub byte_range_length: Option<u64>,
+}
+
+/// Tags of the segment following the last complete one.
+///
+/// In Low-Latency HLS playlists, they precede the partial segments of the segment
+/// being produced. `hls_m3u8` rejects them because no URI follows.
+#[derive(Debug, Clone, Default)]
+pub struct NextSegmentTags {
+    pub discontinuity: bool,
+    pub key: Option<ExtXKey>,
+    pub map: Option<ExtXMap>,
+    pub program_date_time: Option<String>,
+}
+
+/// A parsed media playlist and the tags extracted from it.
+#[derive(Debug)]
+pub struct ParsedMediaPlaylist {
+    pub playlist: MediaPlaylist,
+
+    /// The value of `EXT-X-DISCONTINUITY-SEQUENCE`.
+    pub discontinuity_seq: SequenceNumber,
+
+    pub segment_tags: Vec<SegmentTag>,
+
+    /// Length and offset (if specified) given by `EXT-X-BYTERANGE` for each segment.
+    pub byte_ranges: Vec<Option<(u64, Option<u64>)>>,
+
+    pub server_control: ServerControl,
+
+    /// The value of `PART-TARGET` of `EXT-X-PART-INF` (only given in Low-Latency HLS playlists).
+    pub part_target: Option<f64>,
+
+    pub parts: Vec<PartialSegment>,
+    pub preload_hints: Vec<PreloadHint>,
+    pub next_segment_tags: NextSegmentTags,
+
+    /// The value of `SKIPPED-SEGMENTS` of `EXT-X-SKIP` (only given in Playlist Delta Updates).
+    ///
+    /// The first segment in `playlist` is preceded by this many segments omitted by the server.
+    pub skipped_segments: u64,
+}
+
+/// Parses a media playlist.
+///
+/// `hls_m3u8` mistakes `EXT-X-DISCONTINUITY-SEQUENCE` for `EXT-X-DISCONTINUITY`,
+/// cannot parse the hexadecimal SCTE-35 attributes of `EXT-X-DATERANGE`,
+/// rejects an `EXT-X-BYTERANGE` without an offset if the preceding sub-range is not listed
+/// (it may have left the live window), and does not know the Low-Latency HLS tags,
+/// so they are extracted before parsing.
+pub fn parse_media_playlist(m3u8: &str) -> Result<ParsedMediaPlaylist> {
+    let mut discontinuity_seq = 0;
+    let mut segment_tags = Vec::new();
+    let mut byte_ranges = Vec::new();
+    let mut byte_range = None;
+    let mut server_control = ServerControl::default();
+    let mut part_target = None;
+    let mut parts = Vec::new();
+    let mut preload_hints = Vec::new();
+    let mut skipped_segments = 0;
+    let mut segment_index = 0;
+    let mut lines = Vec::new();
+    let mut last_uri_line = None;
+    for line in m3u8.lines() {
+        let line = line.trim();
+        let tag = if let Some(value) = line.strip_prefix("#EXT-X-DISCONTINUITY-SEQUENCE:") {
+            discontinuity_seq = track!(parse_number(value))?;
+            continue;
+        } else if let Some(value) = line.strip_prefix("#EXT-X-SERVER-CONTROL:") {
+            server_control = track!(parse_server_control(value))?;
+            continue;
+        } else if let Some(value) = line.strip_prefix("#EXT-X-PART-INF:") {
+            for (key, value) in track!(parse_attributes(value))? {
+                if key == "PART-TARGET" {
+                    part_target = Some(track!(parse_number(value))?);
+                }
+            }
+            continue;
+        } else if let Some(value) = line.strip_prefix("#EXT-X-BYTERANGE:") {
+            byte_range = Some(track!(parse_byte_range(value))?);
+            continue;
+        } else if let Some(value) = line.strip_prefix("#EXT-X-PART:") {
+            parts.push(track!(parse_part(value, segment_index))?);
+            continue;
+        } else if let Some(value) = line.strip_prefix("#EXT-X-PRELOAD-HINT:") {
+            preload_hints.push(track!(parse_preload_hint(value))?);
+            continue;
+        } else if let Some(value) = line.strip_prefix("#EXT-X-SKIP:") {
+            for (key, value) in track!(parse_attributes(value))? {
+                if key == "SKIPPED-SEGMENTS" {
+                    skipped_segments = track!(parse_number(value))?;
+                }
+            }
+            continue;
+        } else if let Some(value) = line.strip_prefix("#EXT-X-DATERANGE:") {
+            Tag::DateRange(track!(parse_date_range(value))?)
+        } else if line == "#EXT-X-CUE-OUT" {
+            Tag::CueOut { duration: None }
+        } else if let Some(value) = line.strip_prefix("#EXT-X-CUE-OUT:") {
+            let value = value.trim_start_matches("DURATION=");
+            Tag::CueOut {
+                duration: value.parse().ok(),
+            }
+        } else if line == "#EXT-X-CUE-IN" {
+            Tag::CueIn
+        } else {
+            if !line.is_empty() && !line.starts_with('#') {
+                segment_index += 1;
+                byte_ranges.push(byte_range.take());
+                last_uri_line = Some(lines.len());
+            }
+            lines.push(line);
+            continue;
+        };
+        segment_tags.push(SegmentTag { segment_index, tag });
+    }
+
+    let trailing_start = last_uri_line.map_or(0, |i| i + 1);
+    let mut next_segment_tags = NextSegmentTags::default();
+    let mut m3u8 = String::with_capacity(m3u8.len());
+    for (i, line) in lines.into_iter().enumerate() {
+        if i >= trailing_start {
+            if line == "#EXT-X-DISCONTINUITY" {
+                next_segment_tags.discontinuity = true;
+                continue;
+            } else if line.starts_with("#EXT-X-KEY:") {
+                next_segment_tags.key = Some(track!(line.parse())?);
+                continue;
+            } else if line.starts_with("#EXT-X-MAP:") {
+                next_segment_tags.map = Some(track!(line.parse())?);
+                continue;
+            } else if let Some(value) = line.strip_prefix("#EXT-X-PROGRAM-DATE-TIME:") {
+                next_segment_tags.program_date_time = Some(value.to_owned());
+                continue;
+            }
+        }
+        m3u8.push_str(line);
+        m3u8.push('\n');
+    }
+    let playlist = track!(m3u8.parse())?;
+    Ok(ParsedMediaPlaylist {
+        playlist,
+        discontinuity_seq,
+        segment_tags,
+        byte_ranges,
+        server_control,
+        part_target,
+        parts,
+        preload_hints,
+        next_segment_tags,
+        skipped_segments,
+    })
+}
+
+fn parse_server_control(attrs: &str) -> Result<ServerControl> {
+    let mut server_control = ServerControl::default();
+    for (key, value) in track!(parse_attributes(attrs))? {
+        match key {
+            "CAN-SKIP-UNTIL" => server_control.can_skip_until = Some(track!(parse_number(value))?),
+            "CAN-SKIP-DATERANGES" => server_control.can_skip_dateranges = value == "YES",
+            "HOLD-BACK" => server_control.hold_back = Some(track!(parse_number(value))?),
+            "PART-HOLD-BACK" => server_control.part_hold_back = Some(track!(parse_number(value))?),
+            "CAN-BLOCK-RELOAD" => server_control.can_block_reload = value == "YES",
+            _ => {}
+        }
+    }
+    Ok(server_control)
+}
+
+fn parse_part(attrs: &str, segment_index: usize) -> Result<PartialSegment> {
+    let mut uri = None;
+    let mut duration = None;
+    let mut part = PartialSegment {
+        segment_index,
+        uri: String::new(),
+        duration: 0.0,
+        independent: false,
+        byte_range: None,
+        gap: false,
+    };
+    for (key, value) in track!(parse_attributes(attrs))? {
+        match key {
+            "URI" => uri = Some(unquote(value).to_owned()),
+            "DURATION" => duration = Some(track!(parse_number(value))?),
+            "INDEPENDENT" => part.independent = value == "YES",
+            "BYTERANGE" => part.byte_range = Some(track!(parse_byte_range(unquote(value)))?),
+            "GAP" => part.gap = value == "YES",
+            _ => {}
+        }
+    }
+    part.uri = track_assert_some!(uri, ErrorKind::InvalidInput, "No URI: {:?}", attrs);
+    part.duration = track_assert_some!(
+        duration,
+        ErrorKind::InvalidInput,
+        "No DURATION: {:?}",
+        attrs
+    );
+    Ok(part)
+}
+
+/// Parses `<n>[@<o>]` into the length and the offset (if specified).
+fn parse_byte_range(value: &str) -> Result<(u64, Option<u64>)> {
+    if let Some((length, offset)) = value.split_once('@') {
+        Ok((track!(parse_number(length))?, Some(track!(parse_number(offset))?)))
+    } else {
+        Ok((track!(parse_number(value))?, None))
+    }
+}
+
+fn parse_preload_hint(attrs: &str) -> Result<PreloadHint> {
+    let mut hint_type = None;
+    let mut uri = None;
+    let mut byte_range_start = 0;
+    let mut byte_range_length = None;
+    for (key, value) in track!(parse_attributes(attrs))? {
+        match key {
+            "TYPE" => hint_type = Some(value.to_owned()),
+            "URI" => uri = Some(unquote(value).to_owned()),
+            "BYTERANGE-START" => byte_range_start = track!(parse_number(value))?,
+            "BYTERANGE-LENGTH" => byte_range_length = Some(track!(parse_number(value))?),
+            _ => {}
+        }
+    }
+    Ok(PreloadHint {
+        hint_type: track_assert_some!(hint_type, ErrorKind::InvalidInput, "No TYPE: {:?}", attrs),
+        uri: track_assert_some!(uri, ErrorKind::InvalidInput, "No URI: {:?}", attrs),
+        byte_range_start,
+        byte_range_length,
+    })
+}
+
+fn parse_date_range(attrs: &str) -> Result<DateRange> {
+    let mut date_range = DateRange::default();
+    for (key, value) in track!(parse_attributes(attrs))? {
+        match key {
+            "ID" => date_range.id = unquote(value).to_owned(),
+            "CLASS" => date_range.class = Some(unquote(value).to_owned()),
+            "START-DATE" => date_range.start_date = unquote(value).to_owned(),
+            "DURATION" => date_range.duration = Some(track!(parse_number(value))?),
+            "PLANNED-DURATION" => date_range.planned_duration = Some(track!(parse_number(value))?),
+            "SCTE35-CMD" => date_range.scte35_cmd = Some(track!(parse_hex(value))?),
+            "SCTE35-OUT" => date_range.scte35_out = Some(track!(parse_hex(value))?),
+            "SCTE35-IN" => date_range.scte35_in = Some(track!(parse_hex(value))?),
+            _ => {}
+        }
+    }
+    track_assert!(!date_range.id.is_empty(), ErrorKind::InvalidInput, "No ID: {:?}", attrs);
+    Ok(date_range)
+}
+
+/// Splits an attribute list into name and value pairs (the values may be quoted strings).
+pub fn parse_attributes(attrs: &str) -> Result<Vec<(&str, &str)>> {
+    let mut pairs = Vec::new();
+    let mut rest = attrs.trim();
+    while !rest.is_empty() {
+        let eq = track_assert_some!(
+            rest.find('='),
+            ErrorKind::InvalidInput,
+            "Malformed attribute list: {:?}",
+            attrs
+        );
+        let key = rest[..eq].trim();
+        rest = &rest[eq + 1..];
+        let value_len = if let Some(quoted) = rest.strip_prefix('"') {
+            let close = track_assert_some!(
+                quoted.find('"'),
+                ErrorKind::InvalidInput,
+                "Unterminated quoted string: {:?}",
+                attrs
+            );
+            close + 2
+        } else {
+            rest.find(',').unwrap_or(rest.len())
+        };
+        pairs.push((key, rest[..value_len].trim()));
+        rest = rest[value_len..].trim_start();
+        rest = rest.strip_prefix(',').unwrap_or(rest).trim_start();
+    }
+    Ok(pairs)
+}
+
+pub fn unquote(value: &str) -> &str {
+    value
+        .strip_prefix('"')
+        .and_then(|v| v.strip_suffix('"'))
+        .unwrap_or(value)
+}
+
+fn parse_number<T>(value: &str) -> Result<T>
+where
+    T: ::std::str::FromStr,
+    T::Err: ::std::error::Error + Send + Sync + 'static,
+{
+    let n = track!(
+        value
+            .trim()
+            .parse()
+            .map_err(|e| ErrorKind::InvalidInput.cause(e))
+    )?;
+    Ok(n)
+}
+
+fn parse_hex(value: &str) -> Result<Vec<u8>> {
+    let hex = unquote(value);
+    let hex = track_assert_some!(
+        hex.strip_prefix("0x").or_else(|| hex.strip_prefix("0X")),
+        ErrorKind::InvalidInput,
+        "Not a hexadecimal sequence: {:?}",
+        value
+    );
+    track_assert!(
+        hex.is_ascii() && hex.len() % 2 == 0,
+        ErrorKind::InvalidInput,
+        "Malformed hexadecimal sequence: {:?}",
+        value
+    );
+    let mut bytes = Vec::with_capacity(hex.len() / 2);
+    for i in (0..hex.len()).step_by(2) {
+        let byte = track!(
+            u8::from_str_radix(&hex[i..i + 2], 16).map_err(|e| ErrorKind::InvalidInput.cause(e))
+        )?;
+        bytes.push(byte);
+    }
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Low-Latency HLS playlist (an excerpt of the example in the HLS specification).
+    const LOW_LATENCY_PLAYLIST: &str = r#"#EXTM3U
+#EXT-X-TARGETDURATION:4
+#EXT-X-VERSION:6
+#EXT-X-SERVER-CONTROL:CAN-BLOCK-RELOAD=YES,PART-HOLD-BACK=1.0,CAN-SKIP-UNTIL=12.0
+#EXT-X-PART-INF:PART-TARGET=0.33334
+#EXT-X-MEDIA-SEQUENCE:266
+#EXT-X-DISCONTINUITY-SEQUENCE:3
+#EXT-X-PROGRAM-DATE-TIME:2019-02-14T02:13:36.106Z
+#EXT-X-MAP:URI="init.mp4"
+#EXTINF:4.00008,
+fileSequence266.mp4
+#EXT-X-PART:DURATION=0.33334,URI="filePart267.0.mp4",INDEPENDENT=YES
+#EXT-X-PART:DURATION=0.33334,URI="filePart267.1.mp4"
+#EXT-X-PART:DURATION=0.33334,URI="filePart267.2.mp4",GAP=YES
+#EXTINF:1.00002,
+fileSequence267.mp4
+#EXT-X-DISCONTINUITY
+#EXT-X-PROGRAM-DATE-TIME:2019-02-14T02:13:41.106Z
+#EXT-X-PART:DURATION=0.33334,URI="filePart268.mp4",BYTERANGE="20000@0"
+#EXT-X-PART:DURATION=0.33334,URI="filePart268.mp4",BYTERANGE="23000"
+#EXT-X-PRELOAD-HINT:TYPE=PART,URI="filePart268.mp4",BYTERANGE-START=43000
+#EXT-X-RENDITION-REPORT:URI="../1M/waitForMSN.php",LAST-MSN=268,LAST-PART=1
+"#;
+
+    #[test]
+    fn low_latency_tags() {
+        let parsed = parse_media_playlist(LOW_LATENCY_PLAYLIST).unwrap();
+        assert_eq!(parsed.playlist.segments().len(), 2);
+        assert_eq!(parsed.discontinuity_seq, 3);
+        assert_eq!(parsed.skipped_segments, 0);
+        assert_eq!(
+            parsed.server_control,
+            ServerControl {
+                can_skip_until: Some(12.0),
+                can_skip_dateranges: false,
+                hold_back: None,
+                part_hold_back: Some(1.0),
+                can_block_reload: true,
+            }
+        );
+        assert_eq!(parsed.part_target, Some(0.33334));
+
+        let parts = parsed
+            .parts
+            .iter()
+            .map(|p| (p.segment_index, p.uri.as_str(), p.independent, p.byte_range, p.gap))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            parts,
+            [
+                (1, "filePart267.0.mp4", true, None, false),
+                (1, "filePart267.1.mp4", false, None, false),
+                (1, "filePart267.2.mp4", false, None, true),
+                (2, "filePart268.mp4", false, Some((20000, Some(0))), false),
+                (2, "filePart268.mp4", false, Some((23000, None)), false),
+            ]
+        );
+        assert_eq!(
+            parsed.preload_hints,
+            [PreloadHint {
+                hint_type: "PART".to_owned(),
+                uri: "filePart268.mp4".to_owned(),
+                byte_range_start: 43000,
+                byte_range_length: None,
+            }]
+        );
+
+        // The tags of the segment being produced are not followed by its URI
+        let next = &parsed.next_segment_tags;
+        assert!(next.discontinuity);
+        assert!(next.key.is_none() && next.map.is_none());
+        assert_eq!(
+            next.program_date_time.as_deref(),
+            Some("2019-02-14T02:13:41.106Z")
+        );
+    }
+
+    #[test]
+    fn playlist_delta_update() {
+        let m3u8 = r#"#EXTM3U
+#EXT-X-TARGETDURATION:4
+#EXT-X-VERSION:6
+#EXT-X-SERVER-CONTROL:CAN-SKIP-UNTIL=24.0,CAN-SKIP-DATERANGES=YES
+#EXT-X-MEDIA-SEQUENCE:266
+#EXT-X-SKIP:SKIPPED-SEGMENTS=3
+#EXTINF:4.00008,
+fileSequence269.mp4
+"#;
+        let parsed = parse_media_playlist(m3u8).unwrap();
+        assert_eq!(parsed.skipped_segments, 3);
+        assert!(parsed.server_control.can_skip_dateranges);
+        assert_eq!(parsed.playlist.segments().len(), 1);
+    }
+
+    #[test]
+    fn byte_ranges_without_offsets() {
+        // The sub-range preceding the first segment has left the playlist
+        let m3u8 = "#EXTM3U\n#EXT-X-TARGETDURATION:4\n#EXT-X-MEDIA-SEQUENCE:10\n\
+                    #EXT-X-BYTERANGE:1000\n#EXTINF:4,\nmain.ts\n\
+                    #EXT-X-BYTERANGE:2000@5000\n#EXTINF:4,\nmain.ts\n\
+                    #EXTINF:4,\nother.ts\n";
+        let parsed = parse_media_playlist(m3u8).unwrap();
+        assert_eq!(
+            parsed.byte_ranges,
+            [Some((1000, None)), Some((2000, Some(5000))), None]
+        );
+    }
+
+    #[test]
+    fn attribute_lists() {
+        let attrs = r#"URI="a,b.mp4", DURATION=0.5,TYPE=PART"#;
+        assert_eq!(
+            parse_attributes(attrs).unwrap(),
+            [("URI", "\"a,b.mp4\""), ("DURATION", "0.5"), ("TYPE", "PART")]
+        );
+        assert!(parse_attributes(r#"URI="a.mp4"#).is_err());
+        assert!(parse_attributes("DURATION").is_err());
+
+        assert!(parse_part("URI=\"a.mp4\"", 0).is_err());
+        assert!(parse_preload_hint("URI=\"a.mp4\"").is_err());
+    }
+}
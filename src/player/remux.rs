@@ -0,0 +1,845 @@
+//! Conversion of MPEG-2 TS and packed audio segments into fragmented MP4.
+//!
+//! Unlike `mse_fmp4::mpeg2_ts`, this accepts segments carrying only one of the H.264 and AAC
+//! streams (e.g., the segments of a video variant whose audio is delivered by
+//! an alternate rendition), and ignores the other elementary streams.
+use std::iter;
+use std::mem;
+use mse_fmp4::fmp4::{AacSampleEntry, AvcConfigurationBox, AvcSampleEntry, InitializationSegment,
+                     MediaDataBox, MediaSegment, Mp4Box, Mpeg4EsDescriptorBox, Sample,
+                     SampleEntry, SampleFlags, TrackBox, TrackExtendsBox, TrackFragmentBox};
+use mse_fmp4::avc::AvcDecoderConfigurationRecord;
+use mse_fmp4::io::WriteTo;
+
+use {Error, ErrorKind, Result};
+use super::aac::{self, AdtsHeader};
+use super::h264::{self, SpsSummary};
+use super::ts::{self, TsSegment};
+
+/// Resolution of the timestamps in MPEG-2 TS.
+const TIMESCALE: u32 = 90_000;
+
+/// Upper bound of the 33-bit timestamps in MPEG-2 TS.
+const MAX_TIMESTAMP: u64 = 1 << 33;
+
+/// Duration of the last video sample if there is no other sample to infer it from (30 fps).
+const DEFAULT_AVC_SAMPLE_DURATION: u32 = TIMESCALE / 30;
+
+/// Converts the 33-bit timestamps in a segment into ones relative to the first timestamp seen.
+///
+/// The wraparound is taken into account, so the results may be negative.
+#[derive(Debug, Default)]
+struct Timeline {
+    reference: Option<u64>,
+}
+impl Timeline {
+    fn relative(&mut self, timestamp: u64) -> i64 {
+        let reference = *self.reference.get_or_insert(timestamp);
+        let delta = timestamp as i64 - reference as i64;
+        if delta < -(MAX_TIMESTAMP as i64 / 2) {
+            delta + MAX_TIMESTAMP as i64
+        } else if delta > MAX_TIMESTAMP as i64 / 2 {
+            delta - MAX_TIMESTAMP as i64
+        } else {
+            delta
+        }
+    }
+}
+
+#[derive(Debug)]
+struct AvcTrack {
+    sps: SpsSummary,
+    configuration: AvcDecoderConfigurationRecord,
+
+    /// Decoding time stamps of the samples (relative to the `Timeline`).
+    decode_times: Vec<i64>,
+
+    samples: Vec<Sample>,
+    data: Vec<u8>,
+}
+impl AvcTrack {
+    fn duration(&self) -> Result<u32> {
+        let mut duration: u32 = 0;
+        for sample in &self.samples {
+            let sample_duration = track_assert_some!(sample.duration, ErrorKind::InvalidInput);
+            duration = track_assert_some!(
+                duration.checked_add(sample_duration),
+                ErrorKind::InvalidInput
+            );
+        }
+        Ok(duration)
+    }
+
+    fn start_time(&self) -> i32 {
+        self.samples
+            .first()
+            .and_then(|s| s.composition_time_offset)
+            .unwrap_or(0)
+    }
+}
+
+#[derive(Debug)]
+struct AacTrack {
+    header: AdtsHeader,
+
+    /// Presentation time stamp of the first frame (relative to the `Timeline`).
+    start_time: Option<i64>,
+
+    /// The head of a frame continued in the next PES packet.
+    partial_frame: Vec<u8>,
+
+    samples: Vec<Sample>,
+    data: Vec<u8>,
+}
+impl AacTrack {
+    fn new(header: AdtsHeader) -> Self {
+        AacTrack {
+            header,
+            start_time: None,
+            partial_frame: Vec::new(),
+            samples: Vec::new(),
+            data: Vec::new(),
+        }
+    }
+
+    fn duration(&self) -> u32 {
+        aac::SAMPLES_IN_FRAME * self.samples.len() as u32
+    }
+
+    /// Returns the start time in the timescale of this track.
+    fn base_media_decode_time(&self, origin: i64) -> u64 {
+        let start_time = self.start_time.map_or(0, |t| (t - origin).max(0) as u64);
+        start_time * u64::from(self.header.sampling_frequency_hz()) / u64::from(TIMESCALE)
+    }
+
+    /// Appends the ADTS frames in `data`.
+    ///
+    /// A frame may be split across PES packets, so a trailing incomplete frame is kept
+    /// until the rest is given.
+    fn push_frames(&mut self, data: &[u8]) -> Result<()> {
+        let mut buf = mem::take(&mut self.partial_frame);
+        buf.extend_from_slice(data);
+
+        let mut frames = &buf[..];
+        while !frames.is_empty() {
+            if aac::frame_len(frames).is_none_or(|len| len > frames.len()) {
+                break;
+            }
+            let header = track!(AdtsHeader::parse(frames))?;
+            let raw_data_blocks = &frames[header.header_len..header.frame_len];
+            self.samples.push(Sample {
+                duration: None,
+                size: Some(raw_data_blocks.len() as u32),
+                flags: None,
+                composition_time_offset: None,
+            });
+            self.data.extend_from_slice(raw_data_blocks);
+            frames = &frames[header.frame_len..];
+        }
+        self.partial_frame = frames.to_owned();
+        Ok(())
+    }
+}
+
+/// Converts the H.264 and AAC streams in `segment` into an initialization segment and
+/// a (serialized) media segment.
+///
+/// The tracks start at their first timestamps relative to the earliest one in `segment`.
+pub fn ts_to_fmp4(segment: &TsSegment) -> Result<(InitializationSegment, Vec<u8>)> {
+    let tracks = track!(read_tracks(segment))?;
+    let initialization_segment = track!(make_initialization_segment(&tracks.avc, &tracks.aac))?;
+    let media_segment = track!(make_media_segment(tracks.avc, tracks.aac))?;
+    let media_segment = track!(write_media_segment(
+        &media_segment,
+        &tracks.base_media_decode_times
+    ))?;
+    Ok((initialization_segment, media_segment))
+}
+
+/// Tracks read from a TS segment.
+#[derive(Debug)]
+struct Tracks {
+    avc: Option<AvcTrack>,
+    aac: Option<AacTrack>,
+
+    /// Start times of the tracks (in the order of `avc` and `aac`) in their timescales.
+    base_media_decode_times: Vec<u64>,
+}
+
+fn read_tracks(segment: &TsSegment) -> Result<Tracks> {
+    let mut timeline = Timeline::default();
+    let mut avc_track: Option<AvcTrack> = None;
+    let mut aac_track: Option<AacTrack> = None;
+    for pes in &segment.pes_packets {
+        match segment.stream_type(pes.pid) {
+            Some(ts::STREAM_TYPE_H264) => {
+                let pts = track_assert_some!(track!(pes.pts())?, ErrorKind::InvalidInput);
+                let dts = track!(pes.dts())?.unwrap_or(pts);
+                let payload = track!(pes.payload())?;
+                let nal_units = h264::nal_units(payload);
+                if avc_track.is_none() {
+                    avc_track = Some(track!(new_avc_track(&nal_units))?);
+                }
+
+                let track = avc_track.as_mut().expect("Never fails");
+                let decode_time = timeline.relative(dts);
+                track.decode_times.push(decode_time);
+
+                let prev_data_len = track.data.len();
+                for nal_unit in nal_units {
+                    track
+                        .data
+                        .extend_from_slice(&(nal_unit.len() as u32).to_be_bytes());
+                    track.data.extend_from_slice(nal_unit);
+                }
+                track.samples.push(Sample {
+                    duration: None,
+                    size: Some((track.data.len() - prev_data_len) as u32),
+                    flags: None,
+                    composition_time_offset: Some((timeline.relative(pts) - decode_time) as i32),
+                });
+            }
+            Some(ts::STREAM_TYPE_ADTS_AAC) => {
+                let payload = track!(pes.payload())?;
+                if aac_track.is_none() {
+                    aac_track = Some(AacTrack::new(track!(AdtsHeader::parse(payload))?));
+                }
+                let track = aac_track.as_mut().expect("Never fails");
+                if track.start_time.is_none() {
+                    if let Some(pts) = track!(pes.pts())? {
+                        track.start_time = Some(timeline.relative(pts));
+                    }
+                }
+                track!(track.push_frames(payload))?;
+            }
+            _ => {}
+        }
+    }
+    track_assert!(
+        avc_track.is_some() || aac_track.is_some(),
+        ErrorKind::InvalidInput,
+        "No H.264 or AAC stream in the TS segment"
+    );
+
+    let origin = avc_track
+        .as_ref()
+        .and_then(|t| t.decode_times.first().cloned())
+        .into_iter()
+        .chain(aac_track.as_ref().and_then(|t| t.start_time))
+        .min()
+        .unwrap_or(0);
+    let mut base_media_decode_times = Vec::new();
+    if let Some(ref mut track) = avc_track {
+        track!(set_avc_sample_durations(track))?;
+        base_media_decode_times.push((track.decode_times[0] - origin) as u64);
+    }
+    if let Some(ref track) = aac_track {
+        base_media_decode_times.push(track.base_media_decode_time(origin));
+    }
+    Ok(Tracks {
+        avc: avc_track,
+        aac: aac_track,
+        base_media_decode_times,
+    })
+}
+
+/// Converts a packed audio segment (a sequence of ADTS frames,
+/// optionally preceded by ID3 tags) into fMP4 segments.
+pub fn packed_audio_to_fmp4(data: &[u8]) -> Result<(InitializationSegment, Vec<u8>)> {
+    let mut frames = data;
+    while frames.len() >= 10 && &frames[..3] == b"ID3" {
+        let tag_size = frames[6..10]
+            .iter()
+            .fold(0, |size, &b| (size << 7) | usize::from(b & 0x7F));
+        let footer_size = if frames[5] & 0b0001_0000 != 0 { 10 } else { 0 };
+        let tag_len = 10 + tag_size + footer_size;
+        track_assert!(frames.len() >= tag_len, ErrorKind::InvalidInput, "Truncated ID3 tag");
+        frames = &frames[tag_len..];
+    }
+
+    let mut track = AacTrack::new(track!(AdtsHeader::parse(frames))?);
+    track!(track.push_frames(frames))?;
+    let aac_track = Some(track);
+    let initialization_segment = track!(make_initialization_segment(&None, &aac_track))?;
+    let media_segment = track!(make_media_segment(None, aac_track))?;
+    let media_segment = track!(write_media_segment(&media_segment, &[0]))?;
+    Ok((initialization_segment, media_segment))
+}
+
+fn new_avc_track(nal_units: &[&[u8]]) -> Result<AvcTrack> {
+    let sps = nal_units
+        .iter()
+        .find(|n| h264::nal_unit_type(n) == h264::NAL_UNIT_TYPE_SPS);
+    let pps = nal_units
+        .iter()
+        .find(|n| h264::nal_unit_type(n) == h264::NAL_UNIT_TYPE_PPS);
+    let sps = track_assert_some!(sps, ErrorKind::InvalidInput, "No SPS in the first access unit");
+    let pps = track_assert_some!(pps, ErrorKind::InvalidInput, "No PPS in the first access unit");
+    let summary = track!(SpsSummary::parse(sps))?;
+    Ok(AvcTrack {
+        configuration: AvcDecoderConfigurationRecord {
+            profile_idc: summary.profile_idc,
+            constraint_set_flag: summary.constraint_set_flag,
+            level_idc: summary.level_idc,
+            sequence_parameter_set: sps.to_vec(),
+            picture_parameter_set: pps.to_vec(),
+        },
+        sps: summary,
+        decode_times: Vec::new(),
+        samples: Vec::new(),
+        data: Vec::new(),
+    })
+}
+
+/// Sets the duration of each sample to the gap to the decoding time stamp of the next sample.
+///
+/// The last sample is given the same duration as the previous one.
+fn set_avc_sample_durations(track: &mut AvcTrack) -> Result<()> {
+    let mut last_duration = DEFAULT_AVC_SAMPLE_DURATION;
+    for (i, w) in track.decode_times.windows(2).enumerate() {
+        let duration = w[1] - w[0];
+        track_assert!(
+            duration >= 0 && duration <= i64::from(u32::MAX),
+            ErrorKind::InvalidInput,
+            "Decoding time stamps are not monotonic: {} -> {}",
+            w[0],
+            w[1]
+        );
+        last_duration = duration as u32;
+        track.samples[i].duration = Some(last_duration);
+    }
+    if let Some(sample) = track.samples.last_mut() {
+        sample.duration = Some(last_duration);
+    }
+    Ok(())
+}
+
+fn make_initialization_segment(
+    avc_track: &Option<AvcTrack>,
+    aac_track: &Option<AacTrack>,
+) -> Result<InitializationSegment> {
+    let mut segment = InitializationSegment::default();
+    let (timescale, duration) = if let Some(ref avc_track) = *avc_track {
+        (TIMESCALE, track!(avc_track.duration())?)
+    } else {
+        let aac_track = aac_track.as_ref().expect("Never fails");
+        (aac_track.header.sampling_frequency_hz(), aac_track.duration())
+    };
+    segment.moov_box.mvhd_box.timescale = timescale;
+    segment.moov_box.mvhd_box.duration = duration;
+    segment.moov_box.mvex_box.mehd_box.fragment_duration = duration;
+
+    if let Some(ref avc_track) = *avc_track {
+        let duration = track!(avc_track.duration())?;
+        let mut track = TrackBox::new(true);
+        track.tkhd_box.width = (avc_track.sps.width as u32) << 16;
+        track.tkhd_box.height = (avc_track.sps.height as u32) << 16;
+        track.tkhd_box.duration = duration;
+        track.edts_box.elst_box.media_time = avc_track.start_time();
+        track.mdia_box.mdhd_box.timescale = TIMESCALE;
+        track.mdia_box.mdhd_box.duration = duration;
+        track
+            .mdia_box
+            .minf_box
+            .stbl_box
+            .stsd_box
+            .sample_entries
+            .push(SampleEntry::Avc(AvcSampleEntry {
+                width: avc_track.sps.width as u16,
+                height: avc_track.sps.height as u16,
+                avcc_box: AvcConfigurationBox {
+                    configuration: avc_track.configuration.clone(),
+                },
+            }));
+        segment.moov_box.trak_boxes.push(track);
+        segment
+            .moov_box
+            .mvex_box
+            .trex_boxes
+            .push(TrackExtendsBox::new(true));
+    }
+
+    if let Some(ref aac_track) = *aac_track {
+        let mut track = TrackBox::new(false);
+        track.tkhd_box.duration = aac_track.duration();
+        track.mdia_box.mdhd_box.timescale = aac_track.header.sampling_frequency_hz();
+        track.mdia_box.mdhd_box.duration = aac_track.duration();
+        track
+            .mdia_box
+            .minf_box
+            .stbl_box
+            .stsd_box
+            .sample_entries
+            .push(SampleEntry::Aac(AacSampleEntry {
+                esds_box: Mpeg4EsDescriptorBox {
+                    profile: aac_track.header.profile,
+                    frequency: aac_track.header.sampling_frequency,
+                    channel_configuration: aac_track.header.channel_configuration,
+                },
+            }));
+        segment.moov_box.trak_boxes.push(track);
+        segment
+            .moov_box
+            .mvex_box
+            .trex_boxes
+            .push(TrackExtendsBox::new(false));
+    }
+    Ok(segment)
+}
+
+fn make_media_segment(
+    avc_track: Option<AvcTrack>,
+    aac_track: Option<AacTrack>,
+) -> Result<MediaSegment> {
+    let mut segment = MediaSegment::default();
+    let mut mdat_data = Vec::new();
+
+    if let Some(avc_track) = avc_track {
+        let mut traf = TrackFragmentBox::new(true);
+        traf.tfhd_box.default_sample_flags = Some(SampleFlags {
+            is_leading: 0,
+            sample_depends_on: 1,
+            sample_is_depdended_on: 0,
+            sample_has_redundancy: 0,
+            sample_padding_value: 0,
+            sample_is_non_sync_sample: true,
+            sample_degradation_priority: 0,
+        });
+        traf.trun_box.data_offset = Some(0); // dummy
+        traf.trun_box.first_sample_flags = Some(SampleFlags {
+            is_leading: 0,
+            sample_depends_on: 2,
+            sample_is_depdended_on: 0,
+            sample_has_redundancy: 0,
+            sample_padding_value: 0,
+            sample_is_non_sync_sample: false,
+            sample_degradation_priority: 0,
+        });
+        traf.trun_box.samples = avc_track.samples;
+        segment.moof_box.traf_boxes.push(traf);
+        mdat_data.push(avc_track.data);
+    }
+
+    if let Some(aac_track) = aac_track {
+        let mut traf = TrackFragmentBox::new(false);
+        traf.tfhd_box.default_sample_duration = Some(aac::SAMPLES_IN_FRAME);
+        traf.trun_box.data_offset = Some(0); // dummy
+        traf.trun_box.samples = aac_track.samples;
+        segment.moof_box.traf_boxes.push(traf);
+        mdat_data.push(aac_track.data);
+    }
+
+    // Each `trun` refers to its own `mdat` that follows the `moof`
+    let mut offset = track!(segment.moof_box.box_size().map_err(Error::from))? as i32;
+    for (traf, data) in segment.moof_box.traf_boxes.iter_mut().zip(mdat_data.iter()) {
+        traf.trun_box.data_offset = Some(offset + 8);
+        offset += 8 + data.len() as i32;
+    }
+    for data in mdat_data {
+        segment.mdat_boxes.push(MediaDataBox { data });
+    }
+    Ok(segment)
+}
+
+/// Serializes `segment`, setting the `baseMediaDecodeTime` of its track fragments.
+///
+/// `mse_fmp4` always writes zero to `tfdt`, so the field is overwritten after serialization.
+fn write_media_segment(
+    segment: &MediaSegment,
+    base_media_decode_times: &[u64],
+) -> Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    track!(segment.write_to(&mut buf))?;
+
+    let mut tfdt_offsets = Vec::new();
+    let moof_size = read_box_header(&buf, 0).0;
+    for (traf, traf_size) in child_boxes(&buf, 8, moof_size, b"traf") {
+        tfdt_offsets.extend(child_boxes(&buf, traf + 8, traf + traf_size, b"tfdt").map(|x| x.0));
+    }
+    track_assert_eq!(
+        tfdt_offsets.len(),
+        base_media_decode_times.len(),
+        ErrorKind::Other
+    );
+    for (offset, &time) in tfdt_offsets.into_iter().zip(base_media_decode_times) {
+        track_assert!(time <= u64::from(u32::MAX), ErrorKind::InvalidInput);
+        let value = offset + 12; // Following the box header, version and flags
+        buf[value..value + 4].copy_from_slice(&(time as u32).to_be_bytes());
+    }
+    Ok(buf)
+}
+
+fn read_box_header(buf: &[u8], offset: usize) -> (usize, &[u8]) {
+    let size = buf[offset..offset + 4]
+        .iter()
+        .fold(0, |size, &b| (size << 8) | usize::from(b));
+    (size, &buf[offset + 4..offset + 8])
+}
+
+/// Returns the offsets and sizes of the boxes of type `kind` in `buf[start..end]`.
+fn child_boxes<'a>(
+    buf: &'a [u8],
+    start: usize,
+    end: usize,
+    kind: &'a [u8; 4],
+) -> impl Iterator<Item = (usize, usize)> + 'a {
+    let mut offset = start;
+    iter::from_fn(move || {
+        while offset + 8 <= end {
+            let (size, box_type) = read_box_header(buf, offset);
+            let child = offset;
+            offset += size.max(8);
+            if box_type == kind {
+                return Some((child, size));
+            }
+        }
+        None
+    })
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use super::super::ts::{ElementaryStream, PesPacket};
+
+    pub const VIDEO_PID: u16 = 0x100;
+    pub const AUDIO_PID: u16 = 0x101;
+
+    // Main profile 1280x720 (`mse_fmp4` cannot write configuration records of High profile)
+    const SPS: &[u8] = &[0x67, 0x4d, 0x40, 0x1f, 0xed, 0x00, 0xa0, 0x0b, 0x72];
+    const PPS: &[u8] = &[0x68, 0xeb, 0xe3, 0xcb, 0x22, 0xc0];
+    const IDR_SLICE: &[u8] = &[0x65, 0x88, 0x84, 0x00, 0x33, 0xff];
+    const NON_IDR_SLICE: &[u8] = &[0x41, 0x9a, 0x21, 0x6c, 0x42];
+
+    fn encode_timestamp(prefix: u8, timestamp: u64) -> [u8; 5] {
+        [
+            (prefix << 4) | ((timestamp >> 29) as u8 & 0b1110) | 1,
+            (timestamp >> 22) as u8,
+            ((timestamp >> 14) as u8 & 0b1111_1110) | 1,
+            (timestamp >> 7) as u8,
+            ((timestamp << 1) as u8 & 0b1111_1110) | 1,
+        ]
+    }
+
+    pub fn pes(pid: u16, pts: Option<u64>, dts: Option<u64>, payload: &[u8]) -> PesPacket {
+        let stream_id = if pid == VIDEO_PID { 0xE0 } else { 0xC0 };
+        let mut bytes = vec![0, 0, 1, stream_id, 0, 0, 0x80];
+        match (pts, dts) {
+            (Some(pts), Some(dts)) => {
+                bytes.extend_from_slice(&[0xC0, 10]);
+                bytes.extend_from_slice(&encode_timestamp(0b0011, pts));
+                bytes.extend_from_slice(&encode_timestamp(0b0001, dts));
+            }
+            (Some(pts), None) => {
+                bytes.extend_from_slice(&[0x80, 5]);
+                bytes.extend_from_slice(&encode_timestamp(0b0010, pts));
+            }
+            _ => bytes.extend_from_slice(&[0x00, 0]),
+        }
+        bytes.extend_from_slice(payload);
+        PesPacket { pid, bytes }
+    }
+
+    fn access_unit(nal_units: &[&[u8]]) -> Vec<u8> {
+        let mut buf = Vec::new();
+        for nal_unit in nal_units {
+            buf.extend_from_slice(&[0, 0, 0, 1]);
+            buf.extend_from_slice(nal_unit);
+        }
+        buf
+    }
+
+    pub fn video_pes(pts: u64, dts: u64, is_idr: bool) -> PesPacket {
+        let payload = if is_idr {
+            access_unit(&[SPS, PPS, IDR_SLICE])
+        } else {
+            access_unit(&[NON_IDR_SLICE])
+        };
+        pes(VIDEO_PID, Some(pts), Some(dts), &payload)
+    }
+
+    /// Returns `frames` ADTS frames (AAC-LC, 44.1kHz and stereo) with `payload_len` bytes each.
+    pub fn adts_frames(frames: usize, payload_len: usize) -> Vec<u8> {
+        let frame_len = 7 + payload_len;
+        let mut buf = Vec::new();
+        for i in 0..frames {
+            buf.extend_from_slice(&[
+                0xFF,
+                0xF1,
+                0x50,
+                0x80 | (frame_len >> 11) as u8,
+                (frame_len >> 3) as u8,
+                ((frame_len & 0b111) << 5) as u8 | 0x1F,
+                0xFC,
+            ]);
+            buf.extend((0..payload_len).map(|_| i as u8));
+        }
+        buf
+    }
+
+    pub fn ts_segment(pes_packets: Vec<PesPacket>) -> TsSegment {
+        TsSegment {
+            streams: vec![
+                ElementaryStream {
+                    pid: VIDEO_PID,
+                    stream_type: ts::STREAM_TYPE_H264,
+                },
+                ElementaryStream {
+                    pid: AUDIO_PID,
+                    stream_type: ts::STREAM_TYPE_ADTS_AAC,
+                },
+            ],
+            pes_packets,
+            ..TsSegment::default()
+        }
+    }
+
+    /// Returns the `baseMediaDecodeTime` of each track fragment in `media_segment`.
+    fn base_media_decode_times(media_segment: &[u8]) -> Vec<u32> {
+        let moof_size = read_box_header(media_segment, 0).0;
+        let mut times = Vec::new();
+        for (traf, traf_size) in child_boxes(media_segment, 8, moof_size, b"traf") {
+            for (tfdt, _) in child_boxes(media_segment, traf + 8, traf + traf_size, b"tfdt") {
+                let value = &media_segment[tfdt + 12..tfdt + 16];
+                times.push(value.iter().fold(0, |t, &b| (t << 8) | u32::from(b)));
+            }
+        }
+        times
+    }
+
+    /// Duration, size and composition time offset of a sample.
+    type SampleFields = (u32, u32, i32);
+
+    /// Returns the data offset and the samples of the `trun` box of each track fragment
+    /// in `media_segment`.
+    ///
+    /// Durations not given by `trun` are taken from `tfhd`.
+    fn track_runs(media_segment: &[u8]) -> Vec<(usize, Vec<SampleFields>)> {
+        let read_u32 = |offset: usize| {
+            media_segment[offset..offset + 4]
+                .iter()
+                .fold(0, |n, &b| (n << 8) | u32::from(b))
+        };
+        let moof_size = read_box_header(media_segment, 0).0;
+        let mut runs = Vec::new();
+        for (traf, traf_size) in child_boxes(media_segment, 8, moof_size, b"traf") {
+            let mut default_duration = 0;
+            for (tfhd, _) in child_boxes(media_segment, traf + 8, traf + traf_size, b"tfhd") {
+                let flags = read_u32(tfhd + 8) & 0xFF_FFFF;
+                if flags & 0x08 != 0 {
+                    // Following the track ID, base data offset and sample description index
+                    let offset = tfhd + 16
+                        + if flags & 0x01 != 0 { 8 } else { 0 }
+                        + if flags & 0x02 != 0 { 4 } else { 0 };
+                    default_duration = read_u32(offset);
+                }
+            }
+            for (trun, _) in child_boxes(media_segment, traf + 8, traf + traf_size, b"trun") {
+                let flags = read_u32(trun + 8) & 0xFF_FFFF;
+                let sample_count = read_u32(trun + 12);
+                let mut offset = trun + 16;
+                let mut data_offset = 0;
+                if flags & 0x01 != 0 {
+                    data_offset = read_u32(offset) as usize;
+                    offset += 4;
+                }
+                if flags & 0x04 != 0 {
+                    offset += 4;
+                }
+                let mut samples = Vec::new();
+                for _ in 0..sample_count {
+                    let mut fields = [default_duration, 0, 0, 0];
+                    for (i, field) in fields.iter_mut().enumerate() {
+                        if flags & (0x100 << i) != 0 {
+                            *field = read_u32(offset);
+                            offset += 4;
+                        }
+                    }
+                    samples.push((fields[0], fields[1], fields[3] as i32));
+                }
+                runs.push((data_offset, samples));
+            }
+        }
+        runs
+    }
+
+    #[test]
+    fn avc_configuration_and_samples() {
+        let segment = ts_segment(vec![
+            video_pes(90_000, 90_000, true),
+            video_pes(93_000, 93_000, false),
+        ]);
+        let tracks = read_tracks(&segment).unwrap();
+        let avc = tracks.avc.unwrap();
+        assert_eq!(avc.configuration.profile_idc, 77);
+        assert_eq!(avc.configuration.level_idc, 31);
+        assert_eq!(avc.configuration.sequence_parameter_set, SPS);
+        assert_eq!(avc.configuration.picture_parameter_set, PPS);
+        assert_eq!((avc.sps.width, avc.sps.height), (1280, 720));
+
+        // The NAL units are prefixed with their lengths instead of start codes
+        let mut data = Vec::new();
+        for nal_unit in &[SPS, PPS, IDR_SLICE, NON_IDR_SLICE] {
+            data.extend_from_slice(&(nal_unit.len() as u32).to_be_bytes());
+            data.extend_from_slice(nal_unit);
+        }
+        assert_eq!(avc.data, data);
+        let sizes = avc.samples.iter().map(|s| s.size).collect::<Vec<_>>();
+        assert_eq!(sizes, [Some(4 * 3 + 21), Some(4 + 5)]);
+    }
+
+    #[test]
+    fn avc_sample_timing_with_reordered_frames() {
+        // I P B B in decoding order
+        let segment = ts_segment(vec![
+            video_pes(93_000, 90_000, true),
+            video_pes(102_000, 93_000, false),
+            video_pes(96_000, 96_000, false),
+            video_pes(99_000, 99_000, false),
+        ]);
+        let tracks = read_tracks(&segment).unwrap();
+        let samples = tracks.avc.unwrap().samples;
+        let durations = samples.iter().map(|s| s.duration).collect::<Vec<_>>();
+        assert_eq!(durations, [Some(3000); 4]);
+        let offsets = samples
+            .iter()
+            .map(|s| s.composition_time_offset)
+            .collect::<Vec<_>>();
+        assert_eq!(offsets, [Some(3000), Some(9000), Some(0), Some(0)]);
+        assert_eq!(tracks.base_media_decode_times, [0]);
+    }
+
+    #[test]
+    fn avc_sample_timing_across_timestamp_wraparound() {
+        let segment = ts_segment(vec![
+            video_pes(MAX_TIMESTAMP - 3000, MAX_TIMESTAMP - 3000, true),
+            video_pes(0, 0, false),
+        ]);
+        let samples = read_tracks(&segment).unwrap().avc.unwrap().samples;
+        let durations = samples.iter().map(|s| s.duration).collect::<Vec<_>>();
+        assert_eq!(durations, [Some(3000), Some(3000)]);
+    }
+
+    #[test]
+    fn single_avc_sample_has_default_duration() {
+        let segment = ts_segment(vec![video_pes(90_000, 90_000, true)]);
+        let samples = read_tracks(&segment).unwrap().avc.unwrap().samples;
+        assert_eq!(samples[0].duration, Some(DEFAULT_AVC_SAMPLE_DURATION));
+    }
+
+    #[test]
+    fn adts_frames_split_across_pes_packets() {
+        let frames = adts_frames(3, 100);
+        let segment = ts_segment(vec![
+            pes(AUDIO_PID, Some(90_000), None, &frames[..150]),
+            pes(AUDIO_PID, None, None, &frames[150..152]),
+            pes(AUDIO_PID, Some(94_180), None, &frames[152..]),
+        ]);
+        let aac = read_tracks(&segment).unwrap().aac.unwrap();
+        let sizes = aac.samples.iter().map(|s| s.size).collect::<Vec<_>>();
+        assert_eq!(sizes, [Some(100); 3]);
+        assert_eq!(aac.data[..100], [0; 100][..]);
+        assert_eq!(aac.data[100..200], [1; 100][..]);
+        assert_eq!(aac.data[200..], [2; 100][..]);
+        assert!(aac.partial_frame.is_empty());
+        assert_eq!(aac.duration(), 3 * 1024);
+    }
+
+    #[test]
+    fn audio_only_segment() {
+        let frames = adts_frames(2, 10);
+        let segment = ts_segment(vec![pes(AUDIO_PID, Some(1_000_000), None, &frames)]);
+        let (initialization_segment, media_segment) = ts_to_fmp4(&segment).unwrap();
+        let moov = &initialization_segment.moov_box;
+        assert_eq!(moov.trak_boxes.len(), 1);
+        assert_eq!(moov.mvhd_box.timescale, 44_100);
+        assert_eq!(moov.mvhd_box.duration, 2 * 1024);
+        assert_eq!(base_media_decode_times(&media_segment), [0]);
+    }
+
+    #[test]
+    fn tracks_start_relative_to_the_earliest_one() {
+        // The audio starts 20ms after the video
+        let segment = ts_segment(vec![
+            video_pes(90_000, 90_000, true),
+            pes(AUDIO_PID, Some(91_800), None, &adts_frames(1, 10)),
+            video_pes(93_000, 93_000, false),
+        ]);
+        let (_, media_segment) = ts_to_fmp4(&segment).unwrap();
+        assert_eq!(base_media_decode_times(&media_segment), [0, 882]);
+
+        // The video starts 20ms after the audio
+        let segment = ts_segment(vec![
+            pes(AUDIO_PID, Some(88_200), None, &adts_frames(1, 10)),
+            video_pes(90_000, 90_000, true),
+            video_pes(93_000, 93_000, false),
+        ]);
+        let (_, media_segment) = ts_to_fmp4(&segment).unwrap();
+        assert_eq!(base_media_decode_times(&media_segment), [1800, 0]);
+    }
+
+    #[test]
+    fn packed_audio_segment() {
+        let mut data = b"ID3\x04\x00\x00\x00\x00\x00\x02\x00\x00".to_vec();
+        data.extend(adts_frames(4, 10));
+        let (initialization_segment, media_segment) = packed_audio_to_fmp4(&data).unwrap();
+        assert_eq!(initialization_segment.moov_box.mvhd_box.duration, 4 * 1024);
+        assert_eq!(base_media_decode_times(&media_segment), [0]);
+    }
+
+    #[test]
+    fn ts_segment_is_remuxed_into_fmp4() {
+        // I P B B in decoding order, and the audio starting 20ms after the video
+        let frames = adts_frames(2, 10);
+        let segment = ts_segment(vec![
+            video_pes(93_000, 90_000, true),
+            pes(AUDIO_PID, Some(91_800), None, &frames),
+            video_pes(102_000, 93_000, false),
+            video_pes(96_000, 96_000, false),
+            video_pes(99_000, 99_000, false),
+        ]);
+        let data = ts::tests::mux(&segment);
+        let segment = TsSegment::demux(&data).unwrap();
+        let (initialization_segment, media_segment) = ts_to_fmp4(&segment).unwrap();
+
+        let mut init = Vec::new();
+        initialization_segment.write_to(&mut init).unwrap();
+        let timescales = initialization_segment
+            .moov_box
+            .trak_boxes
+            .iter()
+            .map(|t| t.mdia_box.mdhd_box.timescale)
+            .collect::<Vec<_>>();
+        assert_eq!(timescales, [90_000, 44_100]);
+
+        assert_eq!(base_media_decode_times(&media_segment), [0, 882]);
+        let runs = track_runs(&media_segment);
+        let video_samples = [
+            (3000, 4 * 3 + 21, 3000),
+            (3000, 4 + 5, 9000),
+            (3000, 4 + 5, 0),
+            (3000, 4 + 5, 0),
+        ];
+        assert_eq!(runs[0].1, video_samples);
+        assert_eq!(runs[1].1, [(1024, 10, 0), (1024, 10, 0)]);
+
+        // Each track has its samples in its own `mdat`, at the offset relative to `moof`
+        let mut mdat_offsets = Vec::new();
+        let mut offset = read_box_header(&media_segment, 0).0;
+        while offset < media_segment.len() {
+            let (size, kind) = read_box_header(&media_segment, offset);
+            assert_eq!(kind, b"mdat");
+            mdat_offsets.push(offset + 8);
+            offset += size;
+        }
+        assert_eq!(offset, media_segment.len());
+        let data_offsets = runs.iter().map(|r| r.0).collect::<Vec<_>>();
+        assert_eq!(data_offsets, mdat_offsets);
+
+        let video_data = &media_segment[runs[0].0..runs[1].0 - 8];
+        assert_eq!(video_data.len(), 33 + 9 * 3);
+        assert_eq!(&video_data[..4], &[0, 0, 0, SPS.len() as u8]);
+        assert_eq!(&video_data[4..4 + SPS.len()], SPS);
+        assert_eq!(&media_segment[runs[1].0..], &[[0; 10], [1; 10]].concat()[..]);
+    }
+}
@@ -0,0 +1,243 @@
+//! SAMPLE-AES decryption of MPEG-2 TS segments.
+//!
+//! See: [MPEG-2 Stream Encryption Format for HTTP Live Streaming][spec]
+//!
+//! [spec]: https://developer.apple.com/library/archive/documentation/AudioVideo/Conceptual/HLS_Sample_Encryption/
+use Result;
+use super::aac::AdtsHeader;
+use super::crypto::{self, Iv, Key, BLOCK_SIZE};
+use super::h264;
+use super::ts::{self, TsSegment};
+
+/// Size of the unencrypted leader of an encrypted NAL unit (including the NAL unit type byte).
+const NAL_UNIT_CLEAR_LEADER_SIZE: usize = 32;
+
+/// NAL units that are not longer than this size are not encrypted.
+const NAL_UNIT_MIN_ENCRYPTED_SIZE: usize = 48;
+
+/// Number of bytes left unencrypted between two encrypted blocks of a NAL unit.
+const NAL_UNIT_CLEAR_BLOCKS_SIZE: usize = 9 * BLOCK_SIZE;
+
+/// Size of the unencrypted leader that follows an ADTS header.
+const ADTS_CLEAR_LEADER_SIZE: usize = 16;
+
+/// Decrypts the SAMPLE-AES encrypted elementary streams in `segment`.
+///
+/// The decrypted streams are declared with the ordinary (unencrypted) stream types.
+pub fn decrypt_ts_segment(key: &Key, iv: &Iv, segment: &mut TsSegment) -> Result<()> {
+    for i in 0..segment.pes_packets.len() {
+        let stream_type = segment.stream_type(segment.pes_packets[i].pid);
+        let pes = &mut segment.pes_packets[i];
+        let payload = match stream_type {
+            Some(ts::STREAM_TYPE_H264_SAMPLE_AES) => {
+                track!(decrypt_h264(key, iv, track!(pes.payload())?))?
+            }
+            Some(ts::STREAM_TYPE_ADTS_AAC_SAMPLE_AES) => {
+                track!(decrypt_adts(key, iv, track!(pes.payload())?))?
+            }
+            _ => continue,
+        };
+        track!(pes.set_payload(&payload))?;
+    }
+    for stream in &mut segment.streams {
+        match stream.stream_type {
+            ts::STREAM_TYPE_H264_SAMPLE_AES => stream.stream_type = ts::STREAM_TYPE_H264,
+            ts::STREAM_TYPE_ADTS_AAC_SAMPLE_AES => stream.stream_type = ts::STREAM_TYPE_ADTS_AAC,
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Decrypts the video slice NAL units in an Annex B byte stream.
+fn decrypt_h264(key: &Key, iv: &Iv, byte_stream: &[u8]) -> Result<Vec<u8>> {
+    let mut decrypted = Vec::with_capacity(byte_stream.len());
+    let mut last = 0;
+    for (start, end) in h264::nal_unit_ranges(byte_stream) {
+        decrypted.extend_from_slice(&byte_stream[last..start]);
+        last = end;
+
+        let nal_unit = &byte_stream[start..end];
+        let nal_unit_type = h264::nal_unit_type(nal_unit);
+        if nal_unit_type != h264::NAL_UNIT_TYPE_NON_IDR_SLICE
+            && nal_unit_type != h264::NAL_UNIT_TYPE_IDR_SLICE
+        {
+            decrypted.extend_from_slice(nal_unit);
+            continue;
+        }
+
+        let mut rbsp = h264::remove_emulation_prevention_bytes(nal_unit);
+        if rbsp.len() <= NAL_UNIT_MIN_ENCRYPTED_SIZE {
+            decrypted.extend_from_slice(nal_unit);
+            continue;
+        }
+
+        let mut encrypted_blocks = Vec::new();
+        let mut offset = NAL_UNIT_CLEAR_LEADER_SIZE;
+        while rbsp.len() - offset > BLOCK_SIZE {
+            encrypted_blocks.push(offset);
+            offset += BLOCK_SIZE + NAL_UNIT_CLEAR_BLOCKS_SIZE;
+            if offset >= rbsp.len() {
+                break;
+            }
+        }
+
+        let mut buf = Vec::with_capacity(encrypted_blocks.len() * BLOCK_SIZE);
+        for &offset in &encrypted_blocks {
+            buf.extend_from_slice(&rbsp[offset..offset + BLOCK_SIZE]);
+        }
+        crypto::decrypt_cbc_in_place(key, iv, &mut buf);
+        for (&offset, block) in encrypted_blocks.iter().zip(buf.chunks(BLOCK_SIZE)) {
+            rbsp[offset..offset + BLOCK_SIZE].copy_from_slice(block);
+        }
+        h264::add_emulation_prevention_bytes(&rbsp, &mut decrypted);
+    }
+    decrypted.extend_from_slice(&byte_stream[last..]);
+    Ok(decrypted)
+}
+
+/// Decrypts the ADTS frames in `data`.
+fn decrypt_adts(key: &Key, iv: &Iv, data: &[u8]) -> Result<Vec<u8>> {
+    let mut decrypted = data.to_owned();
+    let mut offset = 0;
+    while offset < decrypted.len() {
+        let frame = &mut decrypted[offset..];
+        let header = track!(AdtsHeader::parse(frame))?;
+        let encrypted_start = header.header_len + ADTS_CLEAR_LEADER_SIZE;
+        if header.frame_len > encrypted_start {
+            crypto::decrypt_cbc_in_place(key, iv, &mut frame[encrypted_start..header.frame_len]);
+        }
+        offset += header.frame_len;
+    }
+    Ok(decrypted)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::crypto::tests::encrypt_aes128_cbc;
+
+    const KEY: Key = [7; 16];
+    const IV: Iv = [1; 16];
+
+    /// Encrypts the blocks at `offsets` of `data` as a single AES-128-CBC chain.
+    fn encrypt_blocks(data: &[u8], offsets: &[usize]) -> Vec<u8> {
+        let mut plain = Vec::new();
+        for &offset in offsets {
+            plain.extend_from_slice(&data[offset..offset + BLOCK_SIZE]);
+        }
+        let encrypted = encrypt_aes128_cbc(&KEY, &IV, &plain);
+        let mut data = data.to_owned();
+        for (&offset, block) in offsets.iter().zip(encrypted.chunks(BLOCK_SIZE)) {
+            data[offset..offset + BLOCK_SIZE].copy_from_slice(block);
+        }
+        data
+    }
+
+    fn byte_stream(nal_units: &[&[u8]]) -> Vec<u8> {
+        let mut buf = Vec::new();
+        for rbsp in nal_units {
+            buf.extend_from_slice(&[0, 0, 0, 1]);
+            h264::add_emulation_prevention_bytes(rbsp, &mut buf);
+        }
+        buf
+    }
+
+    /// Returns the RBSP of a slice NAL unit of `len` bytes with no start code emulation.
+    fn slice(nal_unit_type: u8, len: usize) -> Vec<u8> {
+        let mut rbsp = vec![0x40 | nal_unit_type];
+        rbsp.extend((1..len).map(|i| (i % 200) as u8 + 4));
+        rbsp
+    }
+
+    #[test]
+    fn h264_slice_has_clear_leader_and_one_encrypted_block_in_ten() {
+        // 32 clear bytes, then one encrypted block and nine clear blocks,
+        // then another encrypted block and a clear partial block
+        let plain = slice(h264::NAL_UNIT_TYPE_IDR_SLICE, 32 + 16 + 144 + 16 + 10);
+        let encrypted = encrypt_blocks(&plain, &[32, 192]);
+        assert_eq!(encrypted[..32], plain[..32]);
+        assert_eq!(encrypted[48..192], plain[48..192]);
+        assert_eq!(encrypted[208..], plain[208..]);
+
+        let decrypted = decrypt_h264(&KEY, &IV, &byte_stream(&[&encrypted])).unwrap();
+        assert_eq!(decrypted, byte_stream(&[&plain]));
+    }
+
+    #[test]
+    fn h264_trailing_whole_block_is_clear() {
+        // Exactly one block follows the clear blocks, so it is left unencrypted
+        let plain = slice(h264::NAL_UNIT_TYPE_NON_IDR_SLICE, 32 + 16 + 144 + 16);
+        let encrypted = encrypt_blocks(&plain, &[32]);
+
+        let decrypted = decrypt_h264(&KEY, &IV, &byte_stream(&[&encrypted])).unwrap();
+        assert_eq!(decrypted, byte_stream(&[&plain]));
+    }
+
+    #[test]
+    fn h264_short_and_non_slice_nal_units_are_clear() {
+        let short_slice = slice(h264::NAL_UNIT_TYPE_IDR_SLICE, 48);
+        let sps = slice(h264::NAL_UNIT_TYPE_SPS, 100);
+        let stream = byte_stream(&[&sps, &short_slice]);
+        assert_eq!(decrypt_h264(&KEY, &IV, &stream).unwrap(), stream);
+    }
+
+    #[test]
+    fn h264_emulation_prevention_bytes_are_not_counted() {
+        // The clear leader contains start code emulations, so the encrypted block
+        // is preceded by emulation prevention bytes in the NAL unit
+        let mut plain = slice(h264::NAL_UNIT_TYPE_IDR_SLICE, 100);
+        plain[4..8].copy_from_slice(&[0, 0, 1, 0]);
+        plain[20..23].copy_from_slice(&[0, 0, 3]);
+        let encrypted = encrypt_blocks(&plain, &[32]);
+        let stream = byte_stream(&[&encrypted]);
+        assert_eq!(stream.len(), 4 + 100 + 2);
+
+        let decrypted = decrypt_h264(&KEY, &IV, &stream).unwrap();
+        assert_eq!(decrypted, byte_stream(&[&plain]));
+        assert_eq!(h264::remove_emulation_prevention_bytes(&decrypted[4..]), plain);
+    }
+
+    fn adts_frame(payload_len: usize) -> Vec<u8> {
+        let frame_len = 7 + payload_len;
+        let mut frame = vec![
+            0xFF,
+            0xF1,
+            0x50,
+            0x80 | (frame_len >> 11) as u8,
+            (frame_len >> 3) as u8,
+            ((frame_len & 0b111) << 5) as u8 | 0x1F,
+            0xFC,
+        ];
+        frame.extend((0..payload_len).map(|i| i as u8));
+        frame
+    }
+
+    #[test]
+    fn adts_frames_have_clear_leader_and_clear_partial_block() {
+        // 16 clear bytes after the header, two encrypted blocks and five clear bytes
+        let plain_frame = adts_frame(16 + 32 + 5);
+        let encrypted_frame = encrypt_blocks(&plain_frame, &[23, 39]);
+        assert_eq!(encrypted_frame[..23], plain_frame[..23]);
+        assert_eq!(encrypted_frame[55..], plain_frame[55..]);
+
+        // Too short to have an encrypted block
+        let short_frame = adts_frame(16 + 10);
+
+        // Each frame restarts the CBC chain with the IV
+        let mut plain = plain_frame.clone();
+        plain.extend_from_slice(&short_frame);
+        plain.extend_from_slice(&plain_frame);
+        let mut encrypted = encrypted_frame.clone();
+        encrypted.extend_from_slice(&short_frame);
+        encrypted.extend_from_slice(&encrypted_frame);
+        assert_eq!(decrypt_adts(&KEY, &IV, &encrypted).unwrap(), plain);
+    }
+
+    #[test]
+    fn adts_frame_longer_than_data_is_rejected() {
+        let mut frame = adts_frame(40);
+        frame.truncate(30);
+        assert!(decrypt_adts(&KEY, &IV, &frame).is_err());
+    }
+}
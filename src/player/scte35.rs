@@ -0,0 +1,304 @@
+//! SCTE-35 splice information sections.
+//!
+//! See: [ANSI/SCTE 35](https://www.scte.org/standards/)
+use {ErrorKind, Result};
+
+pub const STREAM_TYPE_SCTE35: u8 = 0x86;
+
+const TABLE_ID: u8 = 0xFC;
+const SPLICE_INSERT: u8 = 0x05;
+const TIME_SIGNAL: u8 = 0x06;
+const SEGMENTATION_DESCRIPTOR_TAG: u8 = 0x02;
+
+/// `segmentation_type_id`s that start a break (the ones ending it are the next values).
+const SEGMENTATION_TYPES_START: &[u8] = &[0x22, 0x30, 0x32, 0x34, 0x36];
+
+/// Cue signaling the start or end of a break.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SpliceSignal {
+    pub event_id: u32,
+
+    /// `true` if the signal starts a break (i.e., leaves the network).
+    pub is_out: bool,
+
+    /// Splice time (in 90kHz units), or `None` if the splice is immediate.
+    pub pts: Option<u64>,
+
+    /// Duration of the break in seconds.
+    pub duration: Option<f64>,
+}
+
+/// Parses a `splice_info_section` and returns the break signals in it.
+///
+/// Commands other than `splice_insert` and `time_signal` are ignored.
+pub fn parse_splice_info_section(section: &[u8]) -> Result<Vec<SpliceSignal>> {
+    track_assert!(section.len() >= 14, ErrorKind::InvalidInput, "Too short SCTE-35 section");
+    track_assert_eq!(section[0], TABLE_ID, ErrorKind::InvalidInput);
+    let section_len = (usize::from(section[1] & 0x0F) << 8) | section[2] as usize;
+    track_assert!(section.len() >= 3 + section_len, ErrorKind::InvalidInput);
+    let section = &section[..3 + section_len];
+    track_assert!(
+        section[4] & 0b1000_0000 == 0,
+        ErrorKind::Unsupported,
+        "Encrypted SCTE-35 sections are not supported"
+    );
+
+    let pts_adjustment = read_bits(&section[4..9], 7, 33);
+    let command_len = ((usize::from(section[11]) & 0x0F) << 8) | section[12] as usize;
+    let command_type = section[13];
+    let command_end = 14 + command_len;
+    track_assert!(section.len() >= command_end + 2, ErrorKind::InvalidInput);
+    let command = &section[14..command_end];
+    let adjust = |pts: u64| (pts + pts_adjustment) & ((1 << 33) - 1);
+
+    let mut signals = Vec::new();
+    match command_type {
+        SPLICE_INSERT => {
+            if let Some(mut signal) = track!(parse_splice_insert(command))? {
+                signal.pts = signal.pts.map(adjust);
+                signals.push(signal);
+            }
+        }
+        TIME_SIGNAL => {
+            let (pts, _) = track!(parse_splice_time(command))?;
+            let descriptors_len =
+                (usize::from(section[command_end]) << 8) | section[command_end + 1] as usize;
+            let descriptors = &section[command_end + 2..];
+            track_assert!(descriptors.len() >= descriptors_len, ErrorKind::InvalidInput);
+            let mut descriptors = &descriptors[..descriptors_len];
+            while descriptors.len() >= 2 {
+                let tag = descriptors[0];
+                let len = descriptors[1] as usize;
+                track_assert!(descriptors.len() >= 2 + len, ErrorKind::InvalidInput);
+                if tag == SEGMENTATION_DESCRIPTOR_TAG {
+                    let descriptor = &descriptors[2..2 + len];
+                    if let Some(mut signal) = track!(parse_segmentation_descriptor(descriptor))? {
+                        signal.pts = pts.map(adjust);
+                        signals.push(signal);
+                    }
+                }
+                descriptors = &descriptors[2 + len..];
+            }
+        }
+        _ => {}
+    }
+    Ok(signals)
+}
+
+fn parse_splice_insert(command: &[u8]) -> Result<Option<SpliceSignal>> {
+    track_assert!(command.len() >= 5, ErrorKind::InvalidInput);
+    let event_id = read_bits(&command[..4], 0, 32) as u32;
+    let cancel = command[4] & 0b1000_0000 != 0;
+    if cancel {
+        return Ok(None);
+    }
+    track_assert!(command.len() >= 6, ErrorKind::InvalidInput);
+    let flags = command[5];
+    let is_out = flags & 0b1000_0000 != 0;
+    let program_splice = flags & 0b0100_0000 != 0;
+    let has_duration = flags & 0b0010_0000 != 0;
+    let immediate = flags & 0b0001_0000 != 0;
+
+    let mut rest = &command[6..];
+    let mut pts = None;
+    if program_splice && !immediate {
+        let (x, size) = track!(parse_splice_time(rest))?;
+        pts = x;
+        rest = &rest[size..];
+    } else if !program_splice {
+        track_assert!(!rest.is_empty(), ErrorKind::InvalidInput);
+        let component_count = rest[0] as usize;
+        rest = &rest[1..];
+        for _ in 0..component_count {
+            track_assert!(!rest.is_empty(), ErrorKind::InvalidInput);
+            rest = &rest[1..];
+            if !immediate {
+                let (x, size) = track!(parse_splice_time(rest))?;
+                pts = pts.or(x);
+                rest = &rest[size..];
+            }
+        }
+    }
+    let duration = if has_duration {
+        track_assert!(rest.len() >= 5, ErrorKind::InvalidInput);
+        Some(read_bits(&rest[..5], 7, 33) as f64 / 90_000.0)
+    } else {
+        None
+    };
+    Ok(Some(SpliceSignal {
+        event_id,
+        is_out,
+        pts,
+        duration,
+    }))
+}
+
+/// Parses a `splice_time()` structure, and returns the time and the size of the structure.
+fn parse_splice_time(data: &[u8]) -> Result<(Option<u64>, usize)> {
+    track_assert!(!data.is_empty(), ErrorKind::InvalidInput);
+    if data[0] & 0b1000_0000 == 0 {
+        return Ok((None, 1));
+    }
+    track_assert!(data.len() >= 5, ErrorKind::InvalidInput);
+    Ok((Some(read_bits(&data[..5], 7, 33)), 5))
+}
+
+fn parse_segmentation_descriptor(descriptor: &[u8]) -> Result<Option<SpliceSignal>> {
+    track_assert!(descriptor.len() >= 9, ErrorKind::InvalidInput);
+    track_assert_eq!(&descriptor[..4], b"CUEI", ErrorKind::InvalidInput);
+    let event_id = read_bits(&descriptor[4..8], 0, 32) as u32;
+    let cancel = descriptor[8] & 0b1000_0000 != 0;
+    if cancel {
+        return Ok(None);
+    }
+
+    track_assert!(descriptor.len() >= 10, ErrorKind::InvalidInput);
+    let flags = descriptor[9];
+    let program_segmentation = flags & 0b1000_0000 != 0;
+    let has_duration = flags & 0b0100_0000 != 0;
+    let mut rest = &descriptor[10..];
+    if !program_segmentation {
+        track_assert!(!rest.is_empty(), ErrorKind::InvalidInput);
+        let component_count = rest[0] as usize;
+        track_assert!(rest.len() > component_count * 6, ErrorKind::InvalidInput);
+        rest = &rest[1 + component_count * 6..];
+    }
+    let duration = if has_duration {
+        track_assert!(rest.len() >= 5, ErrorKind::InvalidInput);
+        let duration = read_bits(&rest[..5], 0, 40) as f64 / 90_000.0;
+        rest = &rest[5..];
+        Some(duration)
+    } else {
+        None
+    };
+    track_assert!(rest.len() >= 2, ErrorKind::InvalidInput);
+    let upid_len = rest[1] as usize;
+    track_assert!(rest.len() >= 3 + upid_len, ErrorKind::InvalidInput);
+    let segmentation_type = rest[2 + upid_len];
+
+    let is_out = if SEGMENTATION_TYPES_START.contains(&segmentation_type) {
+        true
+    } else if SEGMENTATION_TYPES_START.contains(&segmentation_type.wrapping_sub(1)) {
+        false
+    } else {
+        return Ok(None);
+    };
+    Ok(Some(SpliceSignal {
+        event_id,
+        is_out,
+        pts: None,
+        duration,
+    }))
+}
+
+/// Reads `len` bits starting from the `offset`-th bit of `bytes`.
+fn read_bits(bytes: &[u8], offset: usize, len: usize) -> u64 {
+    let value = bytes.iter().fold(0, |acc, &b| (acc << 8) | u64::from(b));
+    let total = bytes.len() * 8;
+    (value >> (total - offset - len)) & ((1 << len) - 1)
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    /// `splice_insert` of the sample 14.2 of SCTE 35 (2019).
+    pub const SPLICE_INSERT: &str =
+        "fc302f000000000000fffff014054800008f7feffe7369c02efe0052ccf500000000000a0008435545\
+         490000013562dba30a";
+
+    /// `time_signal` of the sample 14.1 of SCTE 35 (2019)
+    /// (Provider Placement Opportunity Start).
+    pub const TIME_SIGNAL: &str =
+        "fc3034000000000000fffff00506fe72bd0050001e021c435545494800008e7fcf0001a599b008080000\
+         00002ca0a18a3402009ac9d17e";
+
+    pub fn hex(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn splice_insert() {
+        let signals = parse_splice_info_section(&hex(SPLICE_INSERT)).unwrap();
+        assert_eq!(
+            signals,
+            [SpliceSignal {
+                event_id: 0x4800_008F,
+                is_out: true,
+                pts: Some(0x7369_C02E),
+                duration: Some(f64::from(0x0052_CCF5) / 90_000.0),
+            }]
+        );
+    }
+
+    #[test]
+    fn splice_insert_with_pts_adjustment() {
+        // pts_adjustment = 2^33 - 0x1000, so the splice time wraps around
+        let mut section = hex(SPLICE_INSERT);
+        section[4..9].copy_from_slice(&[0x01, 0xFF, 0xFF, 0xF0, 0x00]);
+        let signals = parse_splice_info_section(&section).unwrap();
+        assert_eq!(signals[0].pts, Some(0x7369_C02E - 0x1000));
+    }
+
+    #[test]
+    fn canceled_and_immediate_splice_inserts() {
+        // splice_event_cancel_indicator
+        let mut section = hex(SPLICE_INSERT);
+        section[18] = 0xFF;
+        assert!(parse_splice_info_section(&section).unwrap().is_empty());
+
+        // splice_immediate_flag without duration (the return from a break)
+        let section = hex("fc301b00000000000000fff00a05480000907f5f000000000000ffffffff");
+        let signals = parse_splice_info_section(&section).unwrap();
+        assert_eq!(
+            signals,
+            [SpliceSignal {
+                event_id: 0x4800_0090,
+                is_out: false,
+                pts: None,
+                duration: None,
+            }]
+        );
+    }
+
+    #[test]
+    fn time_signal_with_segmentation_descriptor() {
+        let signals = parse_splice_info_section(&hex(TIME_SIGNAL)).unwrap();
+        assert_eq!(
+            signals,
+            [SpliceSignal {
+                event_id: 0x4800_008E,
+                is_out: true,
+                pts: Some(0x72BD_0050),
+                duration: Some(307.0),
+            }]
+        );
+
+        // Provider Placement Opportunity End
+        let mut section = hex(TIME_SIGNAL);
+        section[48] = 0x35;
+        let signals = parse_splice_info_section(&section).unwrap();
+        assert!(!signals[0].is_out);
+
+        // Program Start (not a break)
+        section[48] = 0x10;
+        assert!(parse_splice_info_section(&section).unwrap().is_empty());
+    }
+
+    #[test]
+    fn malformed_sections_are_rejected() {
+        let section = hex(SPLICE_INSERT);
+        assert!(parse_splice_info_section(&section[..30]).is_err());
+
+        let mut encrypted = section.clone();
+        encrypted[4] |= 0x80;
+        assert!(parse_splice_info_section(&encrypted).is_err());
+
+        let mut other_table = section;
+        other_table[0] = 0x02;
+        assert!(parse_splice_info_section(&other_table).is_err());
+    }
+}
@@ -0,0 +1,366 @@
+//! Minimal MPEG-2 TS demultiplexer.
+use std::collections::HashMap;
+
+use {ErrorKind, Result};
+
+pub const PACKET_SIZE: usize = 188;
+pub const SYNC_BYTE: u8 = 0x47;
+
+/// Frequency of the PTS and DTS.
+pub const TIMESCALE: u32 = 90_000;
+
+pub const STREAM_TYPE_ADTS_AAC: u8 = 0x0F;
+pub const STREAM_TYPE_METADATA: u8 = 0x15;
+pub const STREAM_TYPE_H264: u8 = 0x1B;
+pub const STREAM_TYPE_ADTS_AAC_SAMPLE_AES: u8 = 0xCF;
+pub const STREAM_TYPE_H264_SAMPLE_AES: u8 = 0xDB;
+
+const PAT_PID: u16 = 0x0000;
+const NULL_PID: u16 = 0x1FFF;
+const PAT_TABLE_ID: u8 = 0x00;
+const PMT_TABLE_ID: u8 = 0x02;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ElementaryStream {
+    pub pid: u16,
+    pub stream_type: u8,
+}
+
+/// A PES packet (including its header) carried by the elementary stream `pid`.
+#[derive(Debug, Clone)]
+pub struct PesPacket {
+    pub pid: u16,
+    pub bytes: Vec<u8>,
+}
+impl PesPacket {
+    pub fn header_len(&self) -> Result<usize> {
+        track_assert!(self.bytes.len() >= 9, ErrorKind::InvalidInput);
+        track_assert_eq!(
+            &self.bytes[..3],
+            &[0, 0, 1][..],
+            ErrorKind::InvalidInput,
+            "Unexpected PES start code"
+        );
+        let header_len = 9 + self.bytes[8] as usize;
+        track_assert!(self.bytes.len() >= header_len, ErrorKind::InvalidInput);
+        Ok(header_len)
+    }
+
+    /// Returns the presentation time stamp (in 90kHz units) of this packet.
+    pub fn pts(&self) -> Result<Option<u64>> {
+        let header_len = track!(self.header_len())?;
+        if self.bytes[7] & 0b1000_0000 == 0 {
+            return Ok(None);
+        }
+        track_assert!(header_len >= 14, ErrorKind::InvalidInput);
+        Ok(Some(read_timestamp(&self.bytes[9..14])))
+    }
+
+    /// Returns the decoding time stamp (in 90kHz units) of this packet.
+    pub fn dts(&self) -> Result<Option<u64>> {
+        let header_len = track!(self.header_len())?;
+        if self.bytes[7] & 0b1100_0000 != 0b1100_0000 {
+            return Ok(None);
+        }
+        track_assert!(header_len >= 19, ErrorKind::InvalidInput);
+        Ok(Some(read_timestamp(&self.bytes[14..19])))
+    }
+
+    pub fn payload(&self) -> Result<&[u8]> {
+        let header_len = track!(self.header_len())?;
+        Ok(&self.bytes[header_len..])
+    }
+
+    /// Replaces the payload, and updates `PES_packet_length` accordingly.
+    pub fn set_payload(&mut self, payload: &[u8]) -> Result<()> {
+        let header_len = track!(self.header_len())?;
+        self.bytes.truncate(header_len);
+        self.bytes.extend_from_slice(payload);
+
+        let old_packet_len = (u16::from(self.bytes[4]) << 8) | u16::from(self.bytes[5]);
+        if old_packet_len != 0 {
+            let packet_len = self.bytes.len() - 6;
+            track_assert!(packet_len <= 0xFFFF, ErrorKind::InvalidInput);
+            self.bytes[4] = (packet_len >> 8) as u8;
+            self.bytes[5] = packet_len as u8;
+        }
+        Ok(())
+    }
+}
+
+/// A demultiplexed TS segment.
+#[derive(Debug, Default)]
+pub struct TsSegment {
+    pub program_number: u16,
+    pub pmt_pid: u16,
+    pub streams: Vec<ElementaryStream>,
+    pub pes_packets: Vec<PesPacket>,
+}
+impl TsSegment {
+    pub fn demux(data: &[u8]) -> Result<Self> {
+        track_assert_eq!(
+            data.len() % PACKET_SIZE,
+            0,
+            ErrorKind::InvalidInput,
+            "Truncated TS packet"
+        );
+
+        let mut segment = TsSegment::default();
+        let mut pmt_pid = None;
+        let mut partials: HashMap<u16, Vec<u8>> = HashMap::new();
+        for packet in data.chunks(PACKET_SIZE) {
+            track_assert_eq!(packet[0], SYNC_BYTE, ErrorKind::InvalidInput);
+            let payload_unit_start = packet[1] & 0b0100_0000 != 0;
+            let pid = (u16::from(packet[1] & 0b0001_1111) << 8) | u16::from(packet[2]);
+            let adaptation_field_control = (packet[3] >> 4) & 0b11;
+            if adaptation_field_control & 0b01 == 0 || pid == NULL_PID {
+                continue;
+            }
+            let mut offset = 4;
+            if adaptation_field_control & 0b10 != 0 {
+                offset += 1 + packet[4] as usize;
+            }
+            track_assert!(offset <= PACKET_SIZE, ErrorKind::InvalidInput);
+            let payload = &packet[offset..];
+
+            if pid == PAT_PID {
+                if payload_unit_start {
+                    let (program_number, pid) = track!(parse_pat(payload))?;
+                    segment.program_number = program_number;
+                    pmt_pid = Some(pid);
+                }
+            } else if Some(pid) == pmt_pid {
+                if payload_unit_start && segment.streams.is_empty() {
+                    segment.streams = track!(parse_pmt(payload))?;
+                    segment.pmt_pid = pid;
+                }
+            } else if segment.streams.iter().any(|s| s.pid == pid) {
+                if payload_unit_start {
+                    if let Some(bytes) = partials.remove(&pid) {
+                        segment.pes_packets.push(PesPacket { pid, bytes });
+                    }
+                    partials.insert(pid, payload.to_owned());
+                } else if let Some(bytes) = partials.get_mut(&pid) {
+                    bytes.extend_from_slice(payload);
+                }
+            }
+        }
+        for stream in &segment.streams {
+            if let Some(bytes) = partials.remove(&stream.pid) {
+                segment.pes_packets.push(PesPacket {
+                    pid: stream.pid,
+                    bytes,
+                });
+            }
+        }
+        Ok(segment)
+    }
+
+    pub fn stream_type(&self, pid: u16) -> Option<u8> {
+        self.streams
+            .iter()
+            .find(|s| s.pid == pid)
+            .map(|s| s.stream_type)
+    }
+}
+
+/// Converts a timestamp (in 90kHz units) into seconds.
+pub fn timestamp_to_seconds(timestamp: u64) -> f64 {
+    timestamp as f64 / f64::from(TIMESCALE)
+}
+
+/// Converts `time` (in seconds) in the MPEG-2 TS timeline into the one relative to
+/// the timestamp `origin`.
+///
+/// The wraparound of the 33-bit timestamps is taken into account.
+pub fn relative_time(time: f64, origin: u64) -> f64 {
+    let wraparound = timestamp_to_seconds(1 << 33);
+    let delta = time - timestamp_to_seconds(origin);
+    if delta < -wraparound / 2.0 {
+        delta + wraparound
+    } else if delta > wraparound / 2.0 {
+        delta - wraparound
+    } else {
+        delta
+    }
+}
+
+fn read_timestamp(bytes: &[u8]) -> u64 {
+    (u64::from(bytes[0] & 0b0000_1110) << 29) | (u64::from(bytes[1]) << 22)
+        | (u64::from(bytes[2] >> 1) << 15) | (u64::from(bytes[3]) << 7)
+        | u64::from(bytes[4] >> 1)
+}
+
+/// Returns the body of the PSI section at the head of `payload`.
+///
+/// The returned slice starts with the `table_id` field and excludes the trailing CRC.
+fn section_body(payload: &[u8], table_id: u8) -> Result<&[u8]> {
+    track_assert!(!payload.is_empty(), ErrorKind::InvalidInput);
+    let pointer_field = payload[0] as usize;
+    let section = &payload[1..];
+    track_assert!(section.len() >= pointer_field + 3, ErrorKind::InvalidInput);
+    let section = &section[pointer_field..];
+    track_assert_eq!(section[0], table_id, ErrorKind::InvalidInput);
+
+    let section_len = (usize::from(section[1] & 0b0000_1111) << 8) | section[2] as usize;
+    track_assert!(
+        section_len >= 9 && section.len() >= 3 + section_len,
+        ErrorKind::InvalidInput,
+        "PSI sections spanning multiple TS packets are not supported"
+    );
+    Ok(&section[..3 + section_len - 4])
+}
+
+fn parse_pat(payload: &[u8]) -> Result<(u16, u16)> {
+    let section = track!(section_body(payload, PAT_TABLE_ID))?;
+    for entry in section[8..].chunks(4).filter(|e| e.len() == 4) {
+        let program_number = (u16::from(entry[0]) << 8) | u16::from(entry[1]);
+        if program_number != 0 {
+            let pid = (u16::from(entry[2] & 0b0001_1111) << 8) | u16::from(entry[3]);
+            return Ok((program_number, pid));
+        }
+    }
+    track_panic!(ErrorKind::InvalidInput, "No program in PAT");
+}
+
+fn parse_pmt(payload: &[u8]) -> Result<Vec<ElementaryStream>> {
+    let section = track!(section_body(payload, PMT_TABLE_ID))?;
+    track_assert!(section.len() >= 12, ErrorKind::InvalidInput);
+    let program_info_len = (usize::from(section[10] & 0b0000_1111) << 8) | section[11] as usize;
+
+    let mut streams = Vec::new();
+    let mut rest = &section[12 + program_info_len..];
+    while rest.len() >= 5 {
+        let stream_type = rest[0];
+        let pid = (u16::from(rest[1] & 0b0001_1111) << 8) | u16::from(rest[2]);
+        let es_info_len = (usize::from(rest[3] & 0b0000_1111) << 8) | rest[4] as usize;
+        track_assert!(rest.len() >= 5 + es_info_len, ErrorKind::InvalidInput);
+        streams.push(ElementaryStream { pid, stream_type });
+        rest = &rest[5 + es_info_len..];
+    }
+    Ok(streams)
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    const PMT_PID: u16 = 0x1000;
+
+    /// Multiplexes `segment` into TS packets (preceded by a PAT and a PMT).
+    ///
+    /// The CRCs of the sections are not calculated, since `TsSegment::demux()` ignores them.
+    pub fn mux(segment: &TsSegment) -> Vec<u8> {
+        let mut pat = vec![0, PAT_TABLE_ID, 0xB0, 13, 0, 1, 0xC1, 0, 0, 0, 1];
+        pat.extend_from_slice(&[0xE0 | (PMT_PID >> 8) as u8, PMT_PID as u8, 0, 0, 0, 0]);
+
+        let section_len = 13 + 5 * segment.streams.len();
+        let mut pmt = vec![0, PMT_TABLE_ID, 0xB0, section_len as u8, 0, 1, 0xC1, 0, 0];
+        pmt.extend_from_slice(&[0xE1, 0x00, 0xF0, 0]);
+        for stream in &segment.streams {
+            pmt.extend_from_slice(&[
+                stream.stream_type,
+                0xE0 | (stream.pid >> 8) as u8,
+                stream.pid as u8,
+                0xF0,
+                0,
+            ]);
+        }
+        pmt.extend_from_slice(&[0, 0, 0, 0]);
+
+        let mut data = Vec::new();
+        packetize(&mut data, PAT_PID, &pat);
+        packetize(&mut data, PMT_PID, &pmt);
+        for pes in &segment.pes_packets {
+            packetize(&mut data, pes.pid, &pes.bytes);
+        }
+        data
+    }
+
+    /// Splits `payload` into TS packets, stuffing the last one with an adaptation field.
+    fn packetize(data: &mut Vec<u8>, pid: u16, payload: &[u8]) {
+        for (i, chunk) in payload.chunks(PACKET_SIZE - 4).enumerate() {
+            let payload_unit_start = if i == 0 { 0b0100_0000 } else { 0 };
+            data.extend_from_slice(&[SYNC_BYTE, payload_unit_start | (pid >> 8) as u8, pid as u8]);
+            let stuffing = PACKET_SIZE - 4 - chunk.len();
+            if stuffing == 0 {
+                data.push(0b0001_0000);
+            } else {
+                data.extend_from_slice(&[0b0011_0000, stuffing as u8 - 1]);
+                if stuffing > 1 {
+                    data.push(0);
+                    data.extend((2..stuffing).map(|_| 0xFF));
+                }
+            }
+            data.extend_from_slice(chunk);
+        }
+    }
+
+    #[test]
+    fn demux_of_muxed_segment() {
+        let streams = vec![
+            ElementaryStream {
+                pid: 0x100,
+                stream_type: STREAM_TYPE_H264,
+            },
+            ElementaryStream {
+                pid: 0x101,
+                stream_type: STREAM_TYPE_ADTS_AAC,
+            },
+        ];
+        let pes_packets = vec![
+            PesPacket {
+                pid: 0x100,
+                bytes: (0..400).map(|i| i as u8).collect(),
+            },
+            PesPacket {
+                pid: 0x101,
+                bytes: vec![1; 183],
+            },
+            PesPacket {
+                pid: 0x100,
+                bytes: vec![2; 184],
+            },
+        ];
+        let segment = TsSegment {
+            program_number: 1,
+            pmt_pid: PMT_PID,
+            streams: streams.clone(),
+            pes_packets,
+        };
+        let data = mux(&segment);
+        assert_eq!(data.len(), PACKET_SIZE * 7);
+
+        let demuxed = TsSegment::demux(&data).unwrap();
+        assert_eq!((demuxed.program_number, demuxed.pmt_pid), (1, PMT_PID));
+        assert_eq!(demuxed.streams, streams);
+        assert_eq!(demuxed.stream_type(0x101), Some(STREAM_TYPE_ADTS_AAC));
+
+        // PES packets are in order within each stream
+        let packets = |segment: &TsSegment| {
+            let mut packets = segment
+                .pes_packets
+                .iter()
+                .map(|p| (p.pid, p.bytes.clone()))
+                .collect::<Vec<_>>();
+            packets.sort_by_key(|p| p.0);
+            packets
+        };
+        assert_eq!(packets(&demuxed), packets(&segment));
+    }
+
+    #[test]
+    fn malformed_segments_are_rejected() {
+        assert!(TsSegment::demux(&[SYNC_BYTE; 100]).is_err());
+        assert!(TsSegment::demux(&[0; PACKET_SIZE]).is_err());
+    }
+
+    #[test]
+    fn relative_time_across_wraparound() {
+        assert_eq!(relative_time(1.0, 0), 1.0);
+        let before_wraparound = (1 << 33) - 90_000;
+        assert!((relative_time(1.0, before_wraparound) - 2.0).abs() < 1e-6);
+        let origin = timestamp_to_seconds(before_wraparound);
+        assert!((relative_time(origin, 90_000) + 2.0).abs() < 1e-6);
+    }
+}
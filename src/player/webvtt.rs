@@ -0,0 +1,233 @@
+//! WebVTT subtitle segments.
+//!
+//! See: [RFC 8216 - 3.5. WebVTT](https://tools.ietf.org/html/rfc8216#section-3.5)
+use trackable::error::ErrorKindExt;
+
+use {ErrorKind, Result};
+use super::ts;
+
+/// Timed text cue of subtitles or closed captions.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Cue {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+
+    /// Start time in seconds.
+    pub start: f64,
+
+    /// End time in seconds.
+    pub end: f64,
+
+    /// Cue settings (e.g., `"line:0 align:start"`).
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub settings: String,
+
+    pub text: String,
+}
+impl Cue {
+    /// Converts the cue times in the MPEG-2 TS timeline into the ones relative to `origin`
+    /// (an MPEG-2 TS timestamp in 90kHz units).
+    ///
+    /// The wraparound of the 33-bit timestamps is taken into account.
+    pub fn align_to(&mut self, origin: u64) {
+        self.start = ts::relative_time(self.start, origin);
+        self.end = ts::relative_time(self.end, origin);
+    }
+}
+
+/// Returns `true` if `data` looks like a WebVTT file.
+pub fn is_webvtt(data: &[u8]) -> bool {
+    let data = data.strip_prefix(b"\xEF\xBB\xBF").unwrap_or(data);
+    data.starts_with(b"WEBVTT")
+}
+
+/// Parses a WebVTT segment.
+///
+/// The times of the resulting cues are mapped to the MPEG-2 TS timeline by the
+/// `X-TIMESTAMP-MAP` header. If the header is absent, the cue times are used as is.
+pub fn parse(text: &str) -> Result<Vec<Cue>> {
+    let text = text.trim_start_matches('\u{FEFF}');
+    let mut lines = text.lines();
+    let signature = lines.next().unwrap_or("");
+    track_assert!(
+        signature.starts_with("WEBVTT"),
+        ErrorKind::InvalidInput,
+        "Not a WebVTT file"
+    );
+
+    let mut offset = 0.0;
+    for line in lines.by_ref() {
+        if line.trim().is_empty() {
+            break;
+        }
+        if let Some(value) = line.strip_prefix("X-TIMESTAMP-MAP=") {
+            offset = track!(parse_timestamp_map(value))?;
+        }
+    }
+
+    let mut cues = Vec::new();
+    let mut block = Vec::new();
+    for line in lines.chain(Some("")) {
+        if !line.trim().is_empty() {
+            block.push(line);
+            continue;
+        }
+        if let Some(mut cue) = track!(parse_cue_block(&block))? {
+            cue.start += offset;
+            cue.end += offset;
+            cues.push(cue);
+        }
+        block.clear();
+    }
+    Ok(cues)
+}
+
+/// Returns the offset (in seconds) to add to the cue times.
+fn parse_timestamp_map(value: &str) -> Result<f64> {
+    let mut mpegts = None;
+    let mut local = None;
+    for field in value.split(',') {
+        let field = field.trim();
+        if let Some(x) = field.strip_prefix("MPEGTS:") {
+            let x: u64 = track!(x.parse().map_err(|e| ErrorKind::InvalidInput.cause(e)))?;
+            mpegts = Some(ts::timestamp_to_seconds(x));
+        } else if let Some(x) = field.strip_prefix("LOCAL:") {
+            local = Some(track!(parse_timestamp(x))?);
+        }
+    }
+    let mpegts = track_assert_some!(mpegts, ErrorKind::InvalidInput, "No MPEGTS: {:?}", value);
+    let local = track_assert_some!(local, ErrorKind::InvalidInput, "No LOCAL: {:?}", value);
+    Ok(mpegts - local)
+}
+
+/// Parses a block, and returns `None` if it is not a cue (e.g., `NOTE` or `STYLE`).
+fn parse_cue_block(block: &[&str]) -> Result<Option<Cue>> {
+    let timing_line = if let Some(i) = block.iter().position(|l| l.contains("-->")) {
+        i
+    } else {
+        return Ok(None);
+    };
+    track_assert!(timing_line <= 1, ErrorKind::InvalidInput, "Malformed cue: {:?}", block);
+
+    let id = if timing_line == 1 {
+        Some(block[0].to_owned())
+    } else {
+        None
+    };
+    let mut timing = block[timing_line].splitn(2, "-->");
+    let start = track!(parse_timestamp(timing.next().unwrap_or("").trim()))?;
+    let rest = timing.next().unwrap_or("").trim();
+    let mut rest = rest.splitn(2, char::is_whitespace);
+    let end = track!(parse_timestamp(rest.next().unwrap_or("")))?;
+    let settings = rest.next().unwrap_or("").trim().to_owned();
+    Ok(Some(Cue {
+        id,
+        start,
+        end,
+        settings,
+        text: block[timing_line + 1..].join("\n"),
+    }))
+}
+
+/// Parses a timestamp in the form of `[hh:]mm:ss.ttt`, and returns it in seconds.
+fn parse_timestamp(s: &str) -> Result<f64> {
+    let (hms, millis) = track_assert_some!(
+        s.split_once('.'),
+        ErrorKind::InvalidInput,
+        "Malformed timestamp: {:?}",
+        s
+    );
+    let mut seconds = 0;
+    for unit in hms.split(':') {
+        let unit: u64 = track!(unit.parse().map_err(|e| ErrorKind::InvalidInput.cause(e)))?;
+        seconds = seconds * 60 + unit;
+    }
+    let millis: u64 = track!(millis.parse().map_err(|e| ErrorKind::InvalidInput.cause(e)))?;
+    Ok(seconds as f64 + millis as f64 / 1000.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SEGMENT: &str = "\
+WEBVTT
+X-TIMESTAMP-MAP=MPEGTS:900000,LOCAL:00:00:00.000
+
+1
+00:00:01.000 --> 00:00:04.000 line:0 align:start
+Never drink liquid nitrogen.
+
+NOTE This is a comment,
+which spans two lines
+
+STYLE
+::cue { color: yellow }
+
+00:00:05.000 --> 00:00:09.500
+- It will perforate your stomach.
+- You could die.
+";
+
+    #[test]
+    fn cues_are_mapped_to_mpeg2_ts_timeline() {
+        let cues = parse(SEGMENT).unwrap();
+        assert_eq!(
+            cues,
+            vec![
+                Cue {
+                    id: Some("1".to_owned()),
+                    start: 11.0,
+                    end: 14.0,
+                    settings: "line:0 align:start".to_owned(),
+                    text: "Never drink liquid nitrogen.".to_owned(),
+                },
+                Cue {
+                    id: None,
+                    start: 15.0,
+                    end: 19.5,
+                    settings: String::new(),
+                    text: "- It will perforate your stomach.\n- You could die.".to_owned(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn timestamp_map_with_local_time() {
+        // MPEGTS:183600 is 2.04 seconds, which corresponds to the local time 1 second
+        let text = "WEBVTT\nX-TIMESTAMP-MAP=LOCAL:00:00:01.000,MPEGTS:183600\n\n\
+                    00:00:01.000 --> 00:00:02.000\nHello\n";
+        let cues = parse(text).unwrap();
+        assert!((cues[0].start - 2.04).abs() < 1e-9);
+        assert!((cues[0].end - 3.04).abs() < 1e-9);
+    }
+
+    #[test]
+    fn cue_times_are_used_as_is_without_timestamp_map() {
+        let text = "\u{FEFF}WEBVTT - Title\n\n01:02:03.004 --> 01:02:05.000\nHello\n";
+        let cues = parse(text).unwrap();
+        assert_eq!((cues[0].start, cues[0].end), (3723.004, 3725.0));
+        assert!(is_webvtt(b"\xEF\xBB\xBFWEBVTT\n"));
+    }
+
+    #[test]
+    fn malformed_segments_are_rejected() {
+        assert!(!is_webvtt(&SEGMENT.as_bytes()[1..]));
+        assert!(parse("1\n00:00:01.000 --> 00:00:02.000\nHello\n").is_err());
+        assert!(parse("WEBVTT\nX-TIMESTAMP-MAP=LOCAL:00:00:00.000\n").is_err());
+        assert!(parse("WEBVTT\n\n00:00:01 --> 00:00:02.000\nHello\n").is_err());
+    }
+
+    #[test]
+    fn cue_times_are_aligned_across_timestamp_wraparound() {
+        let mut cue = parse(SEGMENT).unwrap().remove(0);
+        cue.align_to(900_000);
+        assert_eq!((cue.start, cue.end), (1.0, 4.0));
+
+        // The origin precedes the wraparound by one second
+        let mut cue = parse(SEGMENT).unwrap().remove(0);
+        cue.align_to((1 << 33) - 90_000);
+        assert!((cue.start - 12.0).abs() < 1e-6);
+    }
+}
@@ -56,13 +56,41 @@ pub mod hls_player {
     use url::Url;
 
     use {Error, HlsPlayer, MaybeError, MaybeJson, Ptr, WasmBytes, WasmStr};
-    use player::{Action, ActionId};
+    use player::{Action, ActionId, AdEvent, Cue, Event, HlsPlayerConfig, Rendition,
+                 TimedMetadata};
 
     #[no_mangle]
     pub fn hls_player_new() -> Ptr<HlsPlayer> {
         Ptr::new(HlsPlayer::new())
     }
 
+    /// Makes a player configured by `config` (a JSON object in the form of `HlsPlayerConfig`).
+    ///
+    /// If `config` is invalid, null is returned and the reason is written to the (4-byte)
+    /// slot that `error` points to.
+    #[no_mangle]
+    pub fn hls_player_new_with_config(
+        config: WasmStr,
+        mut error: Ptr<MaybeError>,
+    ) -> Ptr<HlsPlayer> {
+        match HlsPlayerConfig::from_json(&config) {
+            Ok(config) => {
+                *error = WasmStr::null();
+                Ptr::new(HlsPlayer::with_config(config))
+            }
+            Err(e) => {
+                *error = WasmStr::from(e.to_json_string());
+                Ptr::null()
+            }
+        }
+    }
+
+    /// Returns the `LogLevel` of the player as an integer (0: error, ..., 3: debug).
+    #[no_mangle]
+    pub fn hls_player_log_level(player: Ptr<HlsPlayer>) -> u32 {
+        player.log_level() as u32
+    }
+
     #[no_mangle]
     pub fn hls_player_free(mut player: Ptr<HlsPlayer>) {
         unsafe {
@@ -120,6 +148,23 @@ pub mod hls_player {
         ok!()
     }
 
+    /// `status` is the HTTP status code of the response, or `0` if no response was received.
+    #[no_mangle]
+    pub fn hls_player_handle_fetch_error(
+        mut player: Ptr<HlsPlayer>,
+        action_id: u32,
+        status: u32,
+    ) -> MaybeError {
+        let action_id = ActionId::from(action_id);
+        let status = if status == 0 {
+            None
+        } else {
+            Some(status as u16)
+        };
+        maybe_error!(player.handle_fetch_error(action_id, status));
+        ok!()
+    }
+
     #[no_mangle]
     pub fn hls_player_next_action(mut player: Ptr<HlsPlayer>) -> MaybeJson<Action> {
         if let Some(action) = player.next_action() {
@@ -129,6 +174,43 @@ pub mod hls_player {
         }
     }
 
+    #[no_mangle]
+    pub fn hls_player_next_event(mut player: Ptr<HlsPlayer>) -> MaybeJson<Event> {
+        if let Some(event) = player.next_event() {
+            MaybeJson::new(&event)
+        } else {
+            MaybeJson::null()
+        }
+    }
+
+    #[no_mangle]
+    pub fn hls_player_mime_type(player: Ptr<HlsPlayer>) -> WasmStr {
+        if let Some(mime_type) = player.mime_type() {
+            WasmStr::from(mime_type)
+        } else {
+            WasmStr::null()
+        }
+    }
+
+    #[no_mangle]
+    pub fn hls_player_next_mime_type(player: Ptr<HlsPlayer>) -> WasmStr {
+        if let Some(mime_type) = player.next_mime_type() {
+            WasmStr::from(mime_type)
+        } else {
+            WasmStr::null()
+        }
+    }
+
+    #[no_mangle]
+    pub fn hls_player_is_ended(player: Ptr<HlsPlayer>) -> bool {
+        player.is_ended()
+    }
+
+    #[no_mangle]
+    pub fn hls_player_is_discontinuity(player: Ptr<HlsPlayer>) -> bool {
+        player.is_discontinuity()
+    }
+
     #[no_mangle]
     pub fn hls_player_next_segment(mut player: Ptr<HlsPlayer>) -> WasmBytes {
         if let Some(segment) = player.next_segment() {
@@ -137,4 +219,160 @@ pub mod hls_player {
             WasmBytes::null()
         }
     }
+
+    #[no_mangle]
+    pub fn hls_player_has_alternate_audio(player: Ptr<HlsPlayer>) -> bool {
+        player.has_alternate_audio()
+    }
+
+    #[no_mangle]
+    pub fn hls_player_audio_mime_type(player: Ptr<HlsPlayer>) -> WasmStr {
+        if let Some(mime_type) = player.audio_mime_type() {
+            WasmStr::from(mime_type)
+        } else {
+            WasmStr::null()
+        }
+    }
+
+    #[no_mangle]
+    pub fn hls_player_next_audio_mime_type(player: Ptr<HlsPlayer>) -> WasmStr {
+        if let Some(mime_type) = player.next_audio_mime_type() {
+            WasmStr::from(mime_type)
+        } else {
+            WasmStr::null()
+        }
+    }
+
+    #[no_mangle]
+    pub fn hls_player_audio_renditions(player: Ptr<HlsPlayer>) -> MaybeJson<Vec<Rendition>> {
+        MaybeJson::new(&player.audio_renditions())
+    }
+
+    #[no_mangle]
+    pub fn hls_player_select_audio_rendition(
+        mut player: Ptr<HlsPlayer>,
+        name_or_language: WasmStr,
+    ) -> MaybeError {
+        maybe_error!(player.select_audio_rendition(&name_or_language));
+        ok!()
+    }
+
+    #[no_mangle]
+    pub fn hls_player_is_audio_discontinuity(player: Ptr<HlsPlayer>) -> bool {
+        player.is_audio_discontinuity()
+    }
+
+    #[no_mangle]
+    pub fn hls_player_next_audio_segment(mut player: Ptr<HlsPlayer>) -> WasmBytes {
+        if let Some(segment) = player.next_audio_segment() {
+            WasmBytes::from(segment)
+        } else {
+            WasmBytes::null()
+        }
+    }
+
+    #[no_mangle]
+    pub fn hls_player_subtitle_renditions(player: Ptr<HlsPlayer>) -> MaybeJson<Vec<Rendition>> {
+        MaybeJson::new(&player.subtitle_renditions())
+    }
+
+    #[no_mangle]
+    pub fn hls_player_select_subtitle_rendition(
+        mut player: Ptr<HlsPlayer>,
+        name_or_language: WasmStr,
+    ) -> MaybeError {
+        maybe_error!(player.select_subtitle_rendition(&name_or_language));
+        ok!()
+    }
+
+    #[no_mangle]
+    pub fn hls_player_take_subtitle_cues(mut player: Ptr<HlsPlayer>) -> MaybeJson<Vec<Cue>> {
+        let cues = player.take_subtitle_cues();
+        if cues.is_empty() {
+            MaybeJson::null()
+        } else {
+            MaybeJson::new(&cues)
+        }
+    }
+
+    #[no_mangle]
+    pub fn hls_player_take_caption_cues(mut player: Ptr<HlsPlayer>) -> MaybeJson<Vec<Cue>> {
+        let cues = player.take_caption_cues();
+        if cues.is_empty() {
+            MaybeJson::null()
+        } else {
+            MaybeJson::new(&cues)
+        }
+    }
+
+    #[no_mangle]
+    pub fn hls_player_take_timed_metadata(
+        mut player: Ptr<HlsPlayer>,
+    ) -> MaybeJson<Vec<TimedMetadata>> {
+        let metadata = player.take_timed_metadata();
+        if metadata.is_empty() {
+            MaybeJson::null()
+        } else {
+            MaybeJson::new(&metadata)
+        }
+    }
+
+    #[no_mangle]
+    pub fn hls_player_take_ad_events(mut player: Ptr<HlsPlayer>) -> MaybeJson<Vec<AdEvent>> {
+        let events = player.take_ad_events();
+        if events.is_empty() {
+            MaybeJson::null()
+        } else {
+            MaybeJson::new(&events)
+        }
+    }
+
+    /// Returns the wall-clock time (in milliseconds since the Unix epoch) at `position`,
+    /// or NaN if unknown.
+    #[no_mangle]
+    pub fn hls_player_position_to_date_time(player: Ptr<HlsPlayer>, position: f64) -> f64 {
+        player.position_to_date_time(position).unwrap_or(f64::NAN)
+    }
+
+    /// Returns the position (in seconds) at `date_time`, or NaN if unknown.
+    #[no_mangle]
+    pub fn hls_player_date_time_to_position(player: Ptr<HlsPlayer>, date_time: f64) -> f64 {
+        player.date_time_to_position(date_time).unwrap_or(f64::NAN)
+    }
+
+    #[no_mangle]
+    pub fn hls_player_seek(mut player: Ptr<HlsPlayer>, position: f64) -> MaybeError {
+        maybe_error!(player.seek(position));
+        ok!()
+    }
+
+    #[no_mangle]
+    pub fn hls_player_timestamp_offset(player: Ptr<HlsPlayer>) -> f64 {
+        player.timestamp_offset().unwrap_or(f64::NAN)
+    }
+
+    #[no_mangle]
+    pub fn hls_player_start_position(player: Ptr<HlsPlayer>) -> f64 {
+        player.start_position().unwrap_or(f64::NAN)
+    }
+
+    #[no_mangle]
+    pub fn hls_player_audio_timestamp_offset(player: Ptr<HlsPlayer>) -> f64 {
+        player.audio_timestamp_offset().unwrap_or(f64::NAN)
+    }
+
+    #[no_mangle]
+    pub fn hls_player_set_buffer_level(mut player: Ptr<HlsPlayer>, level: f64) -> MaybeError {
+        maybe_error!(player.set_buffer_level(level));
+        ok!()
+    }
+
+    #[no_mangle]
+    pub fn hls_player_set_audio_buffer_level(
+        mut player: Ptr<HlsPlayer>,
+        level: f64,
+    ) -> MaybeError {
+        maybe_error!(player.set_audio_buffer_level(level));
+        ok!()
+    }
 }